@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use json_patch::parse_pointer;
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::hex::FromHex;
+use serde_json;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Permission to access a document (or every document whose id starts with a prefix ending in
+/// `*`), optionally restricted to the subtree below a JSON pointer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    pub access: Access,
+    pub doc: String,
+    pub path: Vec<String>,
+}
+
+/// An authenticated caller and the grants it holds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub name: String,
+    pub grants: Vec<Grant>,
+}
+
+#[derive(Debug)]
+pub enum AuthConfigError {
+    IoError(io::Error),
+    JsonError(serde_json::Error),
+    Invalid(String),
+}
+
+wrap_error!(io::Error, AuthConfigError::IoError);
+wrap_error!(serde_json::Error, AuthConfigError::JsonError);
+
+/// Turns a bearer token into a `Principal`
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, token: &str) -> Option<Principal>;
+}
+
+impl Grant {
+    fn covers_doc(&self, id: &str) -> bool {
+        if self.doc.ends_with("*") {
+            id.starts_with(&self.doc[..self.doc.len() - 1])
+        } else {
+            self.doc == id
+        }
+    }
+
+    fn covers_path<S: AsRef<str>>(&self, path: &[S]) -> bool {
+        self.path.len() <= path.len() &&
+        self.path.iter().zip(path).all(|(a, b)| a == b.as_ref())
+    }
+
+    /// Parse a grant like `{"access": "write", "doc": "users*", "path": "/settings"}`
+    pub fn from_value(v: &Value) -> Result<Grant, AuthConfigError> {
+        let access = match v.find("access").and_then(|a| a.as_string()) {
+            Some("read") => Access::Read,
+            Some("write") => Access::Write,
+            _ => return Err(AuthConfigError::Invalid("grant access must be read or write".into())),
+        };
+        let doc = match v.find("doc").and_then(|d| d.as_string()) {
+            Some(doc) => doc.to_string(),
+            None => return Err(AuthConfigError::Invalid("grant doc must be a string".into())),
+        };
+        let path = match v.find("path") {
+            None => vec![],
+            Some(&Value::String(ref p)) => parse_pointer(p),
+            Some(_) => return Err(AuthConfigError::Invalid("grant path must be a string".into())),
+        };
+        Ok(Grant {
+            access: access,
+            doc: doc,
+            path: path,
+        })
+    }
+}
+
+impl Principal {
+    /// Write access implies read access to the same subtree
+    pub fn allows<S: AsRef<str>>(&self, access: Access, id: &str, path: &[S]) -> bool {
+        self.grants.iter().any(|g| g.access >= access && g.covers_doc(id) && g.covers_path(path))
+    }
+
+    /// Whether a grant covers every document in full, as whole-database operations require
+    pub fn allows_all(&self, access: Access) -> bool {
+        self.grants.iter().any(|g| g.access >= access && g.doc == "*" && g.path.is_empty())
+    }
+
+    pub fn from_value(name: &str, v: &Value) -> Result<Principal, AuthConfigError> {
+        let grants = match v.find("grants") {
+            Some(&Value::Array(ref grants)) => {
+                try!(grants.iter().map(Grant::from_value).collect::<Result<Vec<_>, _>>())
+            }
+            None => vec![],
+            Some(_) => return Err(AuthConfigError::Invalid("grants must be an array".into())),
+        };
+        Ok(Principal {
+            name: name.to_string(),
+            grants: grants,
+        })
+    }
+}
+
+/// A fixed set of bearer tokens, loaded from a JSON file shaped like
+/// `{"<token>": {"name": "alice", "grants": [...]}}`
+pub struct StaticTokens(HashMap<String, Principal>);
+
+impl StaticTokens {
+    pub fn from_file(filename: &str) -> Result<StaticTokens, AuthConfigError> {
+        let mut contents = String::new();
+        try!(try!(File::open(filename)).read_to_string(&mut contents));
+        StaticTokens::from_value(&try!(serde_json::from_str(&contents)))
+    }
+
+    pub fn from_value(v: &Value) -> Result<StaticTokens, AuthConfigError> {
+        let entries = try!(v.as_object()
+                            .ok_or(AuthConfigError::Invalid("tokens must be an object".into())));
+        let mut tokens = HashMap::new();
+        for (token, entry) in entries {
+            let name = entry.find("name").and_then(|n| n.as_string()).unwrap_or(token);
+            tokens.insert(token.clone(), try!(Principal::from_value(name, entry)));
+        }
+        Ok(StaticTokens(tokens))
+    }
+}
+
+impl Authenticator for StaticTokens {
+    fn authenticate(&self, token: &str) -> Option<Principal> {
+        self.0
+            .iter()
+            .find(|&(t, _)| fixed_time_eq(t.as_bytes(), token.as_bytes()))
+            .map(|(_, principal)| principal.clone())
+    }
+}
+
+/// Self-describing tokens of the form `<base64 payload>.<hex HMAC-SHA256 of payload>`.
+///
+/// The payload is a JSON object with a `sub` name, `grants` and an optional `exp` unix time.
+pub struct HmacTokens {
+    secret: Vec<u8>,
+}
+
+impl HmacTokens {
+    pub fn new(secret: &[u8]) -> HmacTokens {
+        HmacTokens { secret: secret.to_vec() }
+    }
+
+    fn verify<'a>(&self, token: &'a str) -> Option<&'a str> {
+        let mut parts = token.splitn(2, ".");
+        let (payload, signature) = match (parts.next(), parts.next()) {
+            (Some(p), Some(s)) => (p, s),
+            _ => return None,
+        };
+        let signature = match signature.from_hex() {
+            Ok(s) => s,
+            Err(_) => return None,
+        };
+        let mut mac = Hmac::new(Sha256::new(), &self.secret);
+        mac.input(payload.as_bytes());
+        if fixed_time_eq(mac.result().code(), &signature) {
+            Some(payload)
+        } else {
+            None
+        }
+    }
+}
+
+impl Authenticator for HmacTokens {
+    fn authenticate(&self, token: &str) -> Option<Principal> {
+        let payload = match self.verify(token).and_then(|p| p.from_base64().ok()) {
+            Some(p) => p,
+            None => return None,
+        };
+        let claims: Value = match String::from_utf8(payload)
+                                      .ok()
+                                      .and_then(|s| serde_json::from_str(&s).ok()) {
+            Some(c) => c,
+            None => return None,
+        };
+        if let Some(exp) = claims.find("exp").and_then(|e| e.as_u64()) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs());
+            if now.map(|now| now >= exp).unwrap_or(true) {
+                return None;
+            }
+        }
+        let name = match claims.find("sub").and_then(|s| s.as_string()) {
+            Some(name) => name,
+            None => return None,
+        };
+        Principal::from_value(name, &claims).ok()
+    }
+}
+
+/// Extract the token from an `Authorization: Bearer <token>` header value
+pub fn bearer_token(header: &[u8]) -> Option<&str> {
+    match ::std::str::from_utf8(header) {
+        Ok(value) if value.starts_with("Bearer ") => Some(value["Bearer ".len()..].trim()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+fn parse(s: &str) -> Value {
+    serde_json::from_str(s).unwrap()
+}
+
+/// A token for `HmacTokens` with the given claims, signed with `secret`
+#[cfg(test)]
+fn sign(secret: &[u8], claims: &str) -> String {
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+    use rustc_serialize::hex::ToHex;
+    let payload = claims.as_bytes().to_base64(STANDARD);
+    let mut mac = Hmac::new(Sha256::new(), secret);
+    mac.input(payload.as_bytes());
+    format!("{}.{}", payload, mac.result().code().to_hex())
+}
+
+#[test]
+fn prefix_grants_cover_matching_documents_below_their_path() {
+    let principal = Principal::from_value("alice",
+                                          &parse(r#"{"grants":[{"access":"write","doc":"users*",
+                                                                "path":"/settings"},
+                                                               {"access":"read","doc":"notes"}]}"#))
+                        .unwrap();
+    assert!(principal.allows(Access::Write, "users", &["settings"]));
+    assert!(principal.allows(Access::Write, "users-1", &["settings", "theme"]));
+    assert!(!principal.allows(Access::Write, "users-1", &["name"]));
+    assert!(!principal.allows(Access::Write, "users-1", &[] as &[&str]));
+    assert!(!principal.allows(Access::Write, "admins", &["settings"]));
+    assert!(principal.allows(Access::Read, "notes", &["a", "b"]));
+    assert!(!principal.allows(Access::Read, "notes-1", &[] as &[&str]));
+    assert!(!principal.allows_all(Access::Read));
+}
+
+#[test]
+fn write_grants_allow_reads_but_not_the_reverse() {
+    let grants = r#"{"grants":[{"access":"read","doc":"a"},{"access":"write","doc":"b"},
+                               {"access":"read","doc":"*"}]}"#;
+    let principal = Principal::from_value("bob", &parse(grants)).unwrap();
+    assert!(principal.allows(Access::Read, "a", &[] as &[&str]));
+    assert!(!principal.allows(Access::Write, "a", &[] as &[&str]));
+    assert!(principal.allows(Access::Read, "b", &[] as &[&str]));
+    assert!(principal.allows(Access::Write, "b", &[] as &[&str]));
+    assert!(principal.allows_all(Access::Read));
+    assert!(!principal.allows_all(Access::Write));
+}
+
+#[test]
+fn grant_paths_are_json_pointers() {
+    let grant = Grant::from_value(&parse(r#"{"access":"read","doc":"a","path":"/x~1y/0"}"#))
+                    .unwrap();
+    assert_eq!(grant.path, vec!["x/y", "0"]);
+    assert!(Grant::from_value(&parse(r#"{"access":"admin","doc":"a"}"#)).is_err());
+    assert!(Grant::from_value(&parse(r#"{"access":"read","doc":"a","path":1}"#)).is_err());
+}
+
+#[test]
+fn static_tokens_match_only_in_full() {
+    let tokens = StaticTokens::from_value(&parse(r#"{"s3cret":{"name":"carol","grants":[]},
+                                                     "other":{}}"#))
+                     .unwrap();
+    assert_eq!(tokens.authenticate("s3cret").map(|p| p.name), Some("carol".to_string()));
+    assert_eq!(tokens.authenticate("other").map(|p| p.name), Some("other".to_string()));
+    assert_eq!(tokens.authenticate("s3cre"), None);
+    assert_eq!(tokens.authenticate("s3crets"), None);
+    assert_eq!(tokens.authenticate(""), None);
+}
+
+#[test]
+fn hmac_tokens_must_be_signed_with_the_secret() {
+    let tokens = HmacTokens::new(b"key");
+    let claims = r#"{"sub":"dave","grants":[{"access":"read","doc":"*"}]}"#;
+    let principal = tokens.authenticate(&sign(b"key", claims)).unwrap();
+    assert_eq!(principal.name, "dave");
+    assert!(principal.allows_all(Access::Read));
+
+    assert_eq!(tokens.authenticate(&sign(b"other key", claims)), None);
+    // a payload swapped in under a valid signature
+    let token = sign(b"key", claims);
+    let forged = sign(b"key", r#"{"sub":"dave","grants":[{"access":"write","doc":"*"}]}"#);
+    let payload = &forged[..forged.find('.').unwrap()];
+    let signature = &token[token.find('.').unwrap()..];
+    assert_eq!(tokens.authenticate(&format!("{}{}", payload, signature)), None);
+    assert_eq!(tokens.authenticate("no signature"), None);
+    assert_eq!(tokens.authenticate("eyJ9.not hex"), None);
+    assert_eq!(tokens.authenticate(&sign(b"key", r#"{"grants":[]}"#)), None);
+}
+
+#[test]
+fn expired_hmac_tokens_are_refused() {
+    let tokens = HmacTokens::new(b"key");
+    let expired = sign(b"key", r#"{"sub":"erin","grants":[],"exp":1}"#);
+    assert_eq!(tokens.authenticate(&expired), None);
+    let current = sign(b"key", r#"{"sub":"erin","grants":[],"exp":99999999999}"#);
+    assert_eq!(tokens.authenticate(&current).map(|p| p.name), Some("erin".to_string()));
+}
+
+#[test]
+fn signatures_are_compared_in_full() {
+    // `fixed_time_eq` takes the same time wherever the bytes differ, and refuses a signature of
+    // another length instead of comparing their common prefix
+    let tokens = HmacTokens::new(b"key");
+    let token = sign(b"key", r#"{"sub":"frank","grants":[]}"#);
+    assert!(tokens.authenticate(&token).is_some());
+    let last = if token.ends_with('0') { "1" } else { "0" };
+    let altered = format!("{}{}", &token[..token.len() - 1], last);
+    assert_eq!(tokens.authenticate(&altered), None);
+    assert_eq!(tokens.authenticate(&token[..token.len() - 2]), None);
+    assert_eq!(tokens.authenticate(&format!("{}00", token)), None);
+    let payload = &token[..token.find('.').unwrap()];
+    assert_eq!(tokens.authenticate(&format!("{}.", payload)), None);
+}
+
+#[test]
+fn bearer_tokens_are_read_from_the_header() {
+    assert_eq!(bearer_token(b"Bearer abc.def "), Some("abc.def"));
+    assert_eq!(bearer_token(b"Basic abc"), None);
+    assert_eq!(bearer_token(b"Bearer \xff"), None);
+}
@@ -0,0 +1,249 @@
+extern crate json_api;
+extern crate json_patch;
+
+use std::env;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{stderr, stdin, stdout, BufReader};
+use std::path::Path;
+use std::process::exit;
+
+use json_api::database::{Database, DbOptions, Durability, ExportFormat};
+use json_api::log_file;
+use json_api::log_file::Replay;
+use json_patch::{Op, Patch};
+
+const USAGE: &'static str = "Usage: json-api-admin <command> <data-dir> [<id>]
+
+Inspects and repairs document logs. Do not run repair or compact while a server is using the
+data directory.
+
+Commands:
+    dump <data-dir> <id>       Print the current value of a document
+    log <data-dir> <id>        Print every patch in a document's log with its version
+    verify <data-dir>          Replay every log, reporting the first bad record of each
+    repair <data-dir> <id>     Truncate a log at its first bad record, keeping a .bak copy
+    compact <data-dir> <id>    Replace a log with one patch setting the current value
+                               (this resets the document version to 1)
+    export <data-dir> [log]    Write every document to stdout as newline-delimited JSON,
+                               as snapshots or (with `log`) as full histories
+    import <data-dir>          Restore documents from an export read from stdin";
+
+pub fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let result = match (args.len(), args.first().map(|a| &a[..])) {
+        (3, Some("dump")) => dump(&args[1], &args[2]),
+        (3, Some("log")) => log(&args[1], &args[2]),
+        (2, Some("verify")) => verify(&args[1]),
+        (3, Some("repair")) => repair(&args[1], &args[2]),
+        (3, Some("compact")) => compact(&args[1], &args[2]),
+        (2, Some("export")) => export(&args[1], ExportFormat::Snapshot),
+        (3, Some("export")) if args[2] == "log" => export(&args[1], ExportFormat::Log),
+        (2, Some("import")) => import(&args[1]),
+        _ => Err(USAGE.to_string()),
+    };
+
+    if let Err(message) = result {
+        let _ = writeln!(stderr(), "{}", message);
+        exit(1);
+    }
+}
+
+fn open_log(dir: &str, id: &str) -> Result<BufReader<File>, String> {
+    let filename = Path::new(dir).join(id);
+    File::open(&filename)
+        .map(BufReader::new)
+        .map_err(|e| format!("{}: {}", filename.display(), e))
+}
+
+fn describe_error(replay: &Replay) -> Option<String> {
+    replay.error
+          .as_ref()
+          .map(|e| format!("record {} is bad: {:?}", replay.version + 1, e))
+}
+
+fn dump(dir: &str, id: &str) -> Result<(), String> {
+    let replay = try!(log_file::replay(try!(open_log(dir, id))).map_err(|e| e.to_string()));
+    if let Some(message) = describe_error(&replay) {
+        return Err(format!("{}: {} (run repair to recover the first {} records)",
+                           id,
+                           message,
+                           replay.version));
+    }
+    println!("{:?}", replay.value);
+    Ok(())
+}
+
+fn log(dir: &str, id: &str) -> Result<(), String> {
+    let reader = try!(open_log(dir, id));
+    let replay = try!(log_file::replay_with(reader, |version, record, _| {
+                          println!("{}\t{}", version, record)
+                      })
+                          .map_err(|e| e.to_string()));
+    match describe_error(&replay) {
+        Some(message) => Err(format!("{}: {}", id, message)),
+        None => Ok(()),
+    }
+}
+
+fn verify(dir: &str) -> Result<(), String> {
+    let entries = try!(fs::read_dir(dir).map_err(|e| format!("{}: {}", dir, e)));
+    let mut bad = 0;
+    for entry in entries {
+        let entry = try!(entry.map_err(|e| e.to_string()));
+        let id = entry.file_name().to_string_lossy().into_owned();
+        if !entry.path().is_file() || !log_file::is_log_name(&id) {
+            continue;
+        }
+        match log_file::replay(try!(open_log(dir, &id))) {
+            Ok(ref replay) if replay.error.is_none() => {
+                println!("ok\t{}\tversion {}", id, replay.version)
+            }
+            Ok(ref replay) => {
+                bad += 1;
+                println!("BAD\t{}\t{}", id, describe_error(replay).unwrap());
+            }
+            Err(e) => {
+                bad += 1;
+                println!("BAD\t{}\t{}", id, e);
+            }
+        }
+    }
+    if bad > 0 {
+        Err(format!("{} bad log(s)", bad))
+    } else {
+        Ok(())
+    }
+}
+
+fn repair(dir: &str, id: &str) -> Result<(), String> {
+    let replay = try!(log_file::replay(try!(open_log(dir, id))).map_err(|e| e.to_string()));
+    let message = match describe_error(&replay) {
+        Some(message) => message,
+        None => {
+            println!("{}: nothing to repair (version {})", id, replay.version);
+            return Ok(());
+        }
+    };
+
+    let filename = Path::new(dir).join(id);
+    let backup = Path::new(dir).join(format!("{}.bak", id));
+    try!(fs::copy(&filename, &backup)
+             .and_then(|_| OpenOptions::new().write(true).open(&filename))
+             .and_then(|f| f.set_len(replay.valid_bytes))
+             .map_err(|e| format!("{}: {}", filename.display(), e)));
+    println!("{}: {}; truncated to version {}, original saved as {}",
+             id,
+             message,
+             replay.version,
+             backup.display());
+    Ok(())
+}
+
+fn compact(dir: &str, id: &str) -> Result<(), String> {
+    let replay = try!(log_file::replay(try!(open_log(dir, id))).map_err(|e| e.to_string()));
+    if let Some(message) = describe_error(&replay) {
+        return Err(format!("{}: {} (repair it before compacting)", id, message));
+    }
+    let previous_version = replay.version;
+    let snapshot = Patch { ops: vec![Op::Add(vec![], replay.value)] };
+
+    let filename = Path::new(dir).join(id);
+    let temp = Path::new(dir).join(format!("{}.compact", id));
+    try!(write_snapshot(&temp, &snapshot)
+             .and_then(|_| fs::rename(&temp, &filename))
+             .map_err(|e| format!("{}: {}", filename.display(), e)));
+    println!("{}: compacted {} records into 1", id, previous_version);
+    Ok(())
+}
+
+fn write_snapshot(filename: &Path, snapshot: &Patch) -> io::Result<()> {
+    let mut file = try!(File::create(filename));
+    try!(writeln!(file, "{}", snapshot));
+    file.sync_all()
+}
+
+fn open_db(dir: &str) -> Result<Database<File>, String> {
+    let options = DbOptions { durability: Durability::Fsync, ..DbOptions::default() };
+    Database::open(dir, options).map_err(|e| format!("{}: {}", dir, e))
+}
+
+fn export(dir: &str, format: ExportFormat) -> Result<(), String> {
+    let db = try!(open_db(dir));
+    let stdout = stdout();
+    let mut out = stdout.lock();
+    let count = try!(db.export(&mut out, format).map_err(|e| format!("{:?}", e)));
+    let _ = writeln!(stderr(), "exported {} documents", count);
+    Ok(())
+}
+
+fn import(dir: &str) -> Result<(), String> {
+    let db = try!(open_db(dir));
+    let stdin = stdin();
+    let count = try!(db.import(stdin.lock()).map_err(|e| format!("{:?}", e)));
+    println!("imported {} documents", count);
+    Ok(())
+}
+
+/// An empty data directory for a test
+#[cfg(test)]
+fn test_dir(name: &str, logs: &[(&str, &str)]) -> String {
+    let dir = env::temp_dir().join(format!("json-api-admin-test-{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for &(id, contents) in logs {
+        File::create(dir.join(id)).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+    dir.to_str().unwrap().to_string()
+}
+
+#[cfg(test)]
+fn read_log(dir: &str, id: &str) -> String {
+    let mut contents = String::new();
+    File::open(Path::new(dir).join(id)).unwrap().read_to_string(&mut contents).unwrap();
+    contents
+}
+
+#[cfg(test)]
+const GOOD_LOG: &'static str = "[{\"op\":\"add\",\"path\":\"\",\"value\":{}}]\n\
+                                [{\"op\":\"add\",\"path\":\"/a\",\"value\":1}]\n\
+                                [{\"op\":\"replace\",\"path\":\"/a\",\"value\":2}]\n";
+
+#[cfg(test)]
+const BAD_LOG: &'static str = "[{\"op\":\"add\",\"path\":\"\",\"value\":{}}]\n\
+                               [{\"op\":\"remove\",\"path\":\"/missing\"}]\n\
+                               [{\"op\":\"add\",\"path\":\"/a\",\"value\":1}]\n";
+
+#[test]
+fn verify_reports_bad_logs_and_skips_backups() {
+    let dir = test_dir("verify", &[("good", GOOD_LOG), ("good.bak", BAD_LOG)]);
+    assert_eq!(verify(&dir), Ok(()));
+    File::create(Path::new(&dir).join("bad")).unwrap().write_all(BAD_LOG.as_bytes()).unwrap();
+    assert_eq!(verify(&dir), Err("1 bad log(s)".to_string()));
+}
+
+#[test]
+fn repair_truncates_at_the_first_bad_record_and_keeps_a_backup() {
+    let dir = test_dir("repair", &[("doc", BAD_LOG)]);
+    repair(&dir, "doc").unwrap();
+    assert_eq!(read_log(&dir, "doc"), BAD_LOG.lines().next().unwrap().to_string() + "\n");
+    assert_eq!(read_log(&dir, "doc.bak"), BAD_LOG);
+    assert_eq!(verify(&dir), Ok(()));
+    // a good log is left alone
+    repair(&dir, "doc").unwrap();
+    assert_eq!(read_log(&dir, "doc").lines().count(), 1);
+}
+
+#[test]
+fn compact_replaces_a_log_with_its_value() {
+    let dir = test_dir("compact", &[("doc", GOOD_LOG), ("bad", BAD_LOG)]);
+    compact(&dir, "doc").unwrap();
+    assert_eq!(read_log(&dir, "doc"), "[{\"op\":\"add\",\"path\":\"\",\"value\":{\"a\":2}}]\n");
+    assert!(!Path::new(&dir).join("doc.compact").exists());
+    // compacting would throw away the records after the bad one
+    assert!(compact(&dir, "bad").is_err());
+    assert_eq!(read_log(&dir, "bad"), BAD_LOG);
+}
@@ -0,0 +1,460 @@
+//! Collaborative editing over WebSockets.
+//!
+//! Clients exchange JSON text messages with the server, each an object with a `type`:
+//!
+//! * `{"type":"subscribe","doc":<id>}` is answered with
+//!   `{"type":"snapshot","doc":<id>,"version":<n>,"value":<value>}`. From then on every patch
+//!   committed to the document, by any client or over HTTP, is sent in version order as
+//!   `{"type":"patch","doc":<id>,"version":<n>,"patch":[...]}`. Patches committed in a burst
+//!   may arrive composed into one, whose version is that of the last.
+//!   Subscribing again to a subscribed document does nothing.
+//! * `{"type":"unsubscribe","doc":<id>}` stops them.
+//! * `{"type":"patch","doc":<id>,"id":<tag>,"base":<n>,"patch":[...]}` submits a patch made
+//!   against version `base` of a subscribed document. Once it is committed the client gets
+//!   `{"type":"ack","doc":<id>,"id":<tag>,"version":<n>,"patch":[...]}` in place of the usual
+//!   `patch` message, with the patch as rebased over anything committed since `base`. If it
+//!   cannot be committed, or is over the limits on request bodies and patches, the client gets
+//!   `{"type":"reject","doc":<id>,"id":<tag>,"status":<http status>,"message":<reason>}`.
+//! * `{"type":"reset","doc":<id>}` means the document was deleted or replaced by an import; the
+//!   client must subscribe again.
+//!
+//! When authentication is enabled the bearer token is taken from the `Authorization` header of
+//! the handshake or, since browsers cannot set that, an `access_token` query parameter.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use json_patch::{compose, Op, Patch};
+use serde_json;
+use serde_json::Value;
+use ws;
+use ws::{CloseCode, Handshake, Message};
+
+use auth;
+use auth::{Access, Authenticator, Principal};
+use config::Limits;
+use database::{Change, Database, Edit};
+
+/// Serve the collaborative editing protocol on `bind`, blocking until the listener fails
+pub fn listen(bind: &str,
+              db: Arc<Database<File>>,
+              authenticators: Arc<Vec<Box<Authenticator>>>,
+              limits: Limits)
+              -> ws::Result<()> {
+    let mut next_id = 0;
+    ws::listen(bind, move |out| {
+        next_id += 1;
+        Connection {
+            id: next_id,
+            out: out,
+            db: db.clone(),
+            authenticators: authenticators.clone(),
+            limits: limits.clone(),
+            principal: None,
+            subscriptions: HashMap::new(),
+        }
+    })
+}
+
+struct Connection<O> {
+    id: usize,
+    out: O,
+    db: Arc<Database<File>>,
+    authenticators: Arc<Vec<Box<Authenticator>>>,
+    /// Applied to messages as to HTTP requests
+    limits: Limits,
+    principal: Option<Principal>,
+    subscriptions: HashMap<String, Subscription>,
+}
+
+/// A document whose changes are being forwarded to the client by their own thread
+struct Subscription {
+    stop: Arc<AtomicBool>,
+    /// A sender for the channel the database sends changes down, so that it can be woken
+    wake: Sender<Change>,
+    forwarder: thread::JoinHandle<()>,
+}
+
+impl Subscription {
+    /// Stop forwarding, waking the thread if it is waiting for a change. Returns the thread,
+    /// which ends without sending anything more.
+    fn end(self) -> thread::JoinHandle<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.wake.send(Change::Reset);
+        self.forwarder
+    }
+}
+
+impl ws::Handler for Connection<ws::Sender> {
+    fn on_open(&mut self, shake: Handshake) -> ws::Result<()> {
+        if self.authenticators.is_empty() {
+            return Ok(());
+        }
+        let token = shake.request
+                         .header("authorization")
+                         .and_then(|value| auth::bearer_token(value))
+                         .or_else(|| query_param(shake.request.resource(), "access_token"));
+        self.principal = token.and_then(|token| {
+            self.authenticators.iter().filter_map(|a| a.authenticate(token)).next()
+        });
+        if self.principal.is_none() {
+            return self.out.close_with_reason(CloseCode::Policy,
+                                              "a valid bearer token is required");
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        self.receive(msg)
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        for (_, subscription) in self.subscriptions.drain() {
+            subscription.end();
+        }
+    }
+}
+
+impl<O: Outbox + Clone> Connection<O> {
+    fn receive(&mut self, msg: Message) -> ws::Result<()> {
+        if msg.len() as u64 > self.limits.max_body_bytes {
+            let message = format!("messages may not exceed {} bytes", self.limits.max_body_bytes);
+            return self.out.send_text(error(&message));
+        }
+        let request: Value = match msg.as_text().ok().and_then(|t| serde_json::from_str(t).ok()) {
+            Some(request) => request,
+            None => return self.out.send_text(error("messages must be JSON text")),
+        };
+        let doc = match request.find("doc").and_then(|doc| doc.as_string()) {
+            Some(doc) => doc.to_string(),
+            None => return self.out.send_text(error("messages must have a string doc")),
+        };
+        match request.find("type").and_then(|t| t.as_string()) {
+            Some("subscribe") => self.subscribe(&doc),
+            Some("unsubscribe") => {
+                self.unsubscribe(&doc);
+                Ok(())
+            }
+            Some("patch") => self.patch(&doc, &request),
+            _ => self.out.send_text(error("type must be subscribe, unsubscribe or patch")),
+        }
+    }
+
+    fn subscribe(&mut self, doc: &str) -> ws::Result<()> {
+        if !self.allows(Access::Read, doc, &[] as &[&str]) {
+            return self.out.send_text(error("access denied"));
+        }
+        if self.subscriptions.contains_key(doc) {
+            return Ok(());
+        }
+        let (wake, changes) = channel();
+        let (version, value) = match self.db.subscribe(doc, wake.clone()) {
+            Ok(subscription) => subscription,
+            Err(e) => return self.out.send_text(error(&e.status().1)),
+        };
+        try!(self.out
+                 .send_text(format!(r#"{{"type":"snapshot","doc":{:?},"version":{},"value":{:?}}}"#,
+                                    Value::String(doc.to_string()),
+                                    version,
+                                    value)));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let forwarder = forward(self.out.clone(),
+                                doc,
+                                format!("{}:", self.id),
+                                changes,
+                                stop.clone());
+        self.subscriptions.insert(doc.to_string(),
+                                  Subscription {
+                                      stop: stop,
+                                      wake: wake,
+                                      forwarder: forwarder,
+                                  });
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, doc: &str) {
+        if let Some(subscription) = self.subscriptions.remove(doc) {
+            subscription.end();
+        }
+    }
+
+    fn patch(&mut self, doc: &str, request: &Value) -> ws::Result<()> {
+        let tag = request.find("id").cloned().unwrap_or(Value::Null);
+        if !self.subscriptions.contains_key(doc) {
+            let message = "subscribe before sending patches";
+            return self.out.send_text(reject(doc, &tag, 409, message));
+        }
+        let base = request.find("base").and_then(|base| base.as_u64());
+        let options = self.limits.parse_options();
+        let patch = request.find("patch")
+                           .and_then(|patch| Patch::from_value_with(patch.clone(), options).ok());
+        let (base, patch) = match (base, patch) {
+            (Some(base), Some(patch)) => (base as usize, patch),
+            _ => {
+                let message = "a base version and a valid patch are required";
+                return self.out.send_text(reject(doc, &tag, 400, message));
+            }
+        };
+        if let Some(max) = self.limits.max_ops {
+            if patch.ops.len() > max {
+                let message = format!("patch has more than {} operations", max);
+                return self.out.send_text(reject(doc, &tag, 413, &message));
+            }
+        }
+        for op in &patch.ops {
+            let allowed = match op {
+                &Op::Copy(ref path, ref from) => {
+                    self.allows(Access::Write, doc, path) && self.allows(Access::Read, doc, from)
+                }
+                &Op::Move(ref path, ref from) => {
+                    self.allows(Access::Write, doc, path) && self.allows(Access::Write, doc, from)
+                }
+                &Op::Add(ref path, _) |
+                &Op::Remove(ref path) |
+                &Op::Replace(ref path, _) |
+                &Op::Test(ref path, _) |
+                &Op::Assert(ref path, _) |
+                &Op::Extension(ref path, _) => self.allows(Access::Write, doc, path),
+            };
+            if !allowed {
+                return self.out.send_text(reject(doc, &tag, 403, "access denied"));
+            }
+        }
+
+        let edit = Edit::Concurrent {
+            patch: patch,
+            base: base,
+            author: Some(format!("{}:{:?}", self.id, tag)),
+        };
+        match self.db.edit_doc(doc, &[], edit, None) {
+            // the ack arrives through the subscription, in order with the other patches
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let (status, message) = e.status();
+                self.out.send_text(reject(doc, &tag, status, &message))
+            }
+        }
+    }
+
+    fn allows<S: AsRef<str>>(&self, access: Access, doc: &str, path: &[S]) -> bool {
+        self.principal.as_ref().map_or(true, |p| p.allows(access, doc, path))
+    }
+}
+
+/// Where a connection sends its messages, which fails once the client has gone away
+trait Outbox: Send + 'static {
+    fn send_text(&self, message: String) -> ws::Result<()>;
+}
+
+impl Outbox for ws::Sender {
+    fn send_text(&self, message: String) -> ws::Result<()> {
+        self.send(message)
+    }
+}
+
+/// Send a document's changes to the client until `stop` is set or the connection goes away.
+///
+/// Patches whose author starts with `author_prefix` came from this connection and are sent as
+/// acknowledgements carrying the client's tag. Other patches that have piled up while the client
+/// was being sent earlier ones are composed into one message, with the version of the last.
+fn forward<O: Outbox>(out: O,
+                      doc: &str,
+                      author_prefix: String,
+                      changes: Receiver<Change>,
+                      stop: Arc<AtomicBool>)
+                      -> thread::JoinHandle<()> {
+    let doc = Value::String(doc.to_string());
+    thread::spawn(move || {
+        let tag_of = |author: &Option<String>| {
+            author.as_ref().and_then(|author| {
+                if author.starts_with(&author_prefix[..]) {
+                    serde_json::from_str::<Value>(&author[author_prefix.len()..]).ok()
+                } else {
+                    None
+                }
+            })
+        };
+        let mut next = changes.recv().ok();
+        while let Some(change) = next.take() {
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let (message, done) = match change {
+                Change::Patched { version, patch, author } => {
+                    match tag_of(&author) {
+                        Some(tag) => {
+                            let message =
+                                format!(r#"{{"type":"ack","doc":{:?},"id":{:?},"version":{},"#,
+                                        doc,
+                                        tag,
+                                        version) + &format!(r#""patch":{}}}"#, patch);
+                            (message, false)
+                        }
+                        None => {
+                            let (mut version, mut patch) = (version, patch);
+                            while let Ok(change) = changes.try_recv() {
+                                match change {
+                                    Change::Patched { version: v, patch: ref p, ref author }
+                                        if tag_of(author).is_none() => {
+                                        version = v;
+                                        patch = compose(&patch, p);
+                                    }
+                                    change => {
+                                        next = Some(change);
+                                        break;
+                                    }
+                                }
+                            }
+                            let message =
+                                format!(r#"{{"type":"patch","doc":{:?},"version":{},"patch":{}}}"#,
+                                        doc,
+                                        version,
+                                        patch);
+                            (message, false)
+                        }
+                    }
+                }
+                Change::Reset => (format!(r#"{{"type":"reset","doc":{:?}}}"#, doc), true),
+            };
+            if out.send_text(message).is_err() || done {
+                break;
+            }
+            if next.is_none() {
+                next = changes.recv().ok();
+            }
+        }
+    })
+}
+
+fn error(message: &str) -> String {
+    format!(r#"{{"type":"error","message":{:?}}}"#,
+            Value::String(message.to_string()))
+}
+
+fn reject(doc: &str, tag: &Value, status: u16, message: &str) -> String {
+    format!(r#"{{"type":"reject","doc":{:?},"id":{:?},"status":{},"message":{:?}}}"#,
+            Value::String(doc.to_string()),
+            tag,
+            status,
+            Value::String(message.to_string()))
+}
+
+/// The value of a query parameter in a request target like `/path?a=1&b=2`
+fn query_param<'a>(resource: &'a str, name: &str) -> Option<&'a str> {
+    resource.splitn(2, "?")
+            .nth(1)
+            .and_then(|query| {
+                query.split("&")
+                     .filter_map(|pair| {
+                         let mut kv = pair.splitn(2, "=");
+                         if kv.next() == Some(name) {
+                             kv.next()
+                         } else {
+                             None
+                         }
+                     })
+                     .next()
+            })
+}
+
+#[cfg(test)]
+impl Outbox for Sender<String> {
+    fn send_text(&self, message: String) -> ws::Result<()> {
+        self.send(message).map_err(|_| ws::Error::new(ws::ErrorKind::Internal, "client gone"))
+    }
+}
+
+/// A connection to a database in an empty directory holding the document `a`, and the
+/// messages sent to its client
+#[cfg(test)]
+fn test_connection(name: &str, limits: Limits) -> (Connection<Sender<String>>, Receiver<String>) {
+    use database::DbOptions;
+
+    let dir = ::std::env::temp_dir().join(format!("json-api-collab-test-{}", name));
+    let _ = ::std::fs::remove_dir_all(&dir);
+    let db = Database::open(dir.to_str().unwrap(), DbOptions::default()).unwrap();
+    let put = Edit::Put {
+        value: serde_json::from_str("[]").unwrap(),
+        mkdirs: false,
+    };
+    db.edit_doc("a", &[], put, None).unwrap();
+    let (out, sent) = channel();
+    let connection = Connection {
+        id: 1,
+        out: out,
+        db: Arc::new(db),
+        authenticators: Arc::new(vec![]),
+        limits: limits,
+        principal: None,
+        subscriptions: HashMap::new(),
+    };
+    (connection, sent)
+}
+
+#[cfg(test)]
+fn append(db: &Database<File>) {
+    let patch = Patch::from_str(r#"[{"op":"add","path":"/-","value":1}]"#).unwrap();
+    db.patch_doc("a", patch, &[], None).unwrap();
+}
+
+#[test]
+fn subscribing_twice_changes_nothing() {
+    let (mut connection, sent) = test_connection("resubscribe", Limits::default());
+    connection.receive(Message::text(r#"{"type":"subscribe","doc":"a"}"#)).unwrap();
+    connection.receive(Message::text(r#"{"type":"subscribe","doc":"a"}"#)).unwrap();
+    assert_eq!(sent.recv().unwrap(),
+               r#"{"type":"snapshot","doc":"a","version":1,"value":[]}"#);
+    append(&connection.db);
+    // one forwarder, whose patch comes straight after the one snapshot
+    assert_eq!(sent.recv().unwrap(),
+               concat!(r#"{"type":"patch","doc":"a","version":2,"#,
+                       r#""patch":[{"op":"add","path":"/0","value":1}]}"#));
+    connection.unsubscribe("a");
+    assert!(sent.try_recv().is_err());
+}
+
+#[test]
+fn unsubscribing_ends_the_forwarder() {
+    let (mut connection, sent) = test_connection("unsubscribe", Limits::default());
+    connection.subscribe("a").unwrap();
+    sent.recv().unwrap();
+    let subscription = connection.subscriptions.remove("a").unwrap();
+    // the thread is waiting for a change, and has to be woken to notice
+    subscription.end().join().unwrap();
+    append(&connection.db);
+    drop(connection);
+    // every sender is gone, so nothing more was sent
+    assert!(sent.recv().is_err());
+}
+
+#[test]
+fn patches_are_held_to_the_request_limits() {
+    let limits = Limits {
+        max_body_bytes: 200,
+        max_ops: Some(1),
+        patch_extensions: false,
+        patch_predicates: true,
+    };
+    let (mut connection, sent) = test_connection("limits", limits);
+    connection.subscribe("a").unwrap();
+    sent.recv().unwrap();
+
+    let two_ops = r#"{"type":"patch","doc":"a","id":1,"base":1,"patch":[
+        {"op":"test","path":"","value":[]},{"op":"add","path":"/-","value":1}]}"#;
+    connection.receive(Message::text(two_ops)).unwrap();
+    assert_eq!(sent.recv().unwrap(),
+               concat!(r#"{"type":"reject","doc":"a","id":1,"status":413,"#,
+                       r#""message":"patch has more than 1 operations"}"#));
+
+    let long: String = ::std::iter::repeat(" ").take(200).collect();
+    connection.receive(Message::text(format!(r#"{{"type":"subscribe","doc":"a"}}{}"#, long)))
+              .unwrap();
+    assert_eq!(sent.recv().unwrap(),
+               r#"{"type":"error","message":"messages may not exceed 200 bytes"}"#);
+    assert_eq!(connection.db.find_in_doc("a", &[]).unwrap(), serde_json::from_str("[]").unwrap());
+}
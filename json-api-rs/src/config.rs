@@ -0,0 +1,342 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use hyper::method::Method;
+use json_patch::ParseOptions;
+use toml;
+
+use cors::{AllowedOrigins, CorsPolicy};
+use database::{CacheLimits, Durability};
+use limits::{DocLimits, Quotas};
+
+/// Everything needed to run the server, built from defaults, a TOML file and command-line flags
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: String,
+    pub data_dir: String,
+    pub threads: usize,
+    pub durability: Durability,
+    pub limits: Limits,
+    pub quotas: Quotas,
+    pub cache: CacheLimits,
+    pub cors: CorsPolicy,
+    pub tokens_file: Option<String>,
+    pub hmac_secret: Option<String>,
+    /// Address for the collaborative editing WebSocket listener, which is off when unset
+    pub websocket_bind: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Largest request body accepted, in bytes
+    pub max_body_bytes: u64,
+    /// Most operations accepted in a single patch
+    pub max_ops: Option<usize>,
+    /// Whether patches may use the extension ops, such as `inc` and `str-ins`
+    pub patch_extensions: bool,
+    /// Whether patches may use the predicate ops, such as `test-type` and `test-range`
+    pub patch_predicates: bool,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(String, io::Error),
+    ParseError(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ConfigError::IoError(ref filename, ref e) => write!(f, "{}: {}", filename, e),
+            &ConfigError::ParseError(ref message) => write!(f, "{}", message),
+            &ConfigError::Invalid(ref message) => write!(f, "invalid configuration: {}", message),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: "0.0.0.0:3000".into(),
+            data_dir: "./logs".into(),
+            threads: 8,
+            durability: Durability::Os,
+            limits: Limits::default(),
+            quotas: Quotas::default(),
+            cache: CacheLimits::default(),
+            cors: CorsPolicy::default(),
+            tokens_file: None,
+            hmac_secret: None,
+            websocket_bind: None,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_body_bytes: 1024 * 1024,
+            max_ops: None,
+            patch_extensions: false,
+            patch_predicates: true,
+        }
+    }
+}
+
+impl Limits {
+    /// How to parse the patches in requests
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            extensions: self.patch_extensions,
+            predicates: self.patch_predicates,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(filename: &str) -> Result<Config, ConfigError> {
+        let mut contents = String::new();
+        try!(File::open(filename)
+                 .and_then(|mut f| f.read_to_string(&mut contents))
+                 .map_err(|e| ConfigError::IoError(filename.to_string(), e)));
+
+        let mut parser = toml::Parser::new(&contents);
+        match parser.parse() {
+            Some(table) => Config::from_toml(&toml::Value::Table(table)),
+            None => {
+                let messages: Vec<String> = parser.errors
+                                                  .iter()
+                                                  .map(|e| {
+                                                      let (line, col) = parser.to_linecol(e.lo);
+                                                      format!("{}:{}:{}: {}",
+                                                              filename,
+                                                              line + 1,
+                                                              col + 1,
+                                                              e.desc)
+                                                  })
+                                                  .collect();
+                Err(ConfigError::ParseError(messages.join("\n")))
+            }
+        }
+    }
+
+    /// Overlay the settings present in `toml` on the defaults
+    pub fn from_toml(toml: &toml::Value) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        if let Some(bind) = try!(string(toml, "bind")) {
+            config.bind = bind;
+        }
+        if let Some(dir) = try!(string(toml, "data_dir")) {
+            config.data_dir = dir;
+        }
+        if let Some(threads) = try!(integer(toml, "threads")) {
+            config.threads = threads as usize;
+        }
+        if let Some(durability) = try!(string(toml, "durability")) {
+            config.durability = try!(durability.parse().map_err(ConfigError::Invalid));
+        }
+        if let Some(max) = try!(integer(toml, "limits.max_body_bytes")) {
+            config.limits.max_body_bytes = max;
+        }
+        config.limits.max_ops = try!(integer(toml, "limits.max_ops")).map(|max| max as usize);
+        if let Some(extensions) = try!(boolean(toml, "limits.patch_extensions")) {
+            config.limits.patch_extensions = extensions;
+        }
+        if let Some(predicates) = try!(boolean(toml, "limits.patch_predicates")) {
+            config.limits.patch_predicates = predicates;
+        }
+        if let Some(limits) = toml.lookup("limits") {
+            config.quotas.default = try!(doc_limits_from_toml(limits));
+        }
+        if let Some(quotas) = toml.lookup("quotas") {
+            let quotas = try!(quotas.as_table()
+                                    .ok_or(ConfigError::Invalid("quotas must be a table".into())));
+            for (pattern, limits) in quotas {
+                config.quotas.overrides.push((pattern.clone(), try!(doc_limits_from_toml(limits))));
+            }
+        }
+        config.cache = CacheLimits {
+            max_docs: try!(integer(toml, "cache.max_docs")).map(|max| max as usize),
+            max_bytes: try!(integer(toml, "cache.max_bytes")),
+            max_open_files: try!(integer(toml, "cache.max_open_files")).map(|max| max as usize),
+        };
+        config.tokens_file = try!(string(toml, "auth.tokens_file"));
+        config.hmac_secret = try!(string(toml, "auth.hmac_secret"));
+        config.websocket_bind = try!(string(toml, "websocket.bind"));
+        try!(cors_from_toml(toml, &mut config.cors));
+        Ok(config)
+    }
+}
+
+/// Read the per-document limits from a `[limits]` or `[quotas."<id>"]` table
+fn doc_limits_from_toml(toml: &toml::Value) -> Result<DocLimits, ConfigError> {
+    Ok(DocLimits {
+        max_bytes: try!(integer(toml, "max_doc_bytes")),
+        max_depth: try!(integer(toml, "max_depth")).map(|max| max as usize),
+        max_log_bytes: try!(integer(toml, "max_log_bytes")),
+    })
+}
+
+fn cors_from_toml(toml: &toml::Value, cors: &mut CorsPolicy) -> Result<(), ConfigError> {
+    match toml.lookup("cors.origins") {
+        None => (),
+        Some(&toml::Value::String(ref any)) if any == "*" => cors.origins = AllowedOrigins::Any,
+        Some(_) => cors.origins = AllowedOrigins::List(try!(strings(toml, "cors.origins"))),
+    }
+    if toml.lookup("cors.methods").is_some() {
+        let mut methods = vec![];
+        for name in try!(strings(toml, "cors.methods")) {
+            methods.push(try!(name.parse::<Method>().map_err(|_| {
+                ConfigError::Invalid(format!("cors.methods: unknown method {:?}", name))
+            })));
+        }
+        cors.methods = methods;
+    }
+    if toml.lookup("cors.headers").is_some() {
+        cors.headers = try!(strings(toml, "cors.headers"));
+    }
+    if let Some(max_age) = try!(integer(toml, "cors.max_age")) {
+        cors.max_age = Some(max_age as u32);
+    }
+    if let Some(credentials) = try!(boolean(toml, "cors.credentials")) {
+        cors.allow_credentials = credentials;
+    }
+    Ok(())
+}
+
+fn string(toml: &toml::Value, key: &str) -> Result<Option<String>, ConfigError> {
+    match toml.lookup(key) {
+        None => Ok(None),
+        Some(v) => {
+            v.as_str()
+             .map(|s| Some(s.to_string()))
+             .ok_or(ConfigError::Invalid(format!("{} must be a string", key)))
+        }
+    }
+}
+
+fn integer(toml: &toml::Value, key: &str) -> Result<Option<u64>, ConfigError> {
+    match toml.lookup(key).map(|v| v.as_integer()) {
+        None => Ok(None),
+        Some(Some(i)) if i >= 0 => Ok(Some(i as u64)),
+        Some(_) => Err(ConfigError::Invalid(format!("{} must be a positive integer", key))),
+    }
+}
+
+fn boolean(toml: &toml::Value, key: &str) -> Result<Option<bool>, ConfigError> {
+    match toml.lookup(key) {
+        None => Ok(None),
+        Some(v) => {
+            v.as_bool()
+             .map(Some)
+             .ok_or(ConfigError::Invalid(format!("{} must be true or false", key)))
+        }
+    }
+}
+
+fn strings(toml: &toml::Value, key: &str) -> Result<Vec<String>, ConfigError> {
+    let invalid = || ConfigError::Invalid(format!("{} must be an array of strings", key));
+    let values = try!(toml.lookup(key).and_then(|v| v.as_slice()).ok_or_else(&invalid));
+    values.iter()
+          .map(|v| v.as_str().map(|s| s.to_string()).ok_or_else(&invalid))
+          .collect()
+}
+
+#[cfg(test)]
+fn parse(source: &str) -> Result<Config, ConfigError> {
+    Config::from_toml(&toml::Value::Table(toml::Parser::new(source).parse().unwrap()))
+}
+
+#[test]
+fn an_empty_file_gives_the_defaults() {
+    let config = parse("").unwrap();
+    assert_eq!(config.bind, "0.0.0.0:3000");
+    assert_eq!(config.durability, Durability::Os);
+    assert_eq!(config.limits.max_body_bytes, 1024 * 1024);
+    assert_eq!(config.limits.parse_options(),
+               ParseOptions {
+                   extensions: false,
+                   predicates: true,
+               });
+    assert_eq!(config.cache, CacheLimits::default());
+    assert!(config.quotas.overrides.is_empty());
+    assert_eq!(config.websocket_bind, None);
+}
+
+#[test]
+fn settings_overlay_the_defaults() {
+    let config = parse(r#"
+        bind = "127.0.0.1:4000"
+        durability = "fsync"
+
+        [limits]
+        max_body_bytes = 100
+        max_ops = 10
+        max_depth = 4
+        patch_extensions = true
+        patch_predicates = false
+
+        [quotas."users/*"]
+        max_doc_bytes = 50
+
+        [cache]
+        max_docs = 3
+
+        [auth]
+        tokens_file = "tokens.toml"
+
+        [websocket]
+        bind = "127.0.0.1:4001"
+    "#)
+                     .unwrap();
+    assert_eq!(config.bind, "127.0.0.1:4000");
+    assert_eq!(config.data_dir, "./logs");
+    assert_eq!(config.durability, Durability::Fsync);
+    assert_eq!(config.limits.max_body_bytes, 100);
+    assert_eq!(config.limits.max_ops, Some(10));
+    assert!(config.limits.patch_extensions && !config.limits.patch_predicates);
+    assert_eq!(config.quotas.default.max_depth, Some(4));
+    assert_eq!(config.quotas.overrides.len(), 1);
+    assert_eq!(config.quotas.overrides[0].0, "users/*");
+    assert_eq!(config.quotas.overrides[0].1.max_bytes, Some(50));
+    assert_eq!(config.cache.max_docs, Some(3));
+    assert_eq!(config.cache.max_bytes, None);
+    assert_eq!(config.tokens_file, Some("tokens.toml".into()));
+    assert_eq!(config.websocket_bind, Some("127.0.0.1:4001".into()));
+}
+
+#[test]
+fn cors_settings_are_read() {
+    let config = parse(r#"
+        [cors]
+        origins = ["https://a.example"]
+        methods = ["GET", "PATCH"]
+        max_age = 60
+        credentials = true
+    "#)
+                     .unwrap();
+    assert_eq!(config.cors.origins, AllowedOrigins::List(vec!["https://a.example".into()]));
+    assert_eq!(config.cors.methods, vec![Method::Get, Method::Patch]);
+    assert_eq!(config.cors.max_age, Some(60));
+    assert!(config.cors.allow_credentials);
+    assert_eq!(parse("[cors]\norigins = \"*\"").unwrap().cors.origins, AllowedOrigins::Any);
+}
+
+#[test]
+fn invalid_settings_are_refused() {
+    for source in &["threads = -1",
+                    "bind = 3000",
+                    "durability = \"sometimes\"",
+                    "[limits]\npatch_extensions = \"yes\"",
+                    "[limits]\npatch_predicates = 1",
+                    "quotas = 1",
+                    "[cors]\nmethods = \"GET\""] {
+        match parse(source) {
+            Err(ConfigError::Invalid(_)) => (),
+            other => panic!("{:?} gave {:?}", source, other),
+        }
+    }
+}
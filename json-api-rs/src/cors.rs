@@ -0,0 +1,179 @@
+use unicase::UniCase;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper::header::{Headers, AccessControlAllowOrigin, AccessControlAllowMethods,
+                    AccessControlAllowHeaders, AccessControlMaxAge, AccessControlRequestMethod,
+                    AccessControlRequestHeaders, Allow};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedOrigins {
+    Any,
+    List(Vec<String>),
+}
+
+/// Which cross-origin requests browsers may make, and how long preflight results can be cached
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    pub origins: AllowedOrigins,
+    pub methods: Vec<Method>,
+    pub headers: Vec<String>,
+    pub max_age: Option<u32>,
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> CorsPolicy {
+        CorsPolicy {
+            origins: AllowedOrigins::Any,
+            methods: vec![Method::Get, Method::Put, Method::Post, Method::Patch, Method::Delete],
+            headers: vec!["content-type".into(), "authorization".into()],
+            max_age: None,
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsPolicy {
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        match self.origins {
+            AllowedOrigins::Any => true,
+            AllowedOrigins::List(ref origins) => origins.iter().any(|o| o == origin),
+        }
+    }
+
+    fn allows_header(&self, header: &str) -> bool {
+        self.headers.iter().any(|h| UniCase(&h[..]) == UniCase(header))
+    }
+
+    /// Set the origin headers shared by every response to a request from an allowed origin.
+    ///
+    /// Returns false if the request is not a CORS request or its origin is not allowed.
+    pub fn set_origin_headers(&self, origin: Option<&str>, headers: &mut Headers) -> bool {
+        let origin = match origin {
+            Some(origin) if self.allows_origin(origin) => origin,
+            _ => return false,
+        };
+        // browsers reject a wildcard origin on credentialed requests
+        if self.origins == AllowedOrigins::Any && !self.allow_credentials {
+            headers.set(AccessControlAllowOrigin::Any);
+        } else {
+            headers.set(AccessControlAllowOrigin::Value(origin.to_string()));
+            headers.set_raw("Vary", vec![b"Origin".to_vec()]);
+        }
+        if self.allow_credentials {
+            headers.set_raw("Access-Control-Allow-Credentials", vec![b"true".to_vec()]);
+        }
+        true
+    }
+
+    /// Answer an `OPTIONS` request, describing the allowed methods and headers if it is a
+    /// preflight for something this policy permits.
+    pub fn preflight(&self,
+                     allowed_origin: bool,
+                     request: &Headers,
+                     headers: &mut Headers)
+                     -> StatusCode {
+        let mut allow = self.methods.clone();
+        allow.push(Method::Options);
+        headers.set(Allow(allow));
+
+        let method = match request.get::<AccessControlRequestMethod>() {
+            Some(&AccessControlRequestMethod(ref method)) => method,
+            None => return StatusCode::NoContent,
+        };
+        let headers_ok = match request.get::<AccessControlRequestHeaders>() {
+            Some(&AccessControlRequestHeaders(ref requested)) => {
+                requested.iter().all(|h| self.allows_header(h))
+            }
+            None => true,
+        };
+
+        if !allowed_origin || !headers_ok || !self.methods.contains(method) {
+            // without the CORS headers the browser refuses to send the actual request
+            headers.remove::<AccessControlAllowOrigin>();
+            return StatusCode::NoContent;
+        }
+
+        headers.set(AccessControlAllowMethods(self.methods.clone()));
+        headers.set(AccessControlAllowHeaders(self.headers
+                                                  .iter()
+                                                  .map(|h| UniCase(h.clone()))
+                                                  .collect()));
+        if let Some(max_age) = self.max_age {
+            headers.set(AccessControlMaxAge(max_age));
+        }
+        StatusCode::NoContent
+    }
+}
+
+#[cfg(test)]
+fn listed(origins: &[&str]) -> CorsPolicy {
+    CorsPolicy {
+        origins: AllowedOrigins::List(origins.iter().map(|o| o.to_string()).collect()),
+        ..CorsPolicy::default()
+    }
+}
+
+#[cfg(test)]
+fn preflight_request(method: Method, headers: &[&str]) -> Headers {
+    let mut request = Headers::new();
+    request.set(AccessControlRequestMethod(method));
+    let headers = headers.iter().map(|h| UniCase(h.to_string())).collect();
+    request.set(AccessControlRequestHeaders(headers));
+    request
+}
+
+#[test]
+fn any_origin_is_a_wildcard_unless_credentials_are_allowed() {
+    let mut headers = Headers::new();
+    assert!(CorsPolicy::default().set_origin_headers(Some("https://a.example"), &mut headers));
+    assert_eq!(headers.get::<AccessControlAllowOrigin>(),
+               Some(&AccessControlAllowOrigin::Any));
+
+    let policy = CorsPolicy { allow_credentials: true, ..CorsPolicy::default() };
+    let mut headers = Headers::new();
+    assert!(policy.set_origin_headers(Some("https://a.example"), &mut headers));
+    assert_eq!(headers.get::<AccessControlAllowOrigin>(),
+               Some(&AccessControlAllowOrigin::Value("https://a.example".into())));
+    assert!(headers.get_raw("Access-Control-Allow-Credentials").is_some());
+}
+
+#[test]
+fn only_listed_origins_get_headers() {
+    let policy = listed(&["https://a.example"]);
+    let mut headers = Headers::new();
+    assert!(!policy.set_origin_headers(Some("https://b.example"), &mut headers));
+    assert!(!policy.set_origin_headers(None, &mut headers));
+    assert_eq!(headers.len(), 0);
+    assert!(policy.set_origin_headers(Some("https://a.example"), &mut headers));
+    assert_eq!(headers.get::<AccessControlAllowOrigin>(),
+               Some(&AccessControlAllowOrigin::Value("https://a.example".into())));
+    assert!(headers.get_raw("Vary").is_some());
+}
+
+#[test]
+fn preflights_describe_permitted_requests() {
+    let policy = CorsPolicy { max_age: Some(60), ..CorsPolicy::default() };
+    let mut headers = Headers::new();
+    policy.set_origin_headers(Some("https://a.example"), &mut headers);
+    let request = preflight_request(Method::Patch, &["Content-Type"]);
+    assert_eq!(policy.preflight(true, &request, &mut headers), StatusCode::NoContent);
+    assert!(headers.get::<AccessControlAllowMethods>().unwrap().0.contains(&Method::Patch));
+    assert_eq!(headers.get::<AccessControlMaxAge>(), Some(&AccessControlMaxAge(60)));
+    assert!(headers.get::<AccessControlAllowOrigin>().is_some());
+}
+
+#[test]
+fn preflights_for_other_methods_or_headers_are_refused() {
+    let policy = CorsPolicy { methods: vec![Method::Get], ..CorsPolicy::default() };
+    for request in &[preflight_request(Method::Delete, &[]),
+                     preflight_request(Method::Get, &["x-custom"])] {
+        let mut headers = Headers::new();
+        policy.set_origin_headers(Some("https://a.example"), &mut headers);
+        assert_eq!(policy.preflight(true, request, &mut headers), StatusCode::NoContent);
+        assert!(headers.get::<AccessControlAllowOrigin>().is_none());
+        assert!(headers.get::<AccessControlAllowMethods>().is_none());
+        // every OPTIONS request is told which methods exist
+        assert!(headers.get::<Allow>().is_some());
+    }
+}
@@ -0,0 +1,862 @@
+//! Conflict-free replicated JSON documents.
+//!
+//! A `Document` can be edited by any number of replicas at once, each recording its edits as
+//! `Op`s. Replicas that have applied the same ops hold the same value, whatever order the ops
+//! arrived in, so they can sync with each other without a central ordering. The only requirement
+//! is that an op arrives after the ops its replica had applied when it was made, which holds as
+//! long as replicas sync by sending each other `Document::ops_since` in full.
+//!
+//! Every value lives in a register. Setting a register replaces the entries its replica could
+//! see, so concurrent sets leave an entry each: the value of the document shows the entry with
+//! the greatest stamp, but `Document::values` returns all of them. Arrays are replicated growable
+//! arrays: an element is identified by the op that inserted it, stays after the element it was
+//! inserted after, and is kept as a tombstone once its register is emptied.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use json_patch;
+use json_patch::{find_path, PatchError};
+use serde_json::Value;
+
+/// A Lamport timestamp identifying an op, ordered by counter and then by replica
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stamp {
+    pub counter: u64,
+    pub replica: String,
+}
+
+/// An array element: the op that created it and its index among the elements that op created
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ElemId(pub Stamp, pub u64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key {
+    Field(String),
+    Elem(ElemId),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Op {
+    pub id: Stamp,
+    /// The register the op acts on. Starting at the root, each step picks an entry of the
+    /// current register and then the register under a key of the object or array it holds.
+    pub at: Vec<(Stamp, Key)>,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Replace the entries `pred` of the register with `value`, or just remove them
+    Set {
+        pred: Vec<Stamp>,
+        value: Option<Value>,
+    },
+    /// Insert `value` into the array held by the entry `array` of the register, after the element
+    /// `after` or at the start
+    Insert {
+        array: Stamp,
+        after: Option<ElemId>,
+        value: Value,
+    },
+}
+
+/// The greatest counter of the ops applied from each replica
+pub type Clock = BTreeMap<String, u64>;
+
+/// Why `Document::check` refuses an op from another replica
+#[derive(Debug, Clone, PartialEq)]
+pub enum Refused {
+    /// The op is stamped as made by the replica the document is edited as
+    OwnReplica,
+    /// The op's counter is more than one past that of every op applied before it, so it cannot
+    /// have been made after them
+    FutureCounter,
+    /// An op from the same replica with a greater counter has already been applied
+    OutOfOrder,
+    /// The op refers to an op that has not been applied before it
+    MissingDependency(Stamp),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Register {
+    entries: BTreeMap<Stamp, Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Leaf(Value),
+    Object(BTreeMap<String, Register>),
+    Array(Vec<Elem>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Elem {
+    id: ElemId,
+    value: Register,
+}
+
+/// The state of a `Document` that `Document::restore` returns it to
+#[derive(Debug)]
+pub struct Snapshot {
+    counter: u64,
+    root: Register,
+    /// How many ops had been applied
+    ops: usize,
+}
+
+/// One replica's copy of a document
+#[derive(Debug, Clone)]
+pub struct Document {
+    replica: String,
+    /// The greatest counter seen, so that local ops are stamped after everything applied
+    counter: u64,
+    root: Register,
+    /// Every op applied, in the order they were applied
+    ops: Vec<Op>,
+    seen: HashSet<Stamp>,
+}
+
+impl Document {
+    /// An empty document, whose value is `null`, edited locally as `replica`
+    pub fn new(replica: &str) -> Document {
+        Document {
+            replica: replica.to_string(),
+            counter: 0,
+            root: Register::default(),
+            ops: vec![],
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn value(&self) -> Value {
+        self.root.project().unwrap_or(Value::Null)
+    }
+
+    /// Every value concurrently set at `pointer`, in stamp order, so the last is the one shown in
+    /// `value`. Empty if nothing is there.
+    pub fn values(&self, pointer: &[&str]) -> Vec<Value> {
+        let pointer: Vec<String> = pointer.iter().map(|s| s.to_string()).collect();
+        let register = self.locate(&pointer).and_then(|at| register_at(&self.root, &at));
+        match register {
+            Some(register) => register.entries.values().map(Node::project).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Every op applied, in an order another replica could apply them in
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn clock(&self) -> Clock {
+        let mut clock = Clock::new();
+        for op in &self.ops {
+            let counter = clock.entry(op.id.replica.clone()).or_insert(0);
+            if op.id.counter > *counter {
+                *counter = op.id.counter;
+            }
+        }
+        clock
+    }
+
+    /// The ops applied here that a replica which has applied up to `clock` is missing, in an
+    /// order it can apply them in
+    pub fn ops_since(&self, clock: &Clock) -> Vec<Op> {
+        self.ops
+            .iter()
+            .filter(|op| clock.get(&op.id.replica).map_or(true, |&seen| op.id.counter > seen))
+            .cloned()
+            .collect()
+    }
+
+    /// Check that ops from other replicas can be applied in order, each after the ops its
+    /// replica had applied when it was made, before applying any of them. Ops that have already
+    /// been applied are skipped, as `apply` skips them. Fails with the index of the first op
+    /// refused.
+    pub fn check(&self, ops: &[Op]) -> Result<(), (usize, Refused)> {
+        let mut counter = self.counter;
+        let mut clock = self.clock();
+        let mut checked = HashSet::new();
+        for (i, op) in ops.iter().enumerate() {
+            if self.seen.contains(&op.id) || checked.contains(&op.id) {
+                continue;
+            }
+            let refused = if op.id.replica == self.replica {
+                Some(Refused::OwnReplica)
+            } else if op.id.counter > counter.saturating_add(1) {
+                Some(Refused::FutureCounter)
+            } else if clock.get(&op.id.replica).map_or(false, |&seen| op.id.counter <= seen) {
+                Some(Refused::OutOfOrder)
+            } else {
+                op.dependencies()
+                  .into_iter()
+                  .find(|&stamp| !self.seen.contains(stamp) && !checked.contains(stamp))
+                  .map(|stamp| Refused::MissingDependency(stamp.clone()))
+            };
+            if let Some(refused) = refused {
+                return Err((i, refused));
+            }
+            if op.id.counter > counter {
+                counter = op.id.counter;
+            }
+            clock.insert(op.id.replica.clone(), op.id.counter);
+            checked.insert(op.id.clone());
+        }
+        Ok(())
+    }
+
+    /// Apply an op made by any replica, returning `false` if it had already been applied
+    pub fn apply(&mut self, op: Op) -> bool {
+        if self.seen.contains(&op.id) {
+            return false;
+        }
+        if op.id.counter > self.counter {
+            self.counter = op.id.counter;
+        }
+        // an op under a value that has since been replaced or removed has nothing to change
+        if let Some(register) = register_at_mut(&mut self.root, &op.at) {
+            register.apply(&op);
+        }
+        self.seen.insert(op.id.clone());
+        self.ops.push(op);
+        true
+    }
+
+    /// The state of the document now, without the ops applied so far, which are kept anyway
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            counter: self.counter,
+            root: self.root.clone(),
+            ops: self.ops.len(),
+        }
+    }
+
+    /// Undo the ops applied since `snapshot` was taken
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        for op in self.ops.drain(snapshot.ops..) {
+            self.seen.remove(&op.id);
+        }
+        self.counter = snapshot.counter;
+        self.root = snapshot.root;
+    }
+
+    /// Make the change described by a JSON patch op to the current value, returning the ops
+    /// that carry it out for other replicas
+    pub fn edit(&mut self, op: &json_patch::Op) -> Result<Vec<Op>, PatchError> {
+        match *op {
+            json_patch::Op::Add(ref path, ref value) => self.add(path, value.clone()),
+            json_patch::Op::Remove(ref path) => self.set(path, None),
+            json_patch::Op::Replace(ref path, ref value) => self.set(path, Some(value.clone())),
+            json_patch::Op::Copy(ref path, ref from) => {
+                let value = try!(find_path(&self.value(), from).cloned().ok_or(PatchError));
+                self.add(path, value)
+            }
+            json_patch::Op::Move(ref path, ref from) => {
+                if path == from {
+                    return Ok(vec![]);
+                }
+                let value = try!(find_path(&self.value(), from).cloned().ok_or(PatchError));
+                let mut ops = try!(self.set(from, None));
+                ops.extend(try!(self.add(path, value)));
+                Ok(ops)
+            }
+            json_patch::Op::Test(ref path, ref value) => {
+                if find_path(&self.value(), path) == Some(value) {
+                    Ok(vec![])
+                } else {
+                    Err(PatchError)
+                }
+            }
+            json_patch::Op::Assert(ref path, ref predicate) => {
+                if predicate.holds(find_path(&self.value(), path)) {
+                    Ok(vec![])
+                } else {
+                    Err(PatchError)
+                }
+            }
+            json_patch::Op::Extension(ref path, ref extension) => {
+                // carried out as a replacement, so concurrent increments do not add up
+                let mut value = try!(find_path(&self.value(), path).cloned().ok_or(PatchError));
+                try!(extension.apply(&mut value));
+                self.set(path, Some(value))
+            }
+        }
+    }
+
+    fn add(&mut self, path: &[String], value: Value) -> Result<Vec<Op>, PatchError> {
+        let (token, parent) = match path.split_last() {
+            Some(split) => split,
+            None => return self.set(path, Some(value)),
+        };
+        let mut at = try!(self.locate(parent).ok_or(PatchError));
+        let action = {
+            let register = try!(register_at(&self.root, &at).ok_or(PatchError));
+            let (stamp, node) = try!(register.entries.iter().next_back().ok_or(PatchError));
+            match *node {
+                Node::Object(ref fields) => {
+                    at.push((stamp.clone(), Key::Field(token.clone())));
+                    Action::Set {
+                        pred: fields.get(token).map_or(vec![], Register::stamps),
+                        value: Some(value),
+                    }
+                }
+                Node::Array(ref elems) => {
+                    let visible: Vec<&ElemId> = elems.iter()
+                                                     .filter(|e| !e.value.entries.is_empty())
+                                                     .map(|e| &e.id)
+                                                     .collect();
+                    let index = if token == "-" {
+                        visible.len()
+                    } else {
+                        try!(token.parse::<usize>().map_err(|_| PatchError))
+                    };
+                    if index > visible.len() {
+                        return Err(PatchError);
+                    }
+                    Action::Insert {
+                        array: stamp.clone(),
+                        after: if index == 0 {
+                            None
+                        } else {
+                            Some(visible[index - 1].clone())
+                        },
+                        value: value,
+                    }
+                }
+                Node::Leaf(_) => return Err(PatchError),
+            }
+        };
+        Ok(vec![self.local(at, action)])
+    }
+
+    /// Replace or remove the value at an existing `path`
+    fn set(&mut self, path: &[String], value: Option<Value>) -> Result<Vec<Op>, PatchError> {
+        let at = try!(self.locate(path).ok_or(PatchError));
+        let pred = try!(register_at(&self.root, &at).ok_or(PatchError)).stamps();
+        if pred.is_empty() && !path.is_empty() {
+            return Err(PatchError);
+        }
+        let action = Action::Set {
+            pred: pred,
+            value: value,
+        };
+        Ok(vec![self.local(at, action)])
+    }
+
+    /// Stamp and apply an op made by this replica
+    fn local(&mut self, at: Vec<(Stamp, Key)>, action: Action) -> Op {
+        let op = Op {
+            id: Stamp {
+                counter: self.counter + 1,
+                replica: self.replica.clone(),
+            },
+            at: at,
+            action: action,
+        };
+        self.apply(op.clone());
+        op
+    }
+
+    /// The steps to the register holding the value at `pointer`, following the entries shown
+    /// in `value`. The register at the root is always there, even when it is empty.
+    fn locate(&self, pointer: &[String]) -> Option<Vec<(Stamp, Key)>> {
+        let mut at = vec![];
+        let mut register = &self.root;
+        for token in pointer {
+            let (stamp, node) = match register.entries.iter().next_back() {
+                Some(entry) => entry,
+                None => return None,
+            };
+            let (key, child) = match *node {
+                Node::Object(ref fields) => {
+                    match fields.get(token) {
+                        Some(child) if !child.entries.is_empty() => {
+                            (Key::Field(token.clone()), child)
+                        }
+                        _ => return None,
+                    }
+                }
+                Node::Array(ref elems) => {
+                    let elem = token.parse::<usize>().ok().and_then(|index| {
+                        elems.iter().filter(|e| !e.value.entries.is_empty()).nth(index)
+                    });
+                    match elem {
+                        Some(elem) => (Key::Elem(elem.id.clone()), &elem.value),
+                        None => return None,
+                    }
+                }
+                Node::Leaf(_) => return None,
+            };
+            at.push((stamp.clone(), key));
+            register = child;
+        }
+        Some(at)
+    }
+}
+
+impl Register {
+    fn holding(stamp: Stamp, node: Node) -> Register {
+        let mut entries = BTreeMap::new();
+        entries.insert(stamp, node);
+        Register { entries: entries }
+    }
+
+    fn stamps(&self) -> Vec<Stamp> {
+        self.entries.keys().cloned().collect()
+    }
+
+    /// The value of the entry with the greatest stamp, if there are any
+    fn project(&self) -> Option<Value> {
+        self.entries.values().next_back().map(Node::project)
+    }
+
+    fn apply(&mut self, op: &Op) {
+        match op.action {
+            Action::Set { ref pred, ref value } => {
+                for stamp in pred {
+                    self.entries.remove(stamp);
+                }
+                if let Some(ref value) = *value {
+                    self.entries.insert(op.id.clone(), build(value, &op.id));
+                }
+            }
+            Action::Insert { ref array, ref after, ref value } => {
+                let elems = match self.entries.get_mut(array) {
+                    Some(&mut Node::Array(ref mut elems)) => elems,
+                    _ => return,
+                };
+                let start = match *after {
+                    Some(ref after) => {
+                        match elems.iter().position(|e| e.id == *after) {
+                            Some(i) => i + 1,
+                            None => return,
+                        }
+                    }
+                    None => 0,
+                };
+                // elements inserted concurrently at the same place go in descending stamp order,
+                // each followed by the elements inserted after it
+                let id = ElemId(op.id.clone(), 0);
+                let index = start + elems[start..].iter().take_while(|e| e.id > id).count();
+                elems.insert(index,
+                             Elem {
+                                 id: id,
+                                 value: Register::holding(op.id.clone(), build(value, &op.id)),
+                             });
+            }
+        }
+    }
+}
+
+impl Node {
+    fn project(&self) -> Value {
+        match *self {
+            Node::Leaf(ref value) => value.clone(),
+            Node::Object(ref fields) => {
+                Value::Object(fields.iter()
+                                    .filter_map(|(k, r)| r.project().map(|v| (k.clone(), v)))
+                                    .collect())
+            }
+            Node::Array(ref elems) => {
+                Value::Array(elems.iter().filter_map(|e| e.value.project()).collect())
+            }
+        }
+    }
+}
+
+/// The node for a value set by the op `id`, whose registers and elements are all stamped `id`
+fn build(value: &Value, id: &Stamp) -> Node {
+    match *value {
+        Value::Object(ref fields) => {
+            Node::Object(fields.iter()
+                               .map(|(k, v)| {
+                                   (k.clone(), Register::holding(id.clone(), build(v, id)))
+                               })
+                               .collect())
+        }
+        Value::Array(ref items) => {
+            Node::Array(items.iter()
+                             .enumerate()
+                             .map(|(i, v)| {
+                                 Elem {
+                                     id: ElemId(id.clone(), i as u64),
+                                     value: Register::holding(id.clone(), build(v, id)),
+                                 }
+                             })
+                             .collect())
+        }
+        ref leaf => Node::Leaf(leaf.clone()),
+    }
+}
+
+fn register_at<'a>(mut register: &'a Register, at: &[(Stamp, Key)]) -> Option<&'a Register> {
+    for &(ref stamp, ref key) in at {
+        register = match (register.entries.get(stamp), key) {
+            (Some(&Node::Object(ref fields)), &Key::Field(ref name)) => {
+                match fields.get(name) {
+                    Some(child) => child,
+                    None => return None,
+                }
+            }
+            (Some(&Node::Array(ref elems)), &Key::Elem(ref id)) => {
+                match elems.iter().find(|e| e.id == *id) {
+                    Some(elem) => &elem.value,
+                    None => return None,
+                }
+            }
+            _ => return None,
+        };
+    }
+    Some(register)
+}
+
+/// As `register_at`, but creating the registers of object fields that have never been set
+fn register_at_mut<'a>(mut register: &'a mut Register,
+                       at: &[(Stamp, Key)])
+                       -> Option<&'a mut Register> {
+    for &(ref stamp, ref key) in at {
+        register = match ({ register }.entries.get_mut(stamp), key) {
+            (Some(&mut Node::Object(ref mut fields)), &Key::Field(ref name)) => {
+                fields.entry(name.clone()).or_insert_with(Register::default)
+            }
+            (Some(&mut Node::Array(ref mut elems)), &Key::Elem(ref id)) => {
+                match elems.iter_mut().find(|e| e.id == *id) {
+                    Some(elem) => &mut elem.value,
+                    None => return None,
+                }
+            }
+            _ => return None,
+        };
+    }
+    Some(register)
+}
+
+impl Op {
+    /// The stamps of the ops this one refers to, which must be applied before it
+    fn dependencies(&self) -> Vec<&Stamp> {
+        let mut stamps = vec![];
+        for &(ref stamp, ref key) in &self.at {
+            stamps.push(stamp);
+            if let Key::Elem(ref elem) = *key {
+                stamps.push(&elem.0);
+            }
+        }
+        match self.action {
+            Action::Set { ref pred, .. } => stamps.extend(pred),
+            Action::Insert { ref array, ref after, .. } => {
+                stamps.push(array);
+                stamps.extend(after.as_ref().map(|after| &after.0));
+            }
+        }
+        stamps
+    }
+
+    /// The op as JSON, e.g.
+    /// `{"id":[2,"a"],"at":[[[1,"a"],"k"]],"op":"set","pred":[[1,"a"]],"value":3}`; elements
+    /// are keyed by `[counter,replica,index]` and a `set` without a `value` removes
+    pub fn to_value(&self) -> Value {
+        let mut o = BTreeMap::new();
+        o.insert("id".to_string(), stamp_to_value(&self.id));
+        let at = self.at
+                     .iter()
+                     .map(|&(ref stamp, ref key)| {
+                         let key = match *key {
+                             Key::Field(ref name) => Value::String(name.clone()),
+                             Key::Elem(ref id) => elem_to_value(id),
+                         };
+                         Value::Array(vec![stamp_to_value(stamp), key])
+                     })
+                     .collect();
+        o.insert("at".to_string(), Value::Array(at));
+        match self.action {
+            Action::Set { ref pred, ref value } => {
+                o.insert("op".to_string(), Value::String("set".into()));
+                o.insert("pred".to_string(),
+                         Value::Array(pred.iter().map(stamp_to_value).collect()));
+                if let Some(ref value) = *value {
+                    o.insert("value".to_string(), value.clone());
+                }
+            }
+            Action::Insert { ref array, ref after, ref value } => {
+                o.insert("op".to_string(), Value::String("insert".into()));
+                o.insert("array".to_string(), stamp_to_value(array));
+                o.insert("after".to_string(), after.as_ref().map_or(Value::Null, elem_to_value));
+                o.insert("value".to_string(), value.clone());
+            }
+        }
+        Value::Object(o)
+    }
+
+    pub fn from_value(value: &Value) -> Result<Op, String> {
+        let id = try!(value.find("id").ok_or("op must have an id".to_string()));
+        let at = match value.find("at").and_then(|at| at.as_array()) {
+            Some(at) => at,
+            None => return Err("op must have an array at".into()),
+        };
+        let at = try!(at.iter()
+                        .map(|step| {
+                            let step = match step.as_array() {
+                                Some(step) if step.len() == 2 => step,
+                                _ => return Err("each step must be a [stamp, key] pair".into()),
+                            };
+                            let key = match step[1] {
+                                Value::String(ref name) => Key::Field(name.clone()),
+                                ref id => Key::Elem(try!(elem_from_value(id))),
+                            };
+                            Ok((try!(stamp_from_value(&step[0])), key))
+                        })
+                        .collect::<Result<Vec<_>, String>>());
+        let action = match value.find("op").and_then(|op| op.as_string()) {
+            Some("set") => {
+                let pred = match value.find("pred").and_then(|pred| pred.as_array()) {
+                    Some(pred) => try!(pred.iter().map(stamp_from_value).collect()),
+                    None => return Err("set must have an array pred".into()),
+                };
+                Action::Set {
+                    pred: pred,
+                    value: value.find("value").cloned(),
+                }
+            }
+            Some("insert") => {
+                let array = try!(value.find("array")
+                                      .ok_or("insert must have an array".to_string())
+                                      .and_then(stamp_from_value));
+                let after = match value.find("after") {
+                    None | Some(&Value::Null) => None,
+                    Some(after) => Some(try!(elem_from_value(after))),
+                };
+                Action::Insert {
+                    array: array,
+                    after: after,
+                    value: try!(value.find("value")
+                                     .cloned()
+                                     .ok_or("insert must have a value".to_string())),
+                }
+            }
+            _ => return Err("op must be set or insert".into()),
+        };
+        Ok(Op {
+            id: try!(stamp_from_value(id)),
+            at: at,
+            action: action,
+        })
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.to_value())
+    }
+}
+
+pub fn clock_to_value(clock: &Clock) -> Value {
+    Value::Object(clock.iter().map(|(r, &c)| (r.clone(), Value::U64(c))).collect())
+}
+
+pub fn clock_from_value(value: &Value) -> Result<Clock, String> {
+    let error = || "clock must be an object of replica counters".to_string();
+    match value.as_object() {
+        Some(o) => {
+            o.iter()
+             .map(|(r, c)| c.as_u64().map(|c| (r.clone(), c)).ok_or_else(&error))
+             .collect()
+        }
+        None => Err(error()),
+    }
+}
+
+fn stamp_to_value(stamp: &Stamp) -> Value {
+    Value::Array(vec![Value::U64(stamp.counter), Value::String(stamp.replica.clone())])
+}
+
+fn elem_to_value(id: &ElemId) -> Value {
+    Value::Array(vec![Value::U64((id.0).counter),
+                      Value::String((id.0).replica.clone()),
+                      Value::U64(id.1)])
+}
+
+fn stamp_from_value(value: &Value) -> Result<Stamp, String> {
+    match value.as_array() {
+        Some(a) if a.len() == 2 => {
+            match (a[0].as_u64(), a[1].as_string()) {
+                (Some(counter), Some(replica)) => {
+                    return Ok(Stamp {
+                        counter: counter,
+                        replica: replica.to_string(),
+                    })
+                }
+                _ => (),
+            }
+        }
+        _ => (),
+    }
+    Err(format!("{:?} is not a [counter, replica] stamp", value))
+}
+
+fn elem_from_value(value: &Value) -> Result<ElemId, String> {
+    match value.as_array() {
+        Some(a) if a.len() == 3 => {
+            let index = try!(a[2].as_u64().ok_or(format!("{:?} has no element index", value)));
+            stamp_from_value(&Value::Array(a[..2].to_vec())).map(|stamp| ElemId(stamp, index))
+        }
+        _ => Err(format!("{:?} is not a [counter, replica, index] element id", value)),
+    }
+}
+
+#[cfg(test)]
+fn parse(s: &str) -> Value {
+    ::serde_json::from_str(s).unwrap()
+}
+
+/// The ops carrying out a JSON patch on `doc`
+#[cfg(test)]
+fn edit(doc: &mut Document, patch: &str) -> Vec<Op> {
+    let patch = json_patch::Patch::from_str(patch).unwrap();
+    patch.ops.iter().flat_map(|op| doc.edit(op).unwrap()).collect()
+}
+
+/// A new replica that has applied `ops`
+#[cfg(test)]
+fn replay(replica: &str, ops: &[Op]) -> Document {
+    let mut doc = Document::new(replica);
+    for op in ops {
+        doc.apply(op.clone());
+    }
+    doc
+}
+
+#[test]
+fn ops_converge_in_any_causal_order() {
+    let mut a = Document::new("a");
+    let base = edit(&mut a, r#"[{"op":"add","path":"","value":{"l":[1,2,3],"o":{"k":0}}}]"#);
+    let mut b = replay("b", &base);
+    let ours = edit(&mut a,
+                    r#"[{"op":"add","path":"/l/1","value":"a"},
+                        {"op":"replace","path":"/o/k","value":"a"},
+                        {"op":"remove","path":"/l/3"}]"#);
+    let theirs = edit(&mut b,
+                      r#"[{"op":"remove","path":"/l/0"},
+                          {"op":"add","path":"/o/n","value":"b"},
+                          {"op":"add","path":"/l/-","value":"b"}]"#);
+
+    let orders = vec![[&ours[..], &theirs[..]].concat(),
+                      [&theirs[..], &ours[..]].concat(),
+                      vec![ours[0].clone(),
+                           theirs[0].clone(),
+                           theirs[1].clone(),
+                           ours[1].clone(),
+                           theirs[2].clone(),
+                           ours[2].clone()]];
+    let values: Vec<Value> = orders.iter()
+                                   .map(|ops| replay("c", &[&base[..], &ops[..]].concat()).value())
+                                   .collect();
+    assert_eq!(values[0], parse(r#"{"l":["a",2,"b"],"o":{"k":"a","n":"b"}}"#));
+    assert!(values.iter().all(|value| *value == values[0]));
+}
+
+#[test]
+fn concurrent_inserts_at_the_same_position_keep_both() {
+    let mut a = Document::new("a");
+    let base = edit(&mut a, r#"[{"op":"add","path":"","value":[0,9]}]"#);
+    let mut b = replay("b", &base);
+    let ours = edit(&mut a,
+                    r#"[{"op":"add","path":"/1","value":"a1"},
+                        {"op":"add","path":"/2","value":"a2"}]"#);
+    let theirs = edit(&mut b, r#"[{"op":"add","path":"/1","value":"b"}]"#);
+    for op in &theirs {
+        a.apply(op.clone());
+    }
+    for op in &ours {
+        b.apply(op.clone());
+    }
+    // the greater stamp goes first, and each run stays together
+    assert_eq!(a.value(), parse(r#"[0,"b","a1","a2",9]"#));
+    assert_eq!(b.value(), a.value());
+}
+
+#[test]
+fn concurrent_set_and_remove_keep_the_set() {
+    let mut a = Document::new("a");
+    let base = edit(&mut a, r#"[{"op":"add","path":"","value":{"x":1,"y":1}}]"#);
+    let mut b = replay("b", &base);
+    let mut c = replay("c", &base);
+    let set = edit(&mut a, r#"[{"op":"replace","path":"/x","value":2}]"#);
+    let removed = edit(&mut b,
+                       r#"[{"op":"remove","path":"/x"},{"op":"replace","path":"/y","value":3}]"#);
+    let other_set = edit(&mut c, r#"[{"op":"replace","path":"/y","value":4}]"#);
+    let all = [&base[..], &set[..], &removed[..], &other_set[..]].concat();
+    let doc = replay("d", &all);
+    // a removal only removes the values its replica could see, and of concurrent sets the one
+    // with the greatest stamp is shown
+    assert_eq!(doc.value(), parse(r#"{"x":2,"y":3}"#));
+    assert_eq!(doc.values(&["y"]), vec![Value::U64(4), Value::U64(3)]);
+    assert_eq!(replay("e", &[&base[..], &other_set[..], &removed[..], &set[..]].concat()).value(),
+               doc.value());
+}
+
+#[test]
+fn ops_round_trip_through_json() {
+    let mut a = Document::new("a");
+    let mut ops = edit(&mut a, r#"[{"op":"add","path":"","value":{"l":[{"k":1}]}}]"#);
+    ops.extend(edit(&mut a,
+                    r#"[{"op":"add","path":"/l/0","value":null},
+                        {"op":"add","path":"/l/-","value":[true]},
+                        {"op":"replace","path":"/l/1/k","value":"v"},
+                        {"op":"remove","path":"/l/0"}]"#));
+    for op in &ops {
+        assert_eq!(Op::from_value(&op.to_value()), Ok(op.clone()));
+        assert_eq!(Op::from_value(&parse(&op.to_string())), Ok(op.clone()));
+    }
+    assert!(Op::from_value(&parse(r#"{"id":[1,"a"],"at":[],"op":"insert"}"#)).is_err());
+}
+
+#[test]
+fn ops_from_other_replicas_are_checked() {
+    let mut a = Document::new("a");
+    let base = edit(&mut a, r#"[{"op":"add","path":"","value":{"l":[]}}]"#);
+    let mut c = replay("c", &base);
+    let others = edit(&mut c,
+                      r#"[{"op":"add","path":"/c","value":1},
+                          {"op":"add","path":"/d","value":1}]"#);
+    let server = replay("server", &[&base[..], &others[..]].concat());
+    let first = edit(&mut a, r#"[{"op":"add","path":"/l/0","value":1}]"#);
+    let second = edit(&mut a, r#"[{"op":"add","path":"/l/1","value":2}]"#);
+    assert_eq!(server.check(&[first[0].clone(), second[0].clone()]), Ok(()));
+    // ops already applied are skipped
+    assert_eq!(server.check(&[base[0].clone(), first[0].clone()]), Ok(()));
+    assert_eq!(server.check(&second),
+               Err((0, Refused::MissingDependency(first[0].id.clone()))));
+    assert_eq!(server.check(&[second[0].clone(), first[0].clone()]),
+               Err((0, Refused::MissingDependency(first[0].id.clone()))));
+
+    let mut forged = first[0].clone();
+    forged.id.replica = "server".into();
+    assert_eq!(server.check(&[forged.clone()]), Err((0, Refused::OwnReplica)));
+    forged.id = Stamp {
+        counter: u64::max_value(),
+        replica: "b".into(),
+    };
+    assert_eq!(server.check(&[forged.clone()]), Err((0, Refused::FutureCounter)));
+    forged.id.counter = 2;
+    assert_eq!(server.check(&[forged.clone()]), Ok(()));
+    let mut stale = forged.clone();
+    stale.id.counter = 1;
+    assert_eq!(server.check(&[forged, stale]), Err((1, Refused::OutOfOrder)));
+}
+
+#[test]
+fn restoring_a_snapshot_undoes_later_ops() {
+    let mut a = Document::new("a");
+    let base = edit(&mut a, r#"[{"op":"add","path":"","value":{"l":[1]}}]"#);
+    let snapshot = a.snapshot();
+    let later = edit(&mut a,
+                     r#"[{"op":"add","path":"/l/-","value":2},{"op":"remove","path":"/l/0"}]"#);
+    a.restore(snapshot);
+    assert_eq!(a.value(), parse(r#"{"l":[1]}"#));
+    assert_eq!(a.ops(), &base[..]);
+    // the counter goes back too, so new ops are stamped as the undone ones were
+    let again = edit(&mut a, r#"[{"op":"replace","path":"/l/0","value":3}]"#);
+    assert_eq!(again[0].id, later[0].id);
+    assert_eq!(a.value(), parse(r#"{"l":[3]}"#));
+}
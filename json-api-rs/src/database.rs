@@ -1,37 +1,283 @@
 use std::io;
 use std::io::prelude::*;
-use std::collections::HashMap;
-use std::fs::{File, create_dir_all, OpenOptions};
-use std::path::Path;
-use std::sync::{RwLock, PoisonError};
-use json_patch::{apply, Op, Patch, InvalidPatchError, PatchError};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, create_dir_all, read_dir, remove_file, rename, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::str::FromStr;
+use std::u64;
+use std::usize;
+use std::sync::{Mutex, RwLock, PoisonError};
+use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use json_patch::{apply, conflicts, diff, expand_parents, find_path, format_pointer, merge3,
+                 resolve_appends, transform, ApplyOptions, Conflict, ConflictKind, MergeResult, Op,
+                 ParseOptions, Patch, InvalidPatchError, PatchError, TestFailure};
+use serde_json;
 use serde_json::Value;
 
+use crdt;
+use crdt::Clock;
+use etag::IfMatch;
+use limits;
+use limits::{DocLimits, Limit, Quotas};
+use log_file;
+use log_file::RecordError;
 use shared_value::SharedValue;
-use patch_helpers::prefix_patch_paths;
+use schema;
+use schema::{ValidationError, SCHEMAS_DOC};
 
 /// A `Doc` wraps a shared value and writes all successfully applied patches to an owned `Write`
 pub struct Doc<W: Write> {
     value: SharedValue,
     version: usize,
-    writer: W,
+    /// Size of the log on disk, for enforcing `DocLimits::max_log_bytes`
+    log_bytes: u64,
+    /// Opened on the first write and closed again when too many files are open
+    writer: Option<W>,
+    /// Serialized size of the value, only tracked when the cache is limited by bytes
+    bytes: u64,
+    /// Tick of the database clock at which the document was last used
+    last_used: AtomicUsize,
+    /// The most recently committed patches, for rebasing edits made against older versions,
+    /// with their appends resolved to indices. Starts empty when the document is loaded.
+    history: VecDeque<Patch>,
+}
+
+/// How many committed patches each document keeps in `Doc::history`
+const HISTORY_LEN: usize = 100;
+
+/// Room in an import record for the id and version around a value of the largest allowed size
+pub const RECORD_ENVELOPE_BYTES: u64 = 4096;
+
+/// Subdirectory of the data directory holding the logs of CRDT documents, one op per line
+const CRDT_DIR: &'static str = "_crdt";
+/// The replica that edits made through `Database::patch_crdt` are made as
+pub const SERVER_REPLICA: &'static str = "server";
+
+/// A CRDT document, whose log holds the ops applied to it rather than patches
+struct CrdtDoc {
+    doc: crdt::Document,
+    writer: Option<File>,
+    /// Size of the log on disk, for enforcing `DocLimits::max_log_bytes`
+    log_bytes: u64,
+    /// Serialized size of the value, only tracked when the cache is limited by bytes
+    bytes: u64,
+    /// Tick of the database clock at which the document was last used
+    last_used: usize,
 }
 
 pub struct Database<W: Write> {
     dir: String,
+    durability: Durability,
+    quotas: Quotas,
+    cache: CacheLimits,
+    stats: DbStats,
+    /// Logical clock ordering document uses, for least-recently-used eviction
+    clock: AtomicUsize,
     docs: RwLock<HashMap<String, Doc<W>>>,
+    /// How many times a document has left `docs` or had its log replaced by an import
+    unloads: AtomicUsize,
+    /// Loaded on first use and held to the cache limits apart from `docs`
+    crdts: Mutex<HashMap<String, CrdtDoc>>,
+    subscribers: Mutex<HashMap<String, Vec<Sender<Change>>>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DbOptions {
+    pub durability: Durability,
+    pub quotas: Quotas,
+    pub cache: CacheLimits,
+}
+
+/// How much of the database may be held in memory. When a limit is exceeded the least recently
+/// used documents are evicted (or, for `max_open_files`, have their log closed) and are loaded
+/// again from disk the next time they are needed. CRDT documents are counted separately, and
+/// held to the same limits.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheLimits {
+    pub max_docs: Option<usize>,
+    /// Total serialized size of the documents in memory
+    pub max_bytes: Option<u64>,
+    pub max_open_files: Option<usize>,
+}
+
+/// Running totals of the work done by a `Database`, for monitoring
+#[derive(Debug, Default)]
+pub struct DbStats {
+    pub patches_applied: AtomicUsize,
+    pub patches_rejected: AtomicUsize,
+    pub log_bytes_written: AtomicUsize,
+    pub loads: AtomicUsize,
+    pub evictions: AtomicUsize,
+    pub writers_closed: AtomicUsize,
+}
+
+/// What the cache currently holds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub docs: usize,
+    pub bytes: u64,
+    pub open_writers: usize,
+}
+
+/// How far a patch is pushed towards the disk before `patch_doc` returns
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// Hand the patch to the operating system, which survives a crash of this process
+    Os,
+    /// `fsync` the log after every patch, which survives a crash of the machine
+    Fsync,
+}
+
+impl Default for Durability {
+    fn default() -> Durability {
+        Durability::Os
+    }
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Durability, String> {
+        match s {
+            "os" => Ok(Durability::Os),
+            "fsync" => Ok(Durability::Fsync),
+            _ => Err(format!("durability must be \"os\" or \"fsync\", not {:?}", s)),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum DbError {
     IoError(io::Error),
     PatchError(PatchError),
+    /// A test in a patch did not hold
+    TestFailed(TestFailure),
     InvalidPatchError(InvalidPatchError),
+    /// The record with this (1-based) number in a document's log could not be replayed
+    CorruptLog(usize, RecordError),
     DocumentDoesNotExist,
     PathDoesNotExist,
+    ValidationError(Vec<ValidationError>),
+    LimitExceeded(Limit),
+    /// The value at the patched path did not satisfy an `If-Match` condition
+    PreconditionFailed,
+    /// A value was appended to something other than an array
+    NotAnArray,
+    /// A concurrent edit was based on a version that is not in the document's recent history
+    UnknownVersion(usize),
+    /// A concurrent edit cannot be rebased over a patch committed since its base version, because
+    /// it tests a value that patch changed or copies or moves values entangled with its changes
+    Conflict(Vec<Conflict>),
+    /// A merged edit changed values that were changed differently since its base. The result
+    /// holds the conflicts, and the merge with the edit's side taken in each.
+    MergeConflict(MergeResult),
+    /// Line `n` of an import could not be restored
+    InvalidImport(usize, String),
+    /// A new document was given an id that the data directory keeps for other files, such as
+    /// the backups left by `json-api-admin`
+    ReservedId,
+    /// A new document was given the id of a document of the other kind. Plain and CRDT
+    /// documents share one namespace of ids, as they share the grants on them.
+    IdInUse,
+    /// Op `n` (from 0) of a CRDT sync was refused, and none of them were applied
+    RefusedCrdtOp(usize, crdt::Refused),
     PoisonError,
 }
 
+/// A change to the value at a pointer in a document
+#[derive(Debug)]
+pub enum Edit {
+    /// Apply a JSON patch whose paths are relative to the pointer
+    Patch(Patch, ApplyOptions),
+    /// Set the value at the pointer, replacing it if it exists. The parent must exist unless
+    /// `mkdirs` is set, in which case missing ancestors are created as for
+    /// `ApplyOptions::create_parents`.
+    Put { value: Value, mkdirs: bool },
+    /// Remove the value at the pointer, or the whole document at the empty pointer
+    Delete,
+    /// Append to the array at the pointer
+    Append(Value),
+    /// Apply a patch made against version `base`, rebasing it over the patches committed since.
+    /// `author` is passed on to subscribers in the resulting `Change`.
+    Concurrent {
+        patch: Patch,
+        base: usize,
+        author: Option<String>,
+    },
+    /// Merge `value`, made offline from `base`, into the current value at the pointer. Fails
+    /// unless every value both changed was changed the same way.
+    Merge { base: Value, value: Value },
+}
+
+/// A change to a document, as sent to its subscribers
+#[derive(Debug, Clone)]
+pub enum Change {
+    /// `patch`, relative to the document root, was committed as `version`
+    Patched {
+        version: usize,
+        patch: Patch,
+        author: Option<String>,
+    },
+    /// The document was deleted or replaced by an import, so subscribers must start over
+    Reset,
+}
+
+/// What an `Edit` did
+#[derive(Debug)]
+pub struct Edited {
+    /// The value at `path` afterwards, `None` if it was removed
+    pub value: Option<Value>,
+    /// The pointer the edit was made at, or for `Append` the new element
+    pub path: Vec<String>,
+    /// Whether the value at `path` is new rather than changed
+    pub created: bool,
+    /// The document version after the edit, 0 once the document is deleted
+    pub version: usize,
+}
+
+/// A document being restored by `Database::import`
+struct Staged {
+    id: String,
+    /// Where its log is written until it replaces the document's
+    temp: PathBuf,
+    file: File,
+    /// The value so far of a plain document
+    value: Value,
+    /// A CRDT document, which is restored from ops rather than patches
+    crdt: Option<crdt::Document>,
+    log_bytes: u64,
+    limits: DocLimits,
+}
+
+/// What a record of an import adds to its document
+enum Imported {
+    Patch(Patch),
+    Op(crdt::Op),
+}
+
+/// The patch that carries out an `Edit`, with paths relative to the document root
+struct Planned {
+    patch: Patch,
+    path: Vec<String>,
+    created: bool,
+    /// Whether the value at `path` is gone afterwards, which for an array element does not
+    /// leave the path empty
+    removed: bool,
+}
+
+/// The shape of the records written by `Database::export` for plain documents. CRDT
+/// documents are always written as one `{"crdt", "op"}` record per op in their log, as other
+/// replicas can only merge with the ops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// One `{"id", "version", "value"}` record per document
+    Snapshot,
+    /// One `{"id", "version", "patch"}` record per log record, preserving history and versions
+    Log,
+}
+
 wrap_error!(InvalidPatchError, DbError::InvalidPatchError);
 wrap_error!(PatchError, DbError::PatchError);
 wrap_error!(io::Error, DbError::IoError);
@@ -42,82 +288,1493 @@ impl <T> From<PoisonError<T>> for DbError {
     }
 }
 
+impl DbError {
+    /// The HTTP status for the error and a message for the client. Errors that are not the
+    /// client's doing are logged, and described to the client only as internal.
+    pub fn status(&self) -> (u16, String) {
+        match *self {
+            DbError::DocumentDoesNotExist => (404, "no such document".into()),
+            DbError::PathDoesNotExist => (404, "path does not exist".into()),
+            DbError::PatchError(_) => (409, "patch could not be applied".into()),
+            DbError::TestFailed(ref failure) => (409, failure.to_string()),
+            DbError::NotAnArray => (409, "values can only be appended to arrays".into()),
+            DbError::Conflict(ref conflicts) => {
+                let pointers: Vec<String> = conflicts.iter()
+                                                     .map(|c| format_pointer(&c.first))
+                                                     .collect();
+                (409,
+                 format!("patch conflicts at {} with a change since its base version",
+                         pointers.join(", ")))
+            }
+            DbError::MergeConflict(ref merged) => {
+                let pointers: Vec<String> = merged.conflicts
+                                                  .iter()
+                                                  .map(|c| format_pointer(&c.path))
+                                                  .collect();
+                (409, format!("{} changed differently since the base", pointers.join(", ")))
+            }
+            DbError::UnknownVersion(base) => {
+                (409, format!("version {} is too old to rebase from; subscribe again", base))
+            }
+            DbError::PreconditionFailed => {
+                (412, "the target does not match the If-Match header".into())
+            }
+            DbError::LimitExceeded(limit) => (413, limit.to_string()),
+            DbError::ValidationError(_) => (422, "document failed schema validation".into()),
+            DbError::InvalidImport(line, ref message) => {
+                (400, format!("line {}: {}", line, message))
+            }
+            DbError::ReservedId => {
+                (400, "document ids may not start with '.' or end in .bak or .compact".into())
+            }
+            DbError::IdInUse => (409, "the id is taken by a document of the other kind".into()),
+            DbError::RefusedCrdtOp(n, crdt::Refused::MissingDependency(ref stamp)) => {
+                (409,
+                 format!("op {} depends on op [{},{:?}], which must be sent first",
+                         n,
+                         stamp.counter,
+                         stamp.replica))
+            }
+            DbError::RefusedCrdtOp(n, ref refused) => {
+                let reason = match *refused {
+                    crdt::Refused::OwnReplica => "is stamped with the server's replica",
+                    crdt::Refused::FutureCounter => "has a counter too far ahead of the document",
+                    crdt::Refused::OutOfOrder |
+                    crdt::Refused::MissingDependency(_) => {
+                        "comes before an op of its replica that was already applied"
+                    }
+                };
+                (400, format!("op {} {}", n, reason))
+            }
+            DbError::IoError(_) |
+            DbError::InvalidPatchError(_) |
+            DbError::CorruptLog(..) |
+            DbError::PoisonError => {
+                error!("internal error: {:?}", self);
+                (500, "internal error".into())
+            }
+        }
+    }
+}
+
 impl Database<File> {
-    pub fn open(dir: &str) -> Result<Database<File>, io::Error> {
+    pub fn open(dir: &str, options: DbOptions) -> Result<Database<File>, io::Error> {
         try!(create_dir_all(Path::new(dir)));
         Ok(Database {
             dir: dir.to_string(),
+            durability: options.durability,
+            quotas: options.quotas,
+            cache: options.cache,
+            stats: DbStats::default(),
+            clock: AtomicUsize::new(0),
             docs: RwLock::new(HashMap::new()),
+            unloads: AtomicUsize::new(0),
+            crdts: Mutex::new(HashMap::new()),
+            subscribers: Mutex::new(HashMap::new()),
         })
     }
 
-    pub fn find_in_doc(&self, id: &str, path: &[&str]) -> Result<Value, DbError> {
+    pub fn stats(&self) -> &DbStats {
+        &self.stats
+    }
+
+    pub fn cache_stats(&self) -> Result<CacheStats, DbError> {
         let live_docs = try!(self.docs.read());
-        if let Some(doc) = live_docs.get(id) {
-            doc.value.clone_path(path).ok_or(DbError::PathDoesNotExist)
-        } else {
-            Err(DbError::DocumentDoesNotExist)
+        Ok(CacheStats {
+            docs: live_docs.len(),
+            bytes: resident_bytes(&live_docs),
+            open_writers: live_docs.values().filter(|doc| doc.writer.is_some()).count(),
+        })
+    }
+
+    pub fn find_in_doc(&self, id: &str, path: &[&str]) -> Result<Value, DbError> {
+        self.open_doc(id, |doc| doc.value.clone_path(path).ok_or(DbError::PathDoesNotExist))
+    }
+
+    /// Apply `patch` below `prefix` in a document, returning the new value at `prefix`.
+    ///
+    /// If `if_match` is given the patch is only applied when the value currently at `prefix`
+    /// satisfies it, so concurrent edits to other parts of the document do not conflict.
+    pub fn patch_doc(&self,
+                     id: &str,
+                     patch: Patch,
+                     prefix: &[&str],
+                     if_match: Option<&IfMatch>)
+                     -> Result<Value, DbError> {
+        self.edit_doc(id, prefix, Edit::Patch(patch, ApplyOptions::default()), if_match)
+            .and_then(|edited| edited.value.ok_or(DbError::PathDoesNotExist))
+    }
+
+    /// Make an `Edit` at `prefix` in a document, subject to `if_match` as for `patch_doc`
+    pub fn edit_doc(&self,
+                    id: &str,
+                    prefix: &[&str],
+                    edit: Edit,
+                    if_match: Option<&IfMatch>)
+                    -> Result<Edited, DbError> {
+        let result = match edit {
+            Edit::Delete if prefix.is_empty() => self.delete_doc(id, if_match),
+            edit => self.apply_to_doc(id, prefix, edit, if_match, false),
+        };
+        if result.is_err() {
+            self.stats.patches_rejected.fetch_add(1, Ordering::Relaxed);
         }
+        result
+    }
+
+    /// What `edit_doc` would do with a patch, including checking limits and the schema, without
+    /// committing it
+    pub fn preview_patch(&self,
+                         id: &str,
+                         prefix: &[&str],
+                         patch: Patch,
+                         options: ApplyOptions,
+                         if_match: Option<&IfMatch>)
+                         -> Result<Edited, DbError> {
+        self.apply_to_doc(id, prefix, Edit::Patch(patch, options), if_match, true)
     }
 
-    pub fn patch_doc(&self, id: &str, patch: Patch, prefix: &[&str]) -> Result<Value, DbError> {
+    fn apply_to_doc(&self,
+                    id: &str,
+                    prefix: &[&str],
+                    edit: Edit,
+                    if_match: Option<&IfMatch>,
+                    dry_run: bool)
+                    -> Result<Edited, DbError> {
         let mut live_docs = try!(self.docs.write());
+        let result = self.edit_loaded(&mut live_docs, id, prefix, edit, if_match, dry_run);
+        if live_docs.get(id).map_or(false, |doc| doc.version == 0) {
+            // nothing was written, so as far as readers are concerned there is no document yet
+            self.unload(&mut live_docs, id);
+        }
+        self.evict(&mut live_docs, id);
+        result
+    }
+
+    fn edit_loaded(&self,
+                   live_docs: &mut HashMap<String, Doc<File>>,
+                   id: &str,
+                   prefix: &[&str],
+                   edit: Edit,
+                   if_match: Option<&IfMatch>,
+                   dry_run: bool)
+                   -> Result<Edited, DbError> {
         if !live_docs.contains_key(id) {
-            live_docs.insert(id.to_string(),
-                             try!(self.load(id, prefix.len() > 0)));
+            let creates_parents = match edit {
+                Edit::Patch(_, options) => options.create_parents,
+                Edit::Put { mkdirs, .. } => mkdirs,
+                _ => false,
+            };
+            let must_exist = !prefix.is_empty() && !creates_parents;
+            live_docs.insert(id.to_string(), try!(self.load(id, must_exist)));
         }
 
+        let schema = try!(self.schema_for(live_docs, id));
+
+        let limits = self.quotas.for_doc(id);
+
         let doc = live_docs.get_mut(id).unwrap();
-        let scoped_patch = prefix_patch_paths(prefix, patch);
+        self.touch(doc);
+        if let Some(condition) = if_match {
+            if !doc.value.read(|value| condition.matches(find_path(value, prefix))) {
+                return Err(DbError::PreconditionFailed);
+            }
+        }
+        let is_new = doc.version == 0;
+        let (base, author) = match edit {
+            Edit::Concurrent { base, ref author, .. } => (Some(base), author.clone()),
+            _ => (None, None),
+        };
+        let mut planned = try!(doc.value.read(|value| plan(edit, value, is_new, prefix)));
+        if let Some(base) = base {
+            let missed = doc.version.checked_sub(base).unwrap_or(usize::MAX);
+            if missed > doc.history.len() {
+                return Err(DbError::UnknownVersion(base));
+            }
+            let since = doc.history.iter().skip(doc.history.len() - missed);
+            planned.patch = try!(rebase(planned.patch, since).map_err(DbError::Conflict));
+        }
+        planned.patch = try!(doc.value.read(|value| {
+            resolve_appends(&planned.patch, value)
+                .map_err(|e| patch_failure(&planned.patch, value, e))
+        }));
+        let record = format!("{}\n", planned.patch);
+        try!(limits.check_log(doc.log_bytes, record.len() as u64)
+                   .map_err(DbError::LimitExceeded));
+
+        let check = |next: &Value| validate(&limits, &schema, next);
+        if dry_run {
+            let next = try!(doc.value.read(|value| {
+                apply(&planned.patch, value).map_err(|e| patch_failure(&planned.patch, value, e))
+            }));
+            try!(check(&next));
+            let value = find_path(&next, &planned.path).cloned();
+            return Ok(Edited {
+                value: if planned.removed { None } else { value },
+                path: planned.path,
+                created: planned.created,
+                version: doc.version + 1,
+            });
+        }
+        match doc.value.patch_checked(&planned.patch, &check) {
+            Err(DbError::PatchError(e)) => {
+                return Err(doc.value.read(|value| patch_failure(&planned.patch, value, e)));
+            }
+            result => try!(result),
+        }
+        if doc.writer.is_none() {
+            doc.writer = Some(try!(self.open_writer(id)));
+        }
+        {
+            let writer = doc.writer.as_mut().unwrap();
+            try!(writer.write_all(record.as_bytes()));
+            if self.durability == Durability::Fsync {
+                try!(writer.sync_data());
+            }
+        }
+        doc.version += 1;
+        doc.log_bytes += record.len() as u64;
+        if self.cache.max_bytes.is_some() {
+            doc.bytes = doc.value.read(limits::serialized_len);
+        }
+        doc.history.push_back(planned.patch.clone());
+        if doc.history.len() > HISTORY_LEN {
+            doc.history.pop_front();
+        }
+        self.stats.patches_applied.fetch_add(1, Ordering::Relaxed);
+        self.stats.log_bytes_written.fetch_add(record.len(), Ordering::Relaxed);
+        self.notify(id,
+                    Change::Patched {
+                        version: doc.version,
+                        patch: planned.patch.clone(),
+                        author: author,
+                    });
 
-        try!(doc.value.patch(&scoped_patch));
-        try!(writeln!(doc.writer, "{}", scoped_patch));
+        let path: Vec<&str> = planned.path.iter().map(|s| &s[..]).collect();
+        Ok(Edited {
+            value: if planned.removed {
+                None
+            } else {
+                doc.value.clone_path(&path)
+            },
+            path: planned.path,
+            created: planned.created,
+            version: doc.version,
+        })
+    }
+
+    /// Remove a document's log and drop it from memory
+    fn delete_doc(&self, id: &str, if_match: Option<&IfMatch>) -> Result<Edited, DbError> {
+        let mut live_docs = try!(self.docs.write());
+        if !live_docs.contains_key(id) {
+            live_docs.insert(id.to_string(), try!(self.load(id, true)));
+        }
+        if let Some(condition) = if_match {
+            if !live_docs[id].value.read(|value| condition.matches(Some(value))) {
+                return Err(DbError::PreconditionFailed);
+            }
+        }
+        // drop the cached copy first, so a failed removal leaves the log to be reloaded
+        self.unload(&mut live_docs, id);
+        try!(remove_file(Path::new(&self.dir).join(id)));
+        self.notify(id, Change::Reset);
+        Ok(Edited {
+            value: None,
+            path: vec![],
+            created: false,
+            version: 0,
+        })
+    }
 
-        doc.value.clone_path(prefix).ok_or(DbError::PathDoesNotExist)
+    /// Write every document to `out` as newline-delimited JSON, the plain documents and then
+    /// the CRDT documents.
+    ///
+    /// Documents are written one at a time, so memory use is bounded by the largest document
+    /// rather than the whole database. Returns the number of documents written.
+    pub fn export<W: Write>(&self, out: &mut W, format: ExportFormat) -> Result<usize, DbError> {
+        let mut count = 0;
+        for id in try!(doc_ids(Path::new(&self.dir))) {
+            try!(self.export_doc(out, &id, format));
+            count += 1;
+        }
+        let crdt_dir = Path::new(&self.dir).join(CRDT_DIR);
+        if crdt_dir.is_dir() {
+            for id in try!(doc_ids(&crdt_dir)) {
+                try!(self.export_crdt(out, &id));
+                count += 1;
+            }
+        }
+        Ok(count)
     }
 
-    fn open_doc(&self, id: &str, must_exist: bool) -> Result<&Doc<File>, DbError> {
-        {
-            // scope our read lock so that self.load can succeed
+    fn export_crdt<W: Write>(&self, out: &mut W, id: &str) -> Result<(), DbError> {
+        let id_value = Value::String(id.to_string());
+        let file = try!(File::open(self.crdt_log(id)));
+        let mut write_error = None;
+        // a record still being appended by a concurrent edit is left out
+        try!(replay_crdt(io::BufReader::new(file), |op| {
+            if write_error.is_none() {
+                write_error = writeln!(out, r#"{{"crdt":{:?},"op":{}}}"#, id_value, op).err();
+            }
+        }));
+        match write_error {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn export_doc<W: Write>(&self,
+                            out: &mut W,
+                            id: &str,
+                            format: ExportFormat)
+                            -> Result<(), DbError> {
+        let id_value = Value::String(id.to_string());
+        if format == ExportFormat::Snapshot {
+            // clone rather than holding the lock while writing to a possibly slow `out`
+            let live = try!(self.docs.read())
+                           .get(id)
+                           .map(|doc| (doc.version, doc.value.clone_path(&[])));
+            if let Some((version, Some(value))) = live {
+                try!(writeln!(out,
+                              r#"{{"id":{:?},"version":{},"value":{:?}}}"#,
+                              id_value,
+                              version,
+                              value));
+                return Ok(());
+            }
+        }
+
+        let reader = io::BufReader::new(try!(File::open(Path::new(&self.dir).join(id))));
+        let mut write_error = None;
+        let replay = try!(log_file::replay_with(reader, |version, record, _| {
+            if format == ExportFormat::Log && write_error.is_none() {
+                write_error = writeln!(out,
+                                       r#"{{"id":{:?},"version":{},"patch":{}}}"#,
+                                       id_value,
+                                       version,
+                                       record)
+                                  .err();
+            }
+        }));
+        if let Some(e) = write_error {
+            return Err(e.into());
+        }
+        match replay.error {
+            // a record that is still being appended by a concurrent write
+            None | Some(RecordError::Truncated) => (),
+            Some(e) => return Err(DbError::CorruptLog(replay.version + 1, e)),
+        }
+        if format == ExportFormat::Snapshot {
+            try!(writeln!(out,
+                          r#"{{"id":{:?},"version":{},"value":{:?}}}"#,
+                          id_value,
+                          replay.version,
+                          replay.value));
+        }
+        Ok(())
+    }
+
+    /// Restore documents from the output of `export`, in either format.
+    ///
+    /// Each document named in `input` replaces any existing document of the same kind with the
+    /// same id, so the records for one document must be contiguous. A document may not take the
+    /// id of one of the other kind. When every document has a size quota, a
+    /// record may be at most `RECORD_ENVELOPE_BYTES` longer than the largest; longer records are
+    /// refused before they are read in full. Snapshot records restart the document at
+    /// version 1. A document's records are written to a temporary file, which only replaces its
+    /// log once every record has applied and the result is within the document's quotas and
+    /// satisfies its schema; if one fails, the documents before it stay imported. Returns the
+    /// number of documents imported.
+    pub fn import<R: BufRead>(&self, input: R) -> Result<usize, DbError> {
+        let mut staged = None;
+        let result = self.import_records(input, &mut staged);
+        if let Some(staged) = staged {
+            let _ = remove_file(&staged.temp);
+        }
+        result
+    }
+
+    fn import_records<R: BufRead>(&self,
+                                  input: R,
+                                  staged: &mut Option<Staged>)
+                                  -> Result<usize, DbError> {
+        let max_record = self.quotas
+                             .max_doc_bytes()
+                             .map_or(u64::MAX, |max| max.saturating_add(RECORD_ENVELOPE_BYTES));
+        let mut input = input;
+        let mut count = 0;
+        let mut line = Vec::new();
+        for i in 0.. {
+            line.clear();
+            // one byte over the limit, and a record has been found too long without reading it all
+            let read = try!((&mut input).take(max_record.saturating_add(1))
+                                        .read_until(b'\n', &mut line));
+            if read == 0 {
+                break;
+            }
+            let invalid = |message: String| DbError::InvalidImport(i + 1, message);
+            if !line.ends_with(b"\n") && read as u64 > max_record {
+                return Err(invalid(format!("record is longer than {} bytes", max_record)));
+            }
+            let line = try!(str::from_utf8(&line).map_err(|e| invalid(format!("{:?}", e))));
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record: Value = try!(serde_json::from_str(line)
+                                         .map_err(|e| invalid(format!("{:?}", e))));
+            let string = |key| record.find(key).and_then(|id| id.as_string());
+            let (id, is_crdt) = match (string("id"), string("crdt")) {
+                (Some(id), None) if is_doc_id(id) => (id, false),
+                (None, Some(id)) if is_doc_id(id) => (id, true),
+                _ => return Err(invalid("record must have a valid string id or crdt".into())),
+            };
+            let imported = if is_crdt {
+                match record.find("op").map(crdt::Op::from_value) {
+                    Some(Ok(op)) => Imported::Op(op),
+                    Some(Err(e)) => return Err(invalid(e)),
+                    None => return Err(invalid("crdt record must have an op".into())),
+                }
+            } else {
+                match (record.find("value"), record.find("patch")) {
+                    (Some(value), None) => {
+                        Imported::Patch(Patch { ops: vec![Op::Add(vec![], value.clone())] })
+                    }
+                    (None, Some(patch)) => {
+                        // exported from logs, which may hold extension and predicate ops
+                        let options = ParseOptions {
+                            extensions: true,
+                            predicates: true,
+                        };
+                        Imported::Patch(try!(Patch::from_value_with(patch.clone(), options)
+                                                 .map_err(|e| invalid(format!("{:?}", e)))))
+                    }
+                    _ => {
+                        return Err(invalid("record must have exactly one of value or patch"
+                                               .into()))
+                    }
+                }
+            };
+
+            if staged.as_ref().map(|s| (&s.id[..], s.crdt.is_some())) != Some((id, is_crdt)) {
+                if let Some(ref done) = *staged {
+                    try!(self.commit_import(done));
+                }
+                *staged = Some(try!(self.stage_import(id, is_crdt)));
+                count += 1;
+            }
+
+            let doc = staged.as_mut().unwrap();
+            let record = match imported {
+                Imported::Patch(ref patch) => format!("{}\n", patch),
+                Imported::Op(ref op) => format!("{}\n", op),
+            };
+            try!(doc.limits
+                    .check_log(doc.log_bytes, record.len() as u64)
+                    .map_err(DbError::LimitExceeded));
+            match imported {
+                Imported::Patch(patch) => {
+                    doc.value = try!(apply(&patch, &doc.value)
+                                         .map_err(|e| invalid(format!("{:?}", e))));
+                }
+                Imported::Op(op) => {
+                    // staged as a CRDT document, as the op's record named one
+                    if let Some(ref mut crdt) = doc.crdt {
+                        crdt.apply(op);
+                    }
+                }
+            }
+            try!(doc.file.write_all(record.as_bytes()));
+            doc.log_bytes += record.len() as u64;
+        }
+
+        if let Some(ref done) = *staged {
+            try!(self.commit_import(done));
+        }
+        *staged = None;
+        Ok(count)
+    }
+
+    /// Start writing an imported document to a temporary file
+    fn stage_import(&self, id: &str, is_crdt: bool) -> Result<Staged, DbError> {
+        let dir = if is_crdt {
+            let dir = Path::new(&self.dir).join(CRDT_DIR);
+            try!(create_dir_all(&dir));
+            dir
+        } else {
+            Path::new(&self.dir).to_path_buf()
+        };
+        // hidden, so that it is never taken for a log
+        let temp = dir.join(format!(".{}.import", id));
+        let file = try!(OpenOptions::new().write(true).create(true).truncate(true).open(&temp));
+        Ok(Staged {
+            id: id.to_string(),
+            temp: temp,
+            file: file,
+            value: Value::Null,
+            crdt: if is_crdt {
+                Some(crdt::Document::new(SERVER_REPLICA))
+            } else {
+                None
+            },
+            log_bytes: 0,
+            limits: self.quotas.for_doc(id),
+        })
+    }
+
+    /// Check a fully imported document as `edit_doc` would, then put it in place of the
+    /// existing document
+    fn commit_import(&self, staged: &Staged) -> Result<(), DbError> {
+        try!(self.sync(&staged.file));
+        let schema = if staged.id == SCHEMAS_DOC && staged.crdt.is_none() {
+            Some(schema::meta_schema())
+        } else {
+            try!(self.lookup_schema(&staged.id))
+        };
+        let crdt_value = staged.crdt.as_ref().map(|doc| doc.value());
+        try!(validate(&staged.limits, &schema, crdt_value.as_ref().unwrap_or(&staged.value)));
+
+        // the locks are taken in the same order as for edits, docs first
+        if staged.crdt.is_some() {
+            let _live_docs = try!(self.docs.read());
+            if Path::new(&self.dir).join(&staged.id).exists() {
+                return Err(DbError::IdInUse);
+            }
+            let mut crdts = try!(self.crdts.lock());
+            try!(rename(&staged.temp, self.crdt_log(&staged.id)));
+            crdts.remove(&staged.id);
+        } else {
+            let mut live_docs = try!(self.docs.write());
+            try!(self.check_not_crdt(&staged.id));
+            try!(rename(&staged.temp, Path::new(&self.dir).join(&staged.id)));
+            self.unload(&mut live_docs, &staged.id);
+            self.notify(&staged.id, Change::Reset);
+        }
+        Ok(())
+    }
+
+    /// The value at `path` in a CRDT document
+    pub fn find_in_crdt(&self, id: &str, path: &[&str]) -> Result<Value, DbError> {
+        let mut crdts = try!(self.crdts.lock());
+        let value = try!(self.load_crdt(&mut crdts, id, true)).doc.value();
+        self.evict_crdts(&mut crdts, id);
+        find_path(&value, path).cloned().ok_or(DbError::PathDoesNotExist)
+    }
+
+    /// Apply ops from another replica to a CRDT document, creating it if they are the first.
+    /// They are all refused if one is not fit to apply, as `crdt::Document::check` describes.
+    ///
+    /// Returns the ops that a replica which has applied up to `clock` is missing, the
+    /// document's clock once it has them, and its value.
+    pub fn sync_crdt(&self,
+                     id: &str,
+                     ops: Vec<crdt::Op>,
+                     clock: &Clock)
+                     -> Result<(Vec<crdt::Op>, Clock, Value), DbError> {
+        self.edit_crdt(id, ops.is_empty(), |doc| {
+                try!(doc.check(&ops).map_err(|(n, refused)| DbError::RefusedCrdtOp(n, refused)));
+                let applied = ops.into_iter().filter(|op| doc.apply(op.clone())).collect();
+                Ok((applied, (doc.ops_since(clock), doc.clock())))
+            })
+            .map(|((missing, clock), value)| (missing, clock, value))
+    }
+
+    /// Make the changes in `patch` to a CRDT document as edits by `SERVER_REPLICA`, creating it
+    /// if it does not exist, and return its value
+    pub fn patch_crdt(&self, id: &str, patch: &Patch) -> Result<Value, DbError> {
+        self.edit_crdt(id, false, |doc| {
+                let mut ops = vec![];
+                for op in &patch.ops {
+                    ops.extend(try!(doc.edit(op)));
+                }
+                Ok((ops, ()))
+            })
+            .map(|((), value)| value)
+    }
+
+    /// Let `f` change a CRDT document, then check its new value against the document's quotas
+    /// and schema and log the ops `f` returns. The document is restored if anything fails.
+    fn edit_crdt<F, T>(&self, id: &str, must_exist: bool, f: F) -> Result<(T, Value), DbError>
+        where F: FnOnce(&mut crdt::Document) -> Result<(Vec<crdt::Op>, T), DbError>
+    {
+        let schema = try!(self.lookup_schema(id));
+        let limits = self.quotas.for_doc(id);
+        // a new document may not take the id of a plain one, and holding the lock until its log
+        // is written keeps one from being created meanwhile
+        let _live_docs = if !must_exist && is_doc_id(id) && !self.crdt_log(id).exists() {
             let live_docs = try!(self.docs.read());
-            if let Some(ref doc) = live_docs.get(id) {
-                return Ok(doc)
+            if Path::new(&self.dir).join(id).exists() {
+                return Err(DbError::IdInUse);
+            }
+            Some(live_docs)
+        } else {
+            None
+        };
+        let mut crdts = try!(self.crdts.lock());
+        let result = {
+            let entry = try!(self.load_crdt(&mut crdts, id, must_exist));
+            let snapshot = entry.doc.snapshot();
+            let result = self.commit_crdt(entry, id, &limits, &schema, f);
+            if result.is_err() {
+                entry.doc.restore(snapshot);
             }
+            result
+        };
+        if crdts[id].doc.ops().is_empty() {
+            // nothing was written, so there is no document yet
+            crdts.remove(id);
         }
-        match self.load(id, must_exist) {
-            Ok(ref doc) => Ok(doc),
-            Err(err) => Err(err),
+        self.evict_crdts(&mut crdts, id);
+        result
+    }
+
+    fn commit_crdt<F, T>(&self,
+                         entry: &mut CrdtDoc,
+                         id: &str,
+                         limits: &DocLimits,
+                         schema: &Option<Value>,
+                         f: F)
+                         -> Result<(T, Value), DbError>
+        where F: FnOnce(&mut crdt::Document) -> Result<(Vec<crdt::Op>, T), DbError>
+    {
+        let (ops, result) = try!(f(&mut entry.doc));
+        let value = entry.doc.value();
+        if ops.is_empty() {
+            return Ok((result, value));
         }
+        try!(validate(limits, schema, &value));
+        let record: String = ops.iter().map(|op| format!("{}\n", op)).collect();
+        try!(limits.check_log(entry.log_bytes, record.len() as u64)
+                   .map_err(DbError::LimitExceeded));
+        if entry.writer.is_none() {
+            let filename = self.crdt_log(id);
+            entry.writer = Some(try!(OpenOptions::new()
+                                         .create(true)
+                                         .append(true)
+                                         .open(&filename)));
+        }
+        {
+            let writer = entry.writer.as_mut().unwrap();
+            try!(writer.write_all(record.as_bytes()));
+            try!(self.sync(writer));
+        }
+        entry.log_bytes += record.len() as u64;
+        if self.cache.max_bytes.is_some() {
+            entry.bytes = limits::serialized_len(&value);
+        }
+        self.stats.log_bytes_written.fetch_add(record.len(), Ordering::Relaxed);
+        Ok((result, value))
     }
 
-    fn load(&self, id: &str, must_exist: bool) -> Result<Doc<File>, DbError> {
-        let filename = Path::new(&self.dir).join(id);
-        let reader = io::BufReader::new(try!(OpenOptions::new()
-                                                 .read(true)
-                                                 .create(must_exist)
-                                                 .open(&filename)));
+    /// The CRDT document `id`, replaying its log if it is not in memory yet. A document with no
+    /// log is `DocumentDoesNotExist` if `must_exist`, otherwise a new empty document. Ids are
+    /// held to the same rules as in `load`, and may not name a file outside `CRDT_DIR`.
+    fn load_crdt<'a>(&self,
+                     crdts: &'a mut HashMap<String, CrdtDoc>,
+                     id: &str,
+                     must_exist: bool)
+                     -> Result<&'a mut CrdtDoc, DbError> {
+        if !is_doc_id(id) {
+            return Err(if must_exist {
+                DbError::DocumentDoesNotExist
+            } else {
+                DbError::ReservedId
+            });
+        }
+        if !crdts.contains_key(id) {
+            let dir = Path::new(&self.dir).join(CRDT_DIR);
+            try!(create_dir_all(&dir));
+            let mut doc = crdt::Document::new(SERVER_REPLICA);
+            let log_bytes = match File::open(dir.join(id)) {
+                Ok(file) => {
+                    try!(replay_crdt(io::BufReader::new(file), |op| {
+                        doc.apply(op);
+                    }))
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && !must_exist => 0,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(DbError::DocumentDoesNotExist)
+                }
+                Err(e) => return Err(DbError::IoError(e)),
+            };
+            self.stats.loads.fetch_add(1, Ordering::Relaxed);
+            let bytes = if self.cache.max_bytes.is_some() {
+                limits::serialized_len(&doc.value())
+            } else {
+                0
+            };
+            crdts.insert(id.to_string(),
+                         CrdtDoc {
+                             doc: doc,
+                             writer: None,
+                             log_bytes: log_bytes,
+                             bytes: bytes,
+                             last_used: 0,
+                         });
+        }
+        let entry = crdts.get_mut(id).unwrap();
+        entry.last_used = self.tick();
+        Ok(entry)
+    }
 
-        let mut version: usize = 0;
-        let mut value = Value::Null;
+    /// Refuse `id` for a new plain document if a CRDT document has it. Called with the `docs`
+    /// lock held, as `edit_crdt` holds it while creating a CRDT document.
+    fn check_not_crdt(&self, id: &str) -> Result<(), DbError> {
+        let _crdts = try!(self.crdts.lock());
+        if self.crdt_log(id).exists() {
+            return Err(DbError::IdInUse);
+        }
+        Ok(())
+    }
+
+    fn crdt_log(&self, id: &str) -> PathBuf {
+        Path::new(&self.dir).join(CRDT_DIR).join(id)
+    }
+
+    fn sync(&self, file: &File) -> Result<(), DbError> {
+        if self.durability == Durability::Fsync {
+            try!(file.sync_data());
+        }
+        Ok(())
+    }
+
+    /// Look up the schema for `id` in the (lazily loaded) schemas document.
+    ///
+    /// The schemas document is read on every write so edits to it take effect immediately.
+    fn schema_for(&self,
+                  live_docs: &mut HashMap<String, Doc<File>>,
+                  id: &str)
+                  -> Result<Option<Value>, DbError> {
+        if id == SCHEMAS_DOC {
+            return Ok(Some(schema::meta_schema()));
+        }
+        if !live_docs.contains_key(SCHEMAS_DOC) {
+            if !Path::new(&self.dir).join(SCHEMAS_DOC).exists() {
+                return Ok(None);
+            }
+            live_docs.insert(SCHEMAS_DOC.to_string(), try!(self.load(SCHEMAS_DOC, false)));
+        }
+        Ok(live_docs[SCHEMAS_DOC].value.read(|schemas| schema::schema_for(schemas, id).cloned()))
+    }
+
+    /// The schema for `id` in the schemas document, looked up without holding the cache lock
+    fn lookup_schema(&self, id: &str) -> Result<Option<Value>, DbError> {
+        let lookup = self.open_doc(SCHEMAS_DOC, |doc| {
+            Ok(doc.value.read(|schemas| schema::schema_for(schemas, id).cloned()))
+        });
+        match lookup {
+            Err(DbError::DocumentDoesNotExist) => Ok(None),
+            schema => schema,
+        }
+    }
 
-        for maybe_line in reader.lines() {
-            let line = try!(maybe_line);
-            let patch = try!(Patch::from_str(&line));
-            value = try!(apply(&patch, &value));
-            version += 1;
+    /// Call `f` with an existing document, replaying its log from disk if it is not in memory.
+    ///
+    /// Documents already in memory are read under the shared lock. A cold document is replayed
+    /// without holding any lock and then cached, unless another thread cached it first. If any
+    /// document was unloaded while the log was being replayed, a write to this one may have been
+    /// made and evicted in the meantime, so the replay is started over.
+    fn open_doc<F, T>(&self, id: &str, f: F) -> Result<T, DbError>
+        where F: FnOnce(&Doc<File>) -> Result<T, DbError>
+    {
+        loop {
+            {
+                // scope our read lock so that we can take the write lock to cache
+                let live_docs = try!(self.docs.read());
+                if let Some(doc) = live_docs.get(id) {
+                    self.touch(doc);
+                    return f(doc);
+                }
+            }
+            let unloads = self.unloads.load(Ordering::SeqCst);
+            let doc = try!(self.load(id, true));
+            let mut live_docs = try!(self.docs.write());
+            if !live_docs.contains_key(id) {
+                if self.unloads.load(Ordering::SeqCst) != unloads {
+                    continue;
+                }
+                live_docs.insert(id.to_string(), doc);
+                self.evict(&mut live_docs, id);
+            }
+            let doc = &live_docs[id];
+            self.touch(doc);
+            return f(doc);
         }
+    }
+
+    /// Drop a document from the cache, if it is there. Used for every removal, and whenever a
+    /// log is replaced behind the cache, as `open_doc` relies on `unloads` to notice them.
+    fn unload(&self, live_docs: &mut HashMap<String, Doc<File>>, id: &str) {
+        live_docs.remove(id);
+        self.unloads.fetch_add(1, Ordering::SeqCst);
+    }
 
-        let writer = try!(OpenOptions::new()
-                              .write(true)
-                              .create(false)
-                              .append(true)
-                              .truncate(false)
-                              .open(&filename));
+    /// Replay a document's log. A document with no log is `DocumentDoesNotExist` if
+    /// `must_exist`, otherwise a new empty document whose log is created on its first write,
+    /// unless a CRDT document has the id. Ids that `log_file::is_log_name` refuses never name a
+    /// document.
+    fn load(&self, id: &str, must_exist: bool) -> Result<Doc<File>, DbError> {
+        if !log_file::is_log_name(id) {
+            // the file, if there is one, is not a document
+            return Err(if must_exist {
+                DbError::DocumentDoesNotExist
+            } else {
+                DbError::ReservedId
+            });
+        }
+        let filename = Path::new(&self.dir).join(id);
+        let replay = match File::open(&filename) {
+            Ok(file) => try!(log_file::replay(io::BufReader::new(file))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && !must_exist => {
+                try!(self.check_not_crdt(id));
+                try!(log_file::replay(io::empty()))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(DbError::DocumentDoesNotExist)
+            }
+            Err(e) => return Err(DbError::IoError(e)),
+        };
+        if let Some(e) = replay.error {
+            return Err(DbError::CorruptLog(replay.version + 1, e));
+        }
 
+        self.stats.loads.fetch_add(1, Ordering::Relaxed);
+        let bytes = if self.cache.max_bytes.is_some() {
+            limits::serialized_len(&replay.value)
+        } else {
+            0
+        };
         Ok(Doc {
-            value: SharedValue::from_value(value),
-            version: version,
-            writer: writer,
+            value: SharedValue::from_value(replay.value),
+            version: replay.version,
+            log_bytes: replay.valid_bytes,
+            writer: None,
+            bytes: bytes,
+            last_used: AtomicUsize::new(self.tick()),
+            history: VecDeque::new(),
+        })
+    }
+
+    /// Send the changes committed to a document from now on to `changes`, until its receiver
+    /// is dropped. They apply on top of the returned version and value.
+    pub fn subscribe(&self, id: &str, changes: Sender<Change>) -> Result<(usize, Value), DbError> {
+        self.open_doc(id, |doc| {
+            // commits need the write lock, so none can slip in before the sender is registered
+            try!(self.subscribers.lock()).entry(id.to_string()).or_insert(vec![]).push(changes);
+            Ok((doc.version, doc.value.clone_path(&[]).unwrap()))
         })
     }
+
+    /// Send `change` to the subscribers of a document, forgetting those that have gone away
+    fn notify(&self, id: &str, change: Change) {
+        let mut subscribers = match self.subscribers.lock() {
+            Ok(subscribers) => subscribers,
+            Err(_) => return,
+        };
+        let gone = match subscribers.get_mut(id) {
+            Some(senders) => {
+                senders.retain(|sender| sender.send(change.clone()).is_ok());
+                senders.is_empty()
+            }
+            None => false,
+        };
+        if gone {
+            subscribers.remove(id);
+        }
+    }
+
+    fn open_writer(&self, id: &str) -> Result<File, DbError> {
+        let filename = Path::new(&self.dir).join(id);
+        Ok(try!(OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(true)
+                    .truncate(false)
+                    .open(&filename)))
+    }
+
+    fn tick(&self) -> usize {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn touch(&self, doc: &Doc<File>) {
+        doc.last_used.store(self.tick(), Ordering::Relaxed);
+    }
+
+    /// Evict the least recently used documents, then close the least recently used logs, until
+    /// the cache is within its limits. The document `keep` is never evicted.
+    fn evict(&self, live_docs: &mut HashMap<String, Doc<File>>, keep: &str) {
+        loop {
+            let over_docs = self.cache.max_docs.map(|max| live_docs.len() > max);
+            let over_bytes = self.cache.max_bytes.map(|max| resident_bytes(live_docs) > max);
+            if over_docs != Some(true) && over_bytes != Some(true) {
+                break;
+            }
+            let candidates = live_docs.iter()
+                                      .map(|(id, doc)| (id, doc.last_used.load(Ordering::Relaxed)));
+            match coldest(candidates, keep) {
+                Some(id) => self.unload(live_docs, &id),
+                None => break,
+            }
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if let Some(max) = self.cache.max_open_files {
+            while live_docs.values().filter(|doc| doc.writer.is_some()).count() > max {
+                let candidates = live_docs.iter()
+                                          .filter(|&(_, doc)| doc.writer.is_some())
+                                          .map(|(id, doc)| {
+                                              (id, doc.last_used.load(Ordering::Relaxed))
+                                          });
+                match coldest(candidates, keep) {
+                    Some(id) => live_docs.get_mut(&id).unwrap().writer = None,
+                    None => break,
+                }
+                self.stats.writers_closed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// `evict` for the CRDT documents
+    fn evict_crdts(&self, crdts: &mut HashMap<String, CrdtDoc>, keep: &str) {
+        loop {
+            let over_docs = self.cache.max_docs.map(|max| crdts.len() > max);
+            let over_bytes = self.cache.max_bytes.map(|max| {
+                crdts.values().map(|doc| doc.bytes).fold(0, |a, b| a + b) > max
+            });
+            if over_docs != Some(true) && over_bytes != Some(true) {
+                break;
+            }
+            match coldest(crdts.iter().map(|(id, doc)| (id, doc.last_used)), keep) {
+                Some(id) => {
+                    crdts.remove(&id);
+                }
+                None => break,
+            }
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if let Some(max) = self.cache.max_open_files {
+            while crdts.values().filter(|doc| doc.writer.is_some()).count() > max {
+                let candidates = crdts.iter()
+                                      .filter(|&(_, doc)| doc.writer.is_some())
+                                      .map(|(id, doc)| (id, doc.last_used));
+                match coldest(candidates, keep) {
+                    Some(id) => crdts.get_mut(&id).unwrap().writer = None,
+                    None => break,
+                }
+                self.stats.writers_closed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn resident_bytes<W: Write>(live_docs: &HashMap<String, Doc<W>>) -> u64 {
+    live_docs.values().map(|doc| doc.bytes).fold(0, |a, b| a + b)
+}
+
+/// The id of the least recently used of `candidates`, each given with the tick it was last used
+/// at, other than `keep`
+fn coldest<'a, I>(candidates: I, keep: &str) -> Option<String>
+    where I: Iterator<Item = (&'a String, usize)>
+{
+    candidates.filter(|&(id, _)| id != keep)
+              .min_by_key(|&(_, last_used)| last_used)
+              .map(|(id, _)| id.clone())
+}
+
+/// Apply the ops in a CRDT document's log to `doc`, returning the size of the records replayed.
+/// A last record with no trailing newline is still being appended, or was cut short by a crash,
+/// and is ignored.
+/// Whether `id` can name a document, plain or CRDT, rather than another file or a path outside
+/// the directory it is looked up in
+fn is_doc_id(id: &str) -> bool {
+    log_file::is_log_name(id) && !id.contains("/")
+}
+
+/// The ids of the documents whose logs are in `dir`
+fn doc_ids(dir: &Path) -> io::Result<Vec<String>> {
+    let mut ids = vec![];
+    for entry in try!(read_dir(dir)) {
+        let entry = try!(entry);
+        let id = entry.file_name().to_string_lossy().into_owned();
+        if try!(entry.file_type()).is_file() && is_doc_id(&id) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// Pass each op in a CRDT document's log to `f`, returning the length of the records read.
+/// A final record without its newline is still being written, and is left out.
+fn replay_crdt<R, F>(mut reader: R, mut f: F) -> Result<u64, DbError>
+    where R: BufRead,
+          F: FnMut(crdt::Op)
+{
+    let mut line = String::new();
+    let mut number = 0;
+    let mut valid_bytes = 0;
+    loop {
+        line.clear();
+        if try!(reader.read_line(&mut line)) == 0 || !line.ends_with("\n") {
+            return Ok(valid_bytes);
+        }
+        number += 1;
+        let op = serde_json::from_str(&line)
+                     .map_err(|e| format!("{:?}", e))
+                     .and_then(|op| crdt::Op::from_value(&op));
+        match op {
+            Ok(op) => {
+                f(op);
+                valid_bytes += line.len() as u64;
+            }
+            Err(e) => return Err(DbError::CorruptLog(number, RecordError::InvalidCrdtOp(e))),
+        }
+    }
+}
+
+/// Work out the patch for `edit` at `prefix` in a document holding `current`
+fn plan(edit: Edit, current: &Value, is_new: bool, prefix: &[&str]) -> Result<Planned, DbError> {
+    let path: Vec<String> = prefix.iter().map(|s| s.to_string()).collect();
+    let target = if is_new {
+        None
+    } else {
+        find_path(current, prefix)
+    };
+    let removed = match edit {
+        Edit::Delete => true,
+        _ => false,
+    };
+    let (ops, path, created) = match edit {
+        Edit::Concurrent { patch, .. } => (patch.prefixed(prefix).ops, path, is_new),
+        Edit::Patch(patch, options) => {
+            // the parents created are logged as explicit adds so that replay needs no options
+            let mut patch = patch.prefixed(prefix);
+            if options.create_parents {
+                patch = try!(expand_parents(&patch, current));
+            }
+            (patch.ops, path, is_new)
+        }
+        Edit::Put { value, .. } if target.is_some() => {
+            (vec![Op::Replace(path.clone(), value)], path, false)
+        }
+        Edit::Put { value, mkdirs } => {
+            let add = Patch { ops: vec![Op::Add(path.clone(), value)] };
+            if mkdirs {
+                (try!(expand_parents(&add, current)).ops, path, true)
+            } else {
+                let parent_exists = path.is_empty() ||
+                                    (!is_new &&
+                                     find_path(current, &prefix[..prefix.len() - 1]).is_some());
+                if !parent_exists {
+                    return Err(DbError::PathDoesNotExist);
+                }
+                (add.ops, path, true)
+            }
+        }
+        Edit::Delete => {
+            if target.is_none() {
+                return Err(DbError::PathDoesNotExist);
+            }
+            (vec![Op::Remove(path.clone())], path, false)
+        }
+        Edit::Merge { base, value } => {
+            let current = try!(target.ok_or(DbError::PathDoesNotExist));
+            let merged = merge3(&base, &value, current);
+            if !merged.conflicts.is_empty() {
+                return Err(DbError::MergeConflict(merged));
+            }
+            (diff(current, &merged.value).prefixed(prefix).ops, path, false)
+        }
+        Edit::Append(value) => {
+            let len = match target {
+                Some(&Value::Array(ref items)) => items.len(),
+                Some(_) => return Err(DbError::NotAnArray),
+                None => return Err(DbError::PathDoesNotExist),
+            };
+            let mut element = path;
+            element.push(len.to_string());
+            (vec![Op::Add(element.clone(), value)], element, true)
+        }
+    };
+    Ok(Planned {
+        patch: Patch { ops: ops },
+        path: path,
+        created: created,
+        removed: removed,
+    })
+}
+
+/// Check the value a document would have next against its quotas and schema
+fn validate(limits: &DocLimits, schema: &Option<Value>, next: &Value) -> Result<(), DbError> {
+    try!(limits.check_value(next).map_err(DbError::LimitExceeded));
+    match *schema {
+        Some(ref schema) => schema::validate(schema, next).map_err(DbError::ValidationError),
+        None => Ok(()),
+    }
+}
+
+/// The error for `patch` failing on `value`, naming the test that failed if one did
+fn patch_failure(patch: &Patch, value: &Value, e: PatchError) -> DbError {
+    patch.failed_test(value).map_or(DbError::PatchError(e), DbError::TestFailed)
+}
+
+/// `patch` applied after the patches committed since it was made, or the conflicts with the
+/// first of them that changed a value a `test` in it checked or that `transform` refuses.
+fn rebase<'a, I>(patch: Patch, since: I) -> Result<Patch, Vec<Conflict>>
+    where I: Iterator<Item = &'a Patch>
+{
+    let tests = |patch: &Patch| {
+        patch.ops
+             .iter()
+             .filter(|op| match **op {
+                 Op::Test(..) | Op::Assert(..) => true,
+                 _ => false,
+             })
+             .count()
+    };
+    let mut patch = patch;
+    for theirs in since {
+        if conflicts(&patch, theirs).is_empty() {
+            continue;
+        }
+        let rebased = match transform(theirs, &patch) {
+            Ok((_, rebased)) => rebased,
+            Err(_) => return Err(conflicts(&patch, theirs)),
+        };
+        if tests(&rebased) < tests(&patch) {
+            return Err(conflicts(&patch, theirs)
+                           .into_iter()
+                           .filter(|c| c.kind == ConflictKind::ReadWrite)
+                           .collect());
+        }
+        patch = rebased;
+    }
+    Ok(patch)
+}
+
+/// A database in an empty directory
+#[cfg(test)]
+fn test_db(name: &str, options: DbOptions) -> Database<File> {
+    let dir = ::std::env::temp_dir().join(format!("json-api-database-test-{}", name));
+    let _ = ::std::fs::remove_dir_all(&dir);
+    Database::open(dir.to_str().unwrap(), options).unwrap()
+}
+
+#[cfg(test)]
+fn json(s: &str) -> Value {
+    serde_json::from_str(s).unwrap()
+}
+
+#[cfg(test)]
+fn put(db: &Database<File>, id: &str, value: &str) -> Result<Edited, DbError> {
+    db.edit_doc(id,
+                &[],
+                Edit::Put {
+                    value: json(value),
+                    mkdirs: false,
+                },
+                None)
+}
+
+#[test]
+fn imports_refuse_records_longer_than_any_document() {
+    let mut options = DbOptions::default();
+    options.quotas.default.max_bytes = Some(10);
+    let db = test_db("import-bound", options);
+    let long: String = ::std::iter::repeat("x").take(2 * RECORD_ENVELOPE_BYTES as usize).collect();
+    let input = format!("{{\"id\":\"a\",\"value\":\"{}\"}}\n", long);
+    match db.import(input.as_bytes()) {
+        Err(DbError::InvalidImport(1, _)) => (),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(db.import(&b"\n{\"id\":\"a\",\"value\":\"short\"}"[..]).unwrap(), 1);
+    assert_eq!(db.find_in_doc("a", &[]).unwrap(), json(r#""short""#));
+}
+
+#[test]
+fn ids_kept_for_other_files_are_refused() {
+    let db = test_db("reserved", DbOptions::default());
+    for id in &["a.bak", "a.compact", ".a"] {
+        match put(&db, id, "1") {
+            Err(DbError::ReservedId) => (),
+            other => panic!("{}: {:?}", id, other),
+        }
+        match db.find_in_doc(id, &[]) {
+            Err(DbError::DocumentDoesNotExist) => (),
+            other => panic!("{}: {:?}", id, other),
+        }
+        let record = format!("{{\"id\":{:?},\"value\":1}}\n", id);
+        match db.import(record.as_bytes()) {
+            Err(DbError::InvalidImport(1, _)) => (),
+            other => panic!("{}: {:?}", id, other),
+        }
+    }
+}
+
+#[test]
+fn crdt_ids_may_not_name_files_outside_their_directory() {
+    let db = test_db("crdt-ids", DbOptions::default());
+    let patch = Patch::from_str(r#"[{"op":"add","path":"","value":{}}]"#).unwrap();
+    for id in &["../a", "b/c", ".d"] {
+        match db.patch_crdt(id, &patch) {
+            Err(DbError::ReservedId) => (),
+            other => panic!("{}: {:?}", id, other),
+        }
+        match db.find_in_crdt(id, &[]) {
+            Err(DbError::DocumentDoesNotExist) => (),
+            other => panic!("{}: {:?}", id, other),
+        }
+    }
+    assert!(!Path::new(&db.dir).join("a").exists());
+}
+
+#[test]
+fn patches_over_a_quota_are_refused_and_leave_the_document_alone() {
+    let mut options = DbOptions::default();
+    options.quotas.overrides.push(("small*".into(),
+                                   DocLimits { max_bytes: Some(10), ..DocLimits::default() }));
+    let db = test_db("quota", options);
+    put(&db, "small", "[1,2]").unwrap();
+    put(&db, "large", "[1,2]").unwrap();
+    let append = Patch::from_str(r#"[{"op":"add","path":"/-","value":"a long string"}]"#).unwrap();
+    match db.patch_doc("small", append.clone(), &[], None) {
+        Err(DbError::LimitExceeded(Limit::DocBytes(10))) => (),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(db.find_in_doc("small", &[]).unwrap(), json("[1,2]"));
+    assert_eq!(db.stats().patches_rejected.load(Ordering::Relaxed), 1);
+    db.patch_doc("large", append, &[], None).unwrap();
+}
+
+#[test]
+fn evicted_documents_reload_with_the_same_content() {
+    let mut options = DbOptions::default();
+    options.cache.max_docs = Some(1);
+    let db = test_db("evict", options);
+    put(&db, "a", r#"{"list":[]}"#).unwrap();
+    for i in 0..3 {
+        let patch = format!(r#"[{{"op":"add","path":"/list/-","value":{}}}]"#, i);
+        db.patch_doc("a", Patch::from_str(&patch).unwrap(), &[], None).unwrap();
+    }
+    put(&db, "b", "true").unwrap();
+    assert_eq!(db.cache_stats().unwrap().docs, 1);
+    assert_eq!(db.stats().evictions.load(Ordering::Relaxed), 1);
+
+    let loads = db.stats().loads.load(Ordering::Relaxed);
+    assert_eq!(db.find_in_doc("a", &[]).unwrap(), json(r#"{"list":[0,1,2]}"#));
+    assert_eq!(db.stats().loads.load(Ordering::Relaxed), loads + 1);
+    assert_eq!(db.find_in_doc("b", &[]).unwrap(), json("true"));
+    assert_eq!(db.stats().evictions.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn reads_racing_evictions_never_cache_a_stale_document() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let mut options = DbOptions::default();
+    options.cache.max_docs = Some(1);
+    let db = Arc::new(test_db("evict-race", options));
+    put(&db, "a", "[]").unwrap();
+    put(&db, "b", "[]").unwrap();
+    let append = Patch::from_str(r#"[{"op":"add","path":"/-","value":0}]"#).unwrap();
+    let length = |db: &Database<File>, id| {
+        match db.find_in_doc(id, &[]).unwrap() {
+            Value::Array(items) => items.len(),
+            other => panic!("{:?}", other),
+        }
+    };
+    // four threads append to a or b, and all eight read both, so each is evicted and reloaded
+    // over and over
+    let threads: Vec<_> = (0..8)
+                              .map(|i| {
+                                  let db = db.clone();
+                                  let append = append.clone();
+                                  thread::spawn(move || {
+                                      let mut seen = [0, 0];
+                                      for _ in 0..100 {
+                                          if i < 4 {
+                                              let id = if i % 2 == 0 { "a" } else { "b" };
+                                              db.patch_doc(id, append.clone(), &[], None)
+                                                .unwrap();
+                                          }
+                                          // a stale copy cached by a read would go back in time
+                                          for (j, id) in ["a", "b"].iter().enumerate() {
+                                              let n = length(&db, id);
+                                              assert!(n >= seen[j], "{} shrank to {}", id, n);
+                                              seen[j] = n;
+                                          }
+                                      }
+                                  })
+                              })
+                              .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!((length(&db, "a"), length(&db, "b")), (200, 200));
+}
+
+#[test]
+fn documents_are_loaded_on_their_first_read() {
+    let dir = {
+        let db = test_db("lazy", DbOptions::default());
+        put(&db, "a", r#"{"b":[1]}"#).unwrap();
+        db.dir.clone()
+    };
+    let db = Database::open(&dir, DbOptions::default()).unwrap();
+    assert_eq!(db.cache_stats().unwrap().docs, 0);
+    assert_eq!(db.find_in_doc("a", &["b", "0"]).unwrap(), json("1"));
+    assert_eq!(db.find_in_doc("a", &[]).unwrap(), json(r#"{"b":[1]}"#));
+    assert_eq!(db.stats().loads.load(Ordering::Relaxed), 1);
+    assert_eq!(db.cache_stats().unwrap().docs, 1);
+
+    match db.find_in_doc("missing", &[]) {
+        Err(DbError::DocumentDoesNotExist) => (),
+        other => panic!("{:?}", other),
+    }
+    match db.find_in_doc("a", &["c"]) {
+        Err(DbError::PathDoesNotExist) => (),
+        other => panic!("{:?}", other),
+    }
+    // a failed read caches nothing
+    assert_eq!(db.cache_stats().unwrap().docs, 1);
+}
+
+#[test]
+fn cold_documents_keep_their_version_and_log() {
+    let dir = {
+        let db = test_db("lazy-write", DbOptions::default());
+        put(&db, "a", "[]").unwrap();
+        db.dir.clone()
+    };
+    let db = Database::open(&dir, DbOptions::default()).unwrap();
+    let append = Patch::from_str(r#"[{"op":"add","path":"/-","value":1}]"#).unwrap();
+    db.patch_doc("a", append, &[], None).unwrap();
+    assert_eq!(db.subscribe("a", ::std::sync::mpsc::channel().0).unwrap(), (2, json("[1]")));
+
+    let db = Database::open(&dir, DbOptions::default()).unwrap();
+    assert_eq!(db.find_in_doc("a", &[]).unwrap(), json("[1]"));
+}
+
+#[test]
+fn puts_create_or_replace_and_make_parents_only_when_asked() {
+    let db = test_db("put", DbOptions::default());
+    let edited = put(&db, "a", r#"{"b":{}}"#).unwrap();
+    assert_eq!((edited.created, edited.version), (true, 1));
+    let edited = put(&db, "a", r#"{"b":{}}"#).unwrap();
+    assert_eq!((edited.created, edited.version), (false, 2));
+
+    let put_at = |prefix: &[&str], mkdirs| {
+        db.edit_doc("a",
+                    prefix,
+                    Edit::Put {
+                        value: json("1"),
+                        mkdirs: mkdirs,
+                    },
+                    None)
+    };
+    let edited = put_at(&["b", "c"], false).unwrap();
+    assert_eq!((edited.created, edited.path), (true, vec!["b".into(), "c".into()]));
+    assert_eq!(edited.value, Some(json("1")));
+    assert!(!put_at(&["b", "c"], false).unwrap().created);
+    match put_at(&["d", "e"], false) {
+        Err(DbError::PathDoesNotExist) => (),
+        other => panic!("{:?}", other),
+    }
+    assert!(put_at(&["d", "e"], true).unwrap().created);
+    assert_eq!(db.find_in_doc("a", &[]).unwrap(), json(r#"{"b":{"c":1},"d":{"e":1}}"#));
+}
+
+#[test]
+fn posts_append_and_deletes_remove_values_or_whole_documents() {
+    let db = test_db("post-delete", DbOptions::default());
+    put(&db, "a", r#"{"list":["x"],"n":1}"#).unwrap();
+    let edited = db.edit_doc("a", &["list"], Edit::Append(json(r#""y""#)), None).unwrap();
+    assert_eq!((edited.created, edited.path), (true, vec!["list".into(), "1".into()]));
+    match db.edit_doc("a", &["n"], Edit::Append(json("2")), None) {
+        Err(DbError::NotAnArray) => (),
+        other => panic!("{:?}", other),
+    }
+    match db.edit_doc("a", &["missing"], Edit::Append(json("2")), None) {
+        Err(DbError::PathDoesNotExist) => (),
+        other => panic!("{:?}", other),
+    }
+
+    let edited = db.edit_doc("a", &["list", "0"], Edit::Delete, None).unwrap();
+    assert_eq!((edited.value, edited.created), (None, false));
+    assert_eq!(db.find_in_doc("a", &[]).unwrap(), json(r#"{"list":["y"],"n":1}"#));
+    match db.edit_doc("a", &["list", "1"], Edit::Delete, None) {
+        Err(DbError::PathDoesNotExist) => (),
+        other => panic!("{:?}", other),
+    }
+
+    assert_eq!(db.edit_doc("a", &[], Edit::Delete, None).unwrap().version, 0);
+    match db.find_in_doc("a", &[]) {
+        Err(DbError::DocumentDoesNotExist) => (),
+        other => panic!("{:?}", other),
+    }
+    match db.edit_doc("a", &[], Edit::Delete, None) {
+        Err(DbError::DocumentDoesNotExist) => (),
+        other => panic!("{:?}", other),
+    }
+    // the id can be used again, starting from scratch
+    assert_eq!(put(&db, "a", "[]").unwrap().version, 1);
+}
+
+#[test]
+fn crdt_documents_are_exported_and_imported_as_ops() {
+    let db = test_db("crdt-export", DbOptions::default());
+    put(&db, "plain", "[1]").unwrap();
+    db.patch_crdt("shared", &Patch::from_str(r#"[{"op":"add","path":"","value":{}}]"#).unwrap())
+      .unwrap();
+    db.patch_crdt("shared", &Patch::from_str(r#"[{"op":"add","path":"/a","value":1}]"#).unwrap())
+      .unwrap();
+    let mut export = vec![];
+    assert_eq!(db.export(&mut export, ExportFormat::Snapshot).unwrap(), 2);
+    let export = String::from_utf8(export).unwrap();
+    let lines: Vec<&str> = export.lines().collect();
+    assert_eq!(lines[0], r#"{"id":"plain","version":1,"value":[1]}"#);
+    assert!(lines[1..].iter().all(|line| line.starts_with(r#"{"crdt":"shared","op":"#)));
+
+    let restored = test_db("crdt-import", DbOptions::default());
+    assert_eq!(restored.import(export.as_bytes()).unwrap(), 2);
+    assert_eq!(restored.find_in_doc("plain", &[]).unwrap(), json("[1]"));
+    assert_eq!(restored.find_in_crdt("shared", &[]).unwrap(), json(r#"{"a":1}"#));
+    // with its history, so that it goes on merging edits
+    let patch = Patch::from_str(r#"[{"op":"replace","path":"/a","value":2}]"#).unwrap();
+    assert_eq!(restored.patch_crdt("shared", &patch).unwrap(), json(r#"{"a":2}"#));
+    let mut again = vec![];
+    restored.export(&mut again, ExportFormat::Log).unwrap();
+    assert_eq!(String::from_utf8(again).unwrap().lines().count(), lines.len() + 1);
+}
+
+#[test]
+fn plain_and_crdt_documents_may_not_share_an_id() {
+    let db = test_db("crdt-ids-shared", DbOptions::default());
+    let root = Patch::from_str(r#"[{"op":"add","path":"","value":{}}]"#).unwrap();
+    put(&db, "plain", "1").unwrap();
+    db.patch_crdt("shared", &root).unwrap();
+    match db.patch_crdt("plain", &root) {
+        Err(DbError::IdInUse) => (),
+        other => panic!("{:?}", other),
+    }
+    match put(&db, "shared", "1") {
+        Err(DbError::IdInUse) => (),
+        other => panic!("{:?}", other),
+    }
+
+    let mut export = vec![];
+    db.export(&mut export, ExportFormat::Log).unwrap();
+    let export = String::from_utf8(export).unwrap();
+    let swapped = export.replace(r#"{"id":"plain""#, r#"{"id":"shared""#)
+                        .replace(r#"{"crdt":"shared""#, r#"{"crdt":"plain""#);
+    for record in swapped.lines() {
+        match db.import(record.as_bytes()) {
+            Err(DbError::IdInUse) => (),
+            other => panic!("{}: {:?}", record, other),
+        }
+    }
+    assert_eq!(db.find_in_doc("plain", &[]).unwrap(), json("1"));
+    assert_eq!(db.find_in_crdt("shared", &[]).unwrap(), json("{}"));
 }
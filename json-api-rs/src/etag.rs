@@ -0,0 +1,233 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde_json::Value;
+use std::{i64, u64};
+
+/// The hex SHA-256 of the canonical JSON serialization of `value`.
+///
+/// The canonical form is that of the JSON Canonicalization Scheme (RFC 8785), which is what
+/// `JSON.stringify` gives with object keys sorted by their UTF-16 code units: no whitespace,
+/// strings escaping only `"`, `\`, and control characters (as `\b`, `\t`, `\n`, `\f`, `\r`, or
+/// else `\u00xx`), and numbers written as by ECMAScript's `Number.prototype.toString`, the
+/// shortest form that reads back as the same double (so `1.0` and `1` hash the same, and `1e21`
+/// is written `1e+21`). Integers that a double cannot hold exactly, which `JSON.stringify` would
+/// round, are written in full.
+pub fn hash(value: &Value) -> String {
+    let mut digest = Sha256::new();
+    feed(value, &mut digest);
+    digest.result_str()
+}
+
+/// The condition from an `If-Match` header
+#[derive(Debug, Clone, PartialEq)]
+pub enum IfMatch {
+    /// `*`, matching any existing value
+    Any,
+    /// A list of hashes, one of which the current value must have
+    Hashes(Vec<String>),
+}
+
+impl IfMatch {
+    /// Parse a header value like `"abc", W/"def"` or `*`
+    pub fn parse(header: &str) -> IfMatch {
+        if header.trim() == "*" {
+            return IfMatch::Any;
+        }
+        IfMatch::Hashes(header.split(",")
+                              .map(|tag| {
+                                  tag.trim()
+                                     .trim_left_matches("W/")
+                                     .trim_matches('"')
+                                     .to_string()
+                              })
+                              .collect())
+    }
+
+    /// Whether the current value at the target (`None` if it does not exist) satisfies this
+    pub fn matches(&self, current: Option<&Value>) -> bool {
+        match (self, current) {
+            (_, None) => false,
+            (&IfMatch::Any, Some(_)) => true,
+            (&IfMatch::Hashes(ref hashes), Some(value)) => {
+                let current = hash(value);
+                hashes.iter().any(|h| *h == current)
+            }
+        }
+    }
+}
+
+fn feed(value: &Value, digest: &mut Sha256) {
+    match value {
+        &Value::Null => digest.input_str("null"),
+        &Value::Bool(b) => digest.input_str(if b { "true" } else { "false" }),
+        // the MAXes round up to a double out of range, which casts back to MAX
+        &Value::I64(i) if i != i64::MAX && i as f64 as i64 == i => {
+            digest.input_str(&format_number(i as f64))
+        }
+        &Value::I64(i) => digest.input_str(&i.to_string()),
+        &Value::U64(u) if u != u64::MAX && u as f64 as u64 == u => {
+            digest.input_str(&format_number(u as f64))
+        }
+        &Value::U64(u) => digest.input_str(&u.to_string()),
+        &Value::F64(f) => digest.input_str(&format_number(f)),
+        &Value::String(ref s) => feed_string(s, digest),
+        &Value::Array(ref a) => {
+            digest.input_str("[");
+            for (i, item) in a.iter().enumerate() {
+                if i > 0 {
+                    digest.input_str(",");
+                }
+                feed(item, digest);
+            }
+            digest.input_str("]");
+        }
+        &Value::Object(ref o) => {
+            // BTreeMap orders keys by code point, which differs from UTF-16 order only for keys
+            // with characters from U+E000 up
+            let mut members: Vec<_> = o.iter().collect();
+            members.sort_by(|&(a, _), &(b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            digest.input_str("{");
+            for (i, (key, item)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    digest.input_str(",");
+                }
+                feed_string(key, digest);
+                digest.input_str(":");
+                feed(item, digest);
+            }
+            digest.input_str("}");
+        }
+    }
+}
+
+fn feed_string(s: &str, digest: &mut Sha256) {
+    digest.input_str(&escape(s));
+}
+
+/// `s` as a JSON string in canonical form
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\u{c}' => escaped.push_str("\\f"),
+            '\r' => escaped.push_str("\\r"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// `f` as ECMAScript's `Number.prototype.toString` writes it
+fn format_number(f: f64) -> String {
+    if !f.is_finite() {
+        // not JSON, and serialized as null
+        return "null".into();
+    }
+    if f == 0.0 {
+        return "0".into();
+    }
+    // Rust's `{:e}` gives the shortest digits that read back as `f`, as ECMAScript requires
+    let scientific = format!("{:e}", f.abs());
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let digits: String = mantissa.chars().filter(|&c| c != '.').collect();
+    let k = digits.len() as i32;
+    // the value is 0.<digits> times ten to the n
+    let n = exponent[1..].parse::<i32>().unwrap() + 1;
+
+    let mut s = if f < 0.0 { "-".to_string() } else { String::new() };
+    if k <= n && n <= 21 {
+        s.push_str(&digits);
+        s.extend((0..n - k).map(|_| '0'));
+    } else if 0 < n && n <= 21 {
+        s.push_str(&digits[..n as usize]);
+        s.push('.');
+        s.push_str(&digits[n as usize..]);
+    } else if -6 < n && n <= 0 {
+        s.push_str("0.");
+        s.extend((0..-n).map(|_| '0'));
+        s.push_str(&digits);
+    } else {
+        s.push_str(&digits[..1]);
+        if k > 1 {
+            s.push('.');
+            s.push_str(&digits[1..]);
+        }
+        s.push_str(&format!("e{}{}", if n > 0 { "+" } else { "-" }, (n - 1).abs()));
+    }
+    s
+}
+
+#[cfg(test)]
+fn sha(canonical: &str) -> String {
+    let mut digest = Sha256::new();
+    digest.input_str(canonical);
+    digest.result_str()
+}
+
+#[cfg(test)]
+fn json(s: &str) -> Value {
+    ::serde_json::from_str(s).unwrap()
+}
+
+#[test]
+fn key_order_does_not_matter() {
+    let canonical = r#"{"a":[1,{"b":null,"c":true}],"d":"e"}"#;
+    assert_eq!(hash(&json(r#"{"d": "e", "a": [1, {"c": true, "b": null}]}"#)), sha(canonical));
+    assert_eq!(hash(&json(canonical)), sha(canonical));
+    // U+FF61 sorts after U+1F600 by code point but before it by UTF-16 code unit
+    assert_eq!(hash(&json("{\"\u{ff61}\":1,\"\u{1f600}\":2}")),
+               sha("{\"\u{1f600}\":2,\"\u{ff61}\":1}"));
+}
+
+#[test]
+fn integers_and_integral_floats_hash_the_same() {
+    assert_eq!(hash(&Value::I64(1)), sha("1"));
+    assert_eq!(hash(&Value::U64(1)), sha("1"));
+    assert_eq!(hash(&Value::F64(1.0)), sha("1"));
+    assert_eq!(hash(&Value::I64(-3)), hash(&Value::F64(-3.0)));
+    assert_eq!(hash(&Value::F64(-0.0)), sha("0"));
+    assert_eq!(hash(&Value::U64(1 << 60)), sha("1152921504606847000"));
+    assert_eq!(hash(&Value::U64(1 << 60)), hash(&Value::F64((1u64 << 60) as f64)));
+    // not rounded to a double
+    assert_eq!(hash(&Value::U64((1 << 53) + 1)), sha("9007199254740993"));
+    assert_eq!(hash(&Value::I64(i64::MAX)), sha("9223372036854775807"));
+    assert_eq!(hash(&Value::U64(u64::MAX)), sha("18446744073709551615"));
+}
+
+#[test]
+fn numbers_are_written_as_ecmascript_writes_them() {
+    for &(f, text) in &[(0.1, "0.1"),
+                        (-1.5, "-1.5"),
+                        (123.456, "123.456"),
+                        (1e20, "100000000000000000000"),
+                        (1e21, "1e+21"),
+                        (1e300, "1e+300"),
+                        (1.5e300, "1.5e+300"),
+                        (0.000001, "0.000001"),
+                        (1e-7, "1e-7"),
+                        (-1.25e-10, "-1.25e-10"),
+                        (4.35, "4.35"),
+                        (0.1 + 0.2, "0.30000000000000004"),
+                        (9007199254740993.0, "9007199254740992"),
+                        (5e-324, "5e-324"),
+                        (1.7976931348623157e308, "1.7976931348623157e+308")] {
+        assert_eq!(format_number(f), text);
+    }
+}
+
+#[test]
+fn strings_escape_only_what_json_requires() {
+    assert_eq!(escape("plain é \u{1f600}"), "\"plain é \u{1f600}\"");
+    assert_eq!(escape("\"\\/"), r#""\"\\/""#);
+    assert_eq!(escape("\u{8}\t\n\u{c}\r"), r#""\b\t\n\f\r""#);
+    assert_eq!(escape("\u{0}\u{1f}\u{7f}"), "\"\\u0000\\u001f\u{7f}\"");
+    assert_eq!(hash(&json(r#"["a\u0001\nb"]"#)), sha(r#"["a\u0001\nb"]"#));
+}
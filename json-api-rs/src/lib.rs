@@ -0,0 +1,29 @@
+extern crate crypto;
+extern crate hyper;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate mime;
+extern crate rustc_serialize;
+extern crate toml;
+extern crate unicase;
+extern crate ws;
+extern crate serde_json;
+extern crate json_patch;
+
+
+#[macro_use]
+mod macros;
+pub mod server;
+pub mod auth;
+pub mod collab;
+pub mod config;
+pub mod cors;
+pub mod crdt;
+pub mod database;
+pub mod etag;
+pub mod limits;
+pub mod log_file;
+pub mod metrics;
+mod shared_value;
+mod schema;
@@ -0,0 +1,200 @@
+use std::cmp;
+use std::fmt;
+use std::io;
+
+use serde_json;
+use serde_json::Value;
+
+/// Bounds on a single document, checked before a patch to it is committed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DocLimits {
+    /// Largest serialized size of the document's value, in bytes
+    pub max_bytes: Option<u64>,
+    /// Deepest nesting of arrays and objects in the document's value
+    pub max_depth: Option<usize>,
+    /// Largest size of the document's log on disk, in bytes
+    pub max_log_bytes: Option<u64>,
+}
+
+/// Default document limits plus overrides for particular documents
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Quotas {
+    pub default: DocLimits,
+    /// Overrides keyed by document id (or an id prefix ending in `*`); the longest match wins
+    /// and any limit it leaves unset falls back to `default`
+    pub overrides: Vec<(String, DocLimits)>,
+}
+
+/// The limit a patch would have exceeded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    DocBytes(u64),
+    Depth(usize),
+    LogBytes(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Limit::DocBytes(max) => write!(f, "document would exceed {} bytes", max),
+            &Limit::Depth(max) => write!(f, "document would be nested deeper than {}", max),
+            &Limit::LogBytes(max) => write!(f, "document log would exceed {} bytes", max),
+        }
+    }
+}
+
+impl Quotas {
+    pub fn for_doc(&self, id: &str) -> DocLimits {
+        let matching = self.overrides
+                           .iter()
+                           .filter(|&&(ref pattern, _)| {
+                               if pattern.ends_with("*") {
+                                   id.starts_with(&pattern[..pattern.len() - 1])
+                               } else {
+                                   pattern == id
+                               }
+                           })
+                           .max_by_key(|&&(ref pattern, _)| {
+                               // an exact match beats a prefix of the same length
+                               (pattern.trim_right_matches('*').len(), !pattern.ends_with("*"))
+                           });
+        match matching {
+            Some(&(_, ref limits)) => {
+                DocLimits {
+                    max_bytes: limits.max_bytes.or(self.default.max_bytes),
+                    max_depth: limits.max_depth.or(self.default.max_depth),
+                    max_log_bytes: limits.max_log_bytes.or(self.default.max_log_bytes),
+                }
+            }
+            None => self.default.clone(),
+        }
+    }
+
+    /// The largest value any document may have, or `None` if some document is unbounded
+    pub fn max_doc_bytes(&self) -> Option<u64> {
+        // with a `*` override the default applies only to the limits overrides leave unset
+        let covers_all = self.overrides.iter().any(|&(ref pattern, _)| pattern == "*");
+        let mut max = if covers_all {
+            Some(0)
+        } else {
+            self.default.max_bytes
+        };
+        for &(_, ref limits) in &self.overrides {
+            max = match (max, limits.max_bytes.or(self.default.max_bytes)) {
+                (Some(a), Some(b)) => Some(cmp::max(a, b)),
+                _ => None,
+            };
+        }
+        max
+    }
+}
+
+impl DocLimits {
+    /// Check the value a patch would produce
+    pub fn check_value(&self, value: &Value) -> Result<(), Limit> {
+        if let Some(max) = self.max_depth {
+            if depth(value) > max {
+                return Err(Limit::Depth(max));
+            }
+        }
+        if let Some(max) = self.max_bytes {
+            if serialized_len(value) > max {
+                return Err(Limit::DocBytes(max));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that appending `record_len` bytes keeps a log of `log_bytes` within its quota
+    pub fn check_log(&self, log_bytes: u64, record_len: u64) -> Result<(), Limit> {
+        match self.max_log_bytes {
+            Some(max) if log_bytes + record_len > max => Err(Limit::LogBytes(max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn depth(value: &Value) -> usize {
+    match value {
+        &Value::Array(ref a) => 1 + a.iter().map(depth).max().unwrap_or(0),
+        &Value::Object(ref o) => 1 + o.values().map(depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Counts the bytes written to it, so a value can be measured without buffering it
+struct ByteCounter(u64);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The length of `value` serialized as JSON
+pub fn serialized_len(value: &Value) -> u64 {
+    let mut counter = ByteCounter(0);
+    // writing to a ByteCounter cannot fail
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
+
+#[cfg(test)]
+fn quotas(overrides: &[(&str, u64)]) -> Quotas {
+    Quotas {
+        default: DocLimits { max_bytes: Some(100), max_depth: Some(3), ..DocLimits::default() },
+        overrides: overrides.iter()
+                            .map(|&(pattern, max)| {
+                                (pattern.to_string(),
+                                 DocLimits { max_bytes: Some(max), ..DocLimits::default() })
+                            })
+                            .collect(),
+    }
+}
+
+#[test]
+fn the_longest_matching_override_wins() {
+    let quotas = quotas(&[("*", 1), ("users/*", 2), ("users/admin", 3), ("users/admin*", 4)]);
+    assert_eq!(quotas.for_doc("other").max_bytes, Some(1));
+    assert_eq!(quotas.for_doc("users/bob").max_bytes, Some(2));
+    // an exact match beats a prefix of the same length
+    assert_eq!(quotas.for_doc("users/admin").max_bytes, Some(3));
+    assert_eq!(quotas.for_doc("users/admins").max_bytes, Some(4));
+}
+
+#[test]
+fn unset_limits_fall_back_to_the_default() {
+    let quotas = quotas(&[("users/*", 2)]);
+    assert_eq!(quotas.for_doc("other"), quotas.default);
+    let limits = quotas.for_doc("users/bob");
+    assert_eq!((limits.max_bytes, limits.max_depth), (Some(2), Some(3)));
+}
+
+#[test]
+fn the_largest_document_is_bounded_only_if_every_document_is() {
+    assert_eq!(quotas(&[("users/*", 200)]).max_doc_bytes(), Some(200));
+    assert_eq!(quotas(&[("*", 10), ("users/*", 20)]).max_doc_bytes(), Some(20));
+    let mut unbounded = quotas(&[("users/*", 200)]);
+    unbounded.default.max_bytes = None;
+    assert_eq!(unbounded.max_doc_bytes(), None);
+}
+
+#[test]
+fn values_and_logs_are_checked_against_their_limits() {
+    let limits = DocLimits {
+        max_bytes: Some(8),
+        max_depth: Some(1),
+        max_log_bytes: Some(100),
+    };
+    let value = |s: &str| serde_json::from_str::<Value>(s).unwrap();
+    assert_eq!(limits.check_value(&value("[1,2,3]")), Ok(()));
+    assert_eq!(limits.check_value(&value("[1,2,3,4]")), Err(Limit::DocBytes(8)));
+    assert_eq!(limits.check_value(&value("[[1]]")), Err(Limit::Depth(1)));
+    assert_eq!(limits.check_log(90, 10), Ok(()));
+    assert_eq!(limits.check_log(90, 11), Err(Limit::LogBytes(100)));
+}
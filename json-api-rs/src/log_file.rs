@@ -0,0 +1,106 @@
+use std::io;
+use std::io::prelude::*;
+
+use json_patch::{apply, Patch, InvalidPatchError, ParseOptions, PatchError};
+use serde_json::Value;
+
+/// Why a record in a document log could not be replayed
+#[derive(Debug)]
+pub enum RecordError {
+    InvalidPatchError(InvalidPatchError),
+    PatchError(PatchError),
+    /// A record in the log of a CRDT document is not a valid op
+    InvalidCrdtOp(String),
+    /// The last record has no trailing newline, usually because a write was interrupted
+    Truncated,
+}
+
+/// The state of a document after replaying its log up to the end or the first bad record
+pub struct Replay {
+    pub value: Value,
+    /// Number of records applied, which is also the document version
+    pub version: usize,
+    /// Length in bytes of the prefix of the log that holds only good records
+    pub valid_bytes: u64,
+    /// Why replay stopped at record `version + 1`, if it did not reach the end
+    pub error: Option<RecordError>,
+}
+
+/// Whether a file in the data directory holds a document log, rather than being hidden or a
+/// backup left behind by `json-api-admin`
+pub fn is_log_name(name: &str) -> bool {
+    !name.starts_with(".") && !name.ends_with(".bak") && !name.ends_with(".compact")
+}
+
+pub fn replay<R: BufRead>(reader: R) -> io::Result<Replay> {
+    replay_with(reader, |_, _, _| ())
+}
+
+/// Replay a log, calling `f(version, record, value)` after each record is applied
+pub fn replay_with<R, F>(mut reader: R, mut f: F) -> io::Result<Replay>
+    where R: BufRead,
+          F: FnMut(usize, &str, &Value)
+{
+    let mut replay = Replay {
+        value: Value::Null,
+        version: 0,
+        valid_bytes: 0,
+        error: None,
+    };
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let len = try!(reader.read_line(&mut line));
+        if len == 0 {
+            break;
+        }
+        if !line.ends_with("\n") {
+            replay.error = Some(RecordError::Truncated);
+            break;
+        }
+
+        let record = line.trim_right_matches('\n');
+        // the log holds whatever patches were accepted, extension and predicate ops included
+        let options = ParseOptions {
+            extensions: true,
+            predicates: true,
+        };
+        let result = Patch::from_str_with(record, options)
+                         .map_err(RecordError::InvalidPatchError)
+                         .and_then(|patch| {
+                             apply(&patch, &replay.value).map_err(RecordError::PatchError)
+                         });
+        match result {
+            Ok(value) => {
+                replay.value = value;
+                replay.version += 1;
+                replay.valid_bytes += len as u64;
+                f(replay.version, record, &replay.value);
+            }
+            Err(e) => {
+                replay.error = Some(e);
+                break;
+            }
+        }
+    }
+    Ok(replay)
+}
+
+#[test]
+fn replay_stops_at_a_bad_or_unterminated_record() {
+    let first = "[{\"op\":\"add\",\"path\":\"\",\"value\":[1]}]\n";
+    let log = format!("{}[{{\"op\":\"remove\",\"path\":\"/5\"}}]\n", first);
+    let replayed = replay(log.as_bytes()).unwrap();
+    assert_eq!((replayed.version, replayed.valid_bytes), (1, first.len() as u64));
+    match replayed.error {
+        Some(RecordError::PatchError(_)) => (),
+        other => panic!("{:?}", other),
+    }
+    let log = format!("{}[{{\"op\":\"add\",\"path\":\"/0\"", first);
+    let replayed = replay(log.as_bytes()).unwrap();
+    assert_eq!(replayed.version, 1);
+    match replayed.error {
+        Some(RecordError::Truncated) => (),
+        other => panic!("{:?}", other),
+    }
+}
@@ -1,18 +1,84 @@
-extern crate hyper;
-#[macro_use]
-extern crate mime;
-extern crate unicase;
-extern crate serde_json;
-extern crate json_patch;
+extern crate env_logger;
+extern crate getopts;
+extern crate json_api;
 
+use std::env;
+use std::io::prelude::*;
+use std::io::stderr;
+use std::process::exit;
 
-#[macro_use]
-mod macros;
-pub mod server;
-mod database;
-mod shared_value;
-mod patch_helpers;
+use getopts::{Matches, Options};
+
+use json_api::config::Config;
+use json_api::server;
+
+const USAGE: &'static str = "Usage: json-api [options] <command>
+
+Commands:
+    serve           Run the HTTP server
+    check-config    Validate the configuration and exit";
 
 pub fn main() {
-    server::start("0.0.0.0:3000");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let _ = env_logger::init();
+
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "read settings from a TOML file", "FILE");
+    opts.optopt("b", "bind", "address to listen on (default 0.0.0.0:3000)", "ADDR");
+    opts.optopt("d", "data-dir", "directory holding the document logs", "DIR");
+    opts.optopt("t", "threads", "number of request handling threads", "N");
+    opts.optopt("", "durability", "os or fsync", "MODE");
+    opts.optflag("h", "help", "print this help");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => fail(&format!("{}\n\n{}", e, opts.usage(USAGE))),
+    };
+    if matches.opt_present("h") {
+        println!("{}", opts.usage(USAGE));
+        return;
+    }
+
+    let config = match load_config(&matches) {
+        Ok(config) => config,
+        Err(message) => fail(&message),
+    };
+
+    match matches.free.first().map(|c| &c[..]) {
+        Some("serve") => {
+            if let Err(e) = server::start(&config) {
+                fail(&e.to_string());
+            }
+        }
+        Some("check-config") => println!("configuration ok"),
+        Some(command) => fail(&format!("unknown command {:?}\n\n{}", command, opts.usage(USAGE))),
+        None => fail(&opts.usage(USAGE)),
+    }
+}
+
+/// Read the config file (if any) and apply command-line overrides to it
+fn load_config(matches: &Matches) -> Result<Config, String> {
+    let mut config = match matches.opt_str("config") {
+        Some(filename) => try!(Config::from_file(&filename).map_err(|e| e.to_string())),
+        None => Config::default(),
+    };
+    if let Some(bind) = matches.opt_str("bind") {
+        config.bind = bind;
+    }
+    if let Some(dir) = matches.opt_str("data-dir") {
+        config.data_dir = dir;
+    }
+    if let Some(threads) = matches.opt_str("threads") {
+        config.threads = try!(threads.parse()
+                                     .map_err(|_| "--threads must be a number".to_string()));
+    }
+    if let Some(durability) = matches.opt_str("durability") {
+        config.durability = try!(durability.parse());
+    }
+    Ok(config)
+}
+
+fn fail(message: &str) -> ! {
+    let _ = writeln!(stderr(), "json-api: {}", message);
+    exit(1)
 }
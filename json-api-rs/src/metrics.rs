@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use database::{CacheStats, DbStats};
+
+/// Upper bounds of the request latency buckets, in seconds
+const LATENCY_BUCKETS: &'static [f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+                                          2.5, 5.0];
+/// Upper bounds of the patch size buckets, in operations
+const OPS_BUCKETS: &'static [f64] = &[1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 1000.0];
+
+/// Request counters and histograms, rendered in the Prometheus text exposition format
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(String, u16), usize>>,
+    latency: Histogram,
+    patch_ops: Histogram,
+}
+
+/// A histogram with fixed buckets. Observations are stored in millionths so they fit in atomics.
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<AtomicUsize>,
+    sum_millionths: AtomicUsize,
+    count: AtomicUsize,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds: bounds,
+            counts: bounds.iter().map(|_| AtomicUsize::new(0)).collect(),
+            sum_millionths: AtomicUsize::new(0),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            if value <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_millionths.fetch_add((value * 1e6) as usize, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out,
+                             "{}_bucket{{le=\"{}\"}} {}",
+                             name,
+                             bound,
+                             count.load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out,
+                         "{}_sum {}",
+                         name,
+                         self.sum_millionths.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            latency: Histogram::new(LATENCY_BUCKETS),
+            patch_ops: Histogram::new(OPS_BUCKETS),
+        }
+    }
+
+    pub fn record_request(&self, method: &str, status: u16, latency: Duration) {
+        if let Ok(mut requests) = self.requests.lock() {
+            *requests.entry((method.to_string(), status)).or_insert(0) += 1;
+        }
+        let seconds = latency.as_secs() as f64 + latency.subsec_nanos() as f64 / 1e9;
+        self.latency.observe(seconds);
+    }
+
+    /// Record the size of a patch that was successfully applied
+    pub fn record_patch(&self, ops: usize) {
+        self.patch_ops.observe(ops as f64);
+    }
+
+    pub fn render(&self, db: &DbStats, cache: &CacheStats) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP json_api_requests_total HTTP requests handled");
+        let _ = writeln!(out, "# TYPE json_api_requests_total counter");
+        if let Ok(requests) = self.requests.lock() {
+            for (&(ref method, status), count) in requests.iter() {
+                let _ = writeln!(out,
+                                 "json_api_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                                 method,
+                                 status,
+                                 count);
+            }
+        }
+        self.latency.render(&mut out,
+                            "json_api_request_duration_seconds",
+                            "Time taken to handle HTTP requests");
+        self.patch_ops.render(&mut out,
+                              "json_api_patch_ops",
+                              "Operations per successfully applied patch");
+
+        counter(&mut out,
+                "json_api_patches_applied_total",
+                "Patches committed to document logs",
+                db.patches_applied.load(Ordering::Relaxed));
+        counter(&mut out,
+                "json_api_patches_rejected_total",
+                "Patches that failed to apply or were refused",
+                db.patches_rejected.load(Ordering::Relaxed));
+        counter(&mut out,
+                "json_api_log_bytes_written_total",
+                "Bytes appended to document logs",
+                db.log_bytes_written.load(Ordering::Relaxed));
+
+        counter(&mut out,
+                "json_api_document_loads_total",
+                "Documents read from their logs into memory",
+                db.loads.load(Ordering::Relaxed));
+        counter(&mut out,
+                "json_api_document_evictions_total",
+                "Documents dropped from memory to stay within the cache limits",
+                db.evictions.load(Ordering::Relaxed));
+        counter(&mut out,
+                "json_api_log_files_closed_total",
+                "Document logs closed to stay within the open file limit",
+                db.writers_closed.load(Ordering::Relaxed));
+
+        gauge(&mut out,
+              "json_api_documents_loaded",
+              "Documents held in memory",
+              cache.docs as u64);
+        gauge(&mut out,
+              "json_api_documents_loaded_bytes",
+              "Serialized size of the documents held in memory, if the cache is limited by bytes",
+              cache.bytes);
+        gauge(&mut out,
+              "json_api_log_files_open",
+              "Document logs open for writing",
+              cache.open_writers as u64);
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
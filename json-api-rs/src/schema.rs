@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use json_patch::format_pointer;
+use serde_json::Value;
+
+/// Id of the document holding the schemas for every other document.
+///
+/// The document is an object mapping a document id (or an id prefix ending in `*`) to a JSON
+/// Schema. Because it is an ordinary document, patching it changes validation immediately.
+pub const SCHEMAS_DOC: &'static str = "_schemas";
+
+/// A single validation failure, located by a JSON pointer into the validated document
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub path: Vec<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", format_pointer(&self.path), self.message)
+    }
+}
+
+/// Find the schema that applies to `id` in the value of the `_schemas` document.
+///
+/// An exact id match wins, otherwise the longest matching `prefix*` key is used.
+pub fn schema_for<'a>(schemas: &'a Value, id: &str) -> Option<&'a Value> {
+    let schemas = match schemas.as_object() {
+        Some(o) => o,
+        None => return None,
+    };
+    if let Some(schema) = schemas.get(id) {
+        return Some(schema);
+    }
+    schemas.iter()
+           .filter(|&(key, _)| key.ends_with("*") && id.starts_with(&key[..key.len() - 1]))
+           .max_by_key(|&(key, _)| key.len())
+           .map(|(_, schema)| schema)
+}
+
+/// The schema that the `_schemas` document itself must satisfy
+pub fn meta_schema() -> Value {
+    let mut any_schema = BTreeMap::new();
+    any_schema.insert("type".to_string(),
+                      Value::Array(vec![Value::String("object".into()),
+                                        Value::String("boolean".into())]));
+    let mut meta = BTreeMap::new();
+    meta.insert("type".to_string(),
+                Value::Array(vec![Value::String("object".into()), Value::String("null".into())]));
+    meta.insert("additionalProperties".to_string(), Value::Object(any_schema));
+    Value::Object(meta)
+}
+
+/// Validate `value` against a (draft 4 subset) JSON Schema, collecting every failure.
+///
+/// Unknown keywords are ignored, as the spec requires.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), Vec<ValidationError>> {
+    let mut errors = vec![];
+    let mut path = vec![];
+    check(schema, value, &mut path, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn check(schema: &Value, value: &Value, path: &mut Vec<String>, errors: &mut Vec<ValidationError>) {
+    let schema = match schema {
+        &Value::Object(ref o) => o,
+        &Value::Bool(false) => return fail(path, errors, "no value is allowed here".into()),
+        _ => return,
+    };
+
+    if let Some(expected) = schema.get("type") {
+        if !type_matches(expected, value) {
+            return fail(path, errors, format!("expected type {:?}", expected));
+        }
+    }
+
+    if let Some(&Value::Array(ref allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            fail(path, errors, "value is not one of the allowed values".into());
+        }
+    }
+
+    check_combinators(schema, value, path, errors);
+
+    match value {
+        &Value::Object(ref o) => check_object(schema, o, path, errors),
+        &Value::Array(ref a) => check_array(schema, a, path, errors),
+        &Value::String(ref s) => check_string(schema, s, path, errors),
+        _ => {
+            if let Some(n) = value.as_f64() {
+                check_number(schema, n, path, errors)
+            }
+        }
+    }
+}
+
+fn check_combinators(schema: &BTreeMap<String, Value>,
+                     value: &Value,
+                     path: &mut Vec<String>,
+                     errors: &mut Vec<ValidationError>) {
+    if let Some(&Value::Array(ref all)) = schema.get("allOf") {
+        for sub in all {
+            check(sub, value, path, errors);
+        }
+    }
+    if let Some(&Value::Array(ref any)) = schema.get("anyOf") {
+        if !any.iter().any(|sub| validate(sub, value).is_ok()) {
+            fail(path, errors, "value does not match any schema in anyOf".into());
+        }
+    }
+    if let Some(&Value::Array(ref one)) = schema.get("oneOf") {
+        let matches = one.iter().filter(|sub| validate(sub, value).is_ok()).count();
+        if matches != 1 {
+            fail(path,
+                 errors,
+                 format!("value matches {} schemas in oneOf, expected exactly 1", matches));
+        }
+    }
+    if let Some(not) = schema.get("not") {
+        if validate(not, value).is_ok() {
+            fail(path, errors, "value must not match the schema in not".into());
+        }
+    }
+}
+
+fn check_object(schema: &BTreeMap<String, Value>,
+                object: &BTreeMap<String, Value>,
+                path: &mut Vec<String>,
+                errors: &mut Vec<ValidationError>) {
+    if let Some(&Value::Array(ref required)) = schema.get("required") {
+        for key in required.iter().filter_map(|k| k.as_string()) {
+            if !object.contains_key(key) {
+                fail(path, errors, format!("missing required property {:?}", key));
+            }
+        }
+    }
+
+    let properties = schema.get("properties").and_then(|p| p.as_object());
+    for (key, child) in object {
+        path.push(key.clone());
+        match properties.and_then(|p| p.get(key)) {
+            Some(sub) => check(sub, child, path, errors),
+            None => {
+                match schema.get("additionalProperties") {
+                    Some(&Value::Bool(false)) => {
+                        fail(path, errors, "additional properties are not allowed".into())
+                    }
+                    Some(sub) => check(sub, child, path, errors),
+                    None => (),
+                }
+            }
+        }
+        path.pop();
+    }
+
+    check_bounds(schema.get("minProperties"),
+                 schema.get("maxProperties"),
+                 object.len(),
+                 "properties",
+                 path,
+                 errors);
+}
+
+fn check_array(schema: &BTreeMap<String, Value>,
+               array: &[Value],
+               path: &mut Vec<String>,
+               errors: &mut Vec<ValidationError>) {
+    for (i, item) in array.iter().enumerate() {
+        let sub = match schema.get("items") {
+            Some(&Value::Array(ref tuple)) => tuple.get(i).or(schema.get("additionalItems")),
+            other => other,
+        };
+        if let Some(sub) = sub {
+            path.push(i.to_string());
+            check(sub, item, path, errors);
+            path.pop();
+        }
+    }
+
+    check_bounds(schema.get("minItems"),
+                 schema.get("maxItems"),
+                 array.len(),
+                 "items",
+                 path,
+                 errors);
+
+    if let Some(&Value::Bool(true)) = schema.get("uniqueItems") {
+        for (i, item) in array.iter().enumerate() {
+            if array[..i].contains(item) {
+                fail(path, errors, format!("item {} is a duplicate", i));
+            }
+        }
+    }
+}
+
+fn check_string(schema: &BTreeMap<String, Value>,
+                s: &str,
+                path: &mut Vec<String>,
+                errors: &mut Vec<ValidationError>) {
+    check_bounds(schema.get("minLength"),
+                 schema.get("maxLength"),
+                 s.chars().count(),
+                 "characters",
+                 path,
+                 errors);
+}
+
+fn check_number(schema: &BTreeMap<String, Value>,
+                n: f64,
+                path: &mut Vec<String>,
+                errors: &mut Vec<ValidationError>) {
+    let exclusive = |k: &str| schema.get(k) == Some(&Value::Bool(true));
+    if let Some(min) = schema.get("minimum").and_then(|m| m.as_f64()) {
+        if n < min || (n == min && exclusive("exclusiveMinimum")) {
+            fail(path, errors, format!("must be at least {}", min));
+        }
+    }
+    if let Some(max) = schema.get("maximum").and_then(|m| m.as_f64()) {
+        if n > max || (n == max && exclusive("exclusiveMaximum")) {
+            fail(path, errors, format!("must be at most {}", max));
+        }
+    }
+    if let Some(divisor) = schema.get("multipleOf").and_then(|m| m.as_f64()) {
+        if divisor > 0.0 && (n / divisor).fract() != 0.0 {
+            fail(path, errors, format!("must be a multiple of {}", divisor));
+        }
+    }
+}
+
+fn check_bounds(min: Option<&Value>,
+                max: Option<&Value>,
+                len: usize,
+                noun: &str,
+                path: &mut Vec<String>,
+                errors: &mut Vec<ValidationError>) {
+    if let Some(min) = min.and_then(|m| m.as_u64()) {
+        if (len as u64) < min {
+            fail(path, errors, format!("must have at least {} {}", min, noun));
+        }
+    }
+    if let Some(max) = max.and_then(|m| m.as_u64()) {
+        if (len as u64) > max {
+            fail(path, errors, format!("must have at most {} {}", max, noun));
+        }
+    }
+}
+
+fn type_matches(expected: &Value, value: &Value) -> bool {
+    match expected {
+        &Value::String(ref name) => is_type(name, value),
+        &Value::Array(ref names) => {
+            names.iter().filter_map(|n| n.as_string()).any(|name| is_type(name, value))
+        }
+        _ => true,
+    }
+}
+
+fn is_type(name: &str, value: &Value) -> bool {
+    match (name, value) {
+        ("null", &Value::Null) => true,
+        ("boolean", &Value::Bool(_)) => true,
+        ("string", &Value::String(_)) => true,
+        ("array", &Value::Array(_)) => true,
+        ("object", &Value::Object(_)) => true,
+        ("number", _) => value.is_number(),
+        ("integer", &Value::I64(_)) | ("integer", &Value::U64(_)) => true,
+        ("integer", &Value::F64(f)) => f.fract() == 0.0,
+        _ => false,
+    }
+}
+
+fn fail(path: &[String], errors: &mut Vec<ValidationError>, message: String) {
+    errors.push(ValidationError {
+        path: path.to_vec(),
+        message: message,
+    });
+}
+
+#[cfg(test)]
+fn parse(s: &str) -> Value {
+    ::serde_json::from_str(s).unwrap()
+}
+
+/// The failures of validating `value` against `schema`, each as `pointer: message`
+#[cfg(test)]
+fn failures(schema: &str, value: &str) -> Vec<String> {
+    match validate(&parse(schema), &parse(value)) {
+        Ok(()) => vec![],
+        Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+    }
+}
+
+/// The pointers to the values that failed validation
+#[cfg(test)]
+fn failed_at(schema: &str, value: &str) -> Vec<String> {
+    failures(schema, value).iter().map(|f| f[..f.find(": ").unwrap()].to_string()).collect()
+}
+
+#[test]
+fn types_are_checked() {
+    assert_eq!(failed_at(r#"{"type":"integer"}"#, "1"), Vec::<String>::new());
+    assert_eq!(failed_at(r#"{"type":"integer"}"#, "1.5"), vec![""]);
+    assert_eq!(failed_at(r#"{"type":"number"}"#, "1.5"), Vec::<String>::new());
+    assert_eq!(failed_at(r#"{"type":["string","null"]}"#, "null"), Vec::<String>::new());
+    assert_eq!(failed_at(r#"{"type":["string","null"]}"#, "[]"), vec![""]);
+    assert_eq!(failed_at("false", "{}"), vec![""]);
+    assert_eq!(failed_at("true", "{}"), Vec::<String>::new());
+}
+
+#[test]
+fn required_properties_are_reported_on_their_object() {
+    let schema = r#"{"properties":{"a":{"required":["b","c"]}}}"#;
+    assert_eq!(failures(schema, r#"{"a":{"b":1}}"#),
+               vec![r#"/a: missing required property "c""#]);
+    assert_eq!(failures(schema, r#"{"a":{"b":1,"c":2}}"#), Vec::<String>::new());
+}
+
+#[test]
+fn additional_properties_apply_to_unlisted_keys() {
+    let closed = r#"{"properties":{"a":{"type":"number"}},"additionalProperties":false}"#;
+    assert_eq!(failed_at(closed, r#"{"a":"x","b":1}"#), vec!["/a", "/b"]);
+    assert_eq!(failures(closed, r#"{"b":1}"#),
+               vec!["/b: additional properties are not allowed"]);
+    let typed = r#"{"properties":{"a":{}},"additionalProperties":{"type":"string"}}"#;
+    assert_eq!(failed_at(typed, r#"{"a":1,"b":"x","c":2}"#), vec!["/c"]);
+}
+
+#[test]
+fn items_are_checked_by_index() {
+    let list = r#"{"items":{"type":"string"}}"#;
+    assert_eq!(failed_at(list, r#"["a",1,"b",null]"#), vec!["/1", "/3"]);
+    let tuple = r#"{"items":[{"type":"string"},{"type":"number"}],"additionalItems":false}"#;
+    assert_eq!(failed_at(tuple, r#"["a",1]"#), Vec::<String>::new());
+    assert_eq!(failed_at(tuple, r#"[1,"a",true]"#), vec!["/0", "/1", "/2"]);
+}
+
+#[test]
+fn enums_allow_only_their_values() {
+    let schema = r#"{"enum":["red",{"rgb":[0,0,0]}]}"#;
+    assert_eq!(failures(schema, r#""red""#), Vec::<String>::new());
+    assert_eq!(failures(schema, r#"{"rgb":[0,0,0]}"#), Vec::<String>::new());
+    assert_eq!(failures(schema, r#""blue""#),
+               vec![": value is not one of the allowed values"]);
+}
+
+#[test]
+fn bounds_are_inclusive_unless_exclusive() {
+    let range = r#"{"minimum":1,"maximum":3}"#;
+    assert_eq!(failures(range, "1"), Vec::<String>::new());
+    assert_eq!(failures(range, "3"), Vec::<String>::new());
+    assert_eq!(failures(range, "0"), vec![": must be at least 1"]);
+    assert_eq!(failures(range, "4"), vec![": must be at most 3"]);
+    let exclusive = r#"{"minimum":1,"exclusiveMinimum":true,
+                        "maximum":3,"exclusiveMaximum":true}"#;
+    assert_eq!(failed_at(exclusive, "1"), vec![""]);
+    assert_eq!(failed_at(exclusive, "3"), vec![""]);
+    assert_eq!(failed_at(exclusive, "2"), Vec::<String>::new());
+    assert_eq!(failures(r#"{"minLength":2,"maxLength":3}"#, r#""é""#),
+               vec![": must have at least 2 characters"]);
+    assert_eq!(failures(r#"{"maxItems":1}"#, "[1,2]"),
+               vec![": must have at most 1 items"]);
+    assert_eq!(failures(r#"{"minProperties":1}"#, "{}"),
+               vec![": must have at least 1 properties"]);
+}
+
+#[test]
+fn every_failure_is_reported_with_its_pointer() {
+    let schema = r#"{"properties":{"users":{"items":{"properties":{"name":{"type":"string"},
+                                                                  "age":{"minimum":0}}}}}}"#;
+    let value = r#"{"users":[{"name":"a","age":1},{"name":2,"age":-1},{"name":"c/d"}]}"#;
+    assert_eq!(failed_at(schema, value), vec!["/users/1/age", "/users/1/name"]);
+    assert_eq!(failures(schema, value)[0], "/users/1/age: must be at least 0");
+    assert_eq!(failed_at(r#"{"properties":{"a/b":{"items":false}}}"#, r#"{"a/b":[1]}"#),
+               vec!["/a~1b/0"]);
+}
+
+#[test]
+fn schemas_are_found_by_id_then_longest_prefix() {
+    let schemas = parse(r#"{"notes":{"type":"object"},"notes*":{"type":"array"},
+                            "n*":{"type":"null"}}"#);
+    let type_of = |id: &str| schema_for(&schemas, id).and_then(|s| s.find("type")).cloned();
+    assert_eq!(type_of("notes"), Some(Value::String("object".into())));
+    assert_eq!(type_of("notes-1"), Some(Value::String("array".into())));
+    assert_eq!(type_of("n"), Some(Value::String("null".into())));
+    assert_eq!(type_of("other"), None);
+}
@@ -1,54 +1,142 @@
-use patch_helpers::prefix_patch_paths;
-
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
 use std::fs::File;
-use std::sync::{RwLock, PoisonError};
-use unicase::UniCase;
+use std::sync::{Arc, RwLock, PoisonError};
+use std::thread;
+use std::time::Instant;
+use hyper;
 use hyper::method::Method;
 use hyper::status::StatusCode;
 use hyper::server::{Handler, Server, Listening, Request, Response};
 use hyper::uri::RequestUri;
-use hyper::header::{AccessControlAllowOrigin, AccessControlAllowMethods, AccessControlAllowHeaders,
-                    ContentType};
+use hyper::header::{ContentLength, ContentType};
 
 use serde_json;
 use serde_json::Value;
 use json_patch;
-use json_patch::{Op, Patch};
+use json_patch::{ApplyOptions, MergeResult, Op, Patch, TestFailure};
 
-use database::{Database, DbError};
+use auth;
+use collab;
+use auth::{Access, AuthConfigError, Authenticator, HmacTokens, Principal, StaticTokens};
+use config::{Config, Limits};
+use cors::CorsPolicy;
+use crdt;
+use crdt::Clock;
+use database::{Database, DbError, DbOptions, Edit, ExportFormat};
+use etag;
+use etag::IfMatch;
+use metrics::Metrics;
+use schema::ValidationError;
 use shared_value::SharedValue;
 
-struct App(Database<File>);
+struct App {
+    /// Shared with the WebSocket listener
+    db: Arc<Database<File>>,
+    /// Tried in order for every request; when empty authentication is disabled
+    authenticators: Arc<Vec<Box<Authenticator>>>,
+    cors: CorsPolicy,
+    limits: Limits,
+    metrics: Metrics,
+}
 
 #[derive(Debug)]
 struct GlobalJsonPointer<'a> {
     doc_id: &'a str,
-    pointer: Vec<&'a str>,
+    /// The rest of the path read as a JSON pointer, with `~1` and `~0` unescaped
+    pointer: Vec<String>,
+    query: &'a str,
 }
 
+impl<'a> GlobalJsonPointer<'a> {
+    fn tokens(&self) -> Vec<&str> {
+        self.pointer.iter().map(|s| &s[..]).collect()
+    }
+
+    /// The value of a `name=value` query parameter, or `""` for a bare `name`
+    fn param(&self, name: &str) -> Option<&'a str> {
+        self.query
+            .split("&")
+            .filter_map(|pair| {
+                let mut kv = pair.splitn(2, "=");
+                if kv.next() == Some(name) {
+                    Some(kv.next().unwrap_or(""))
+                } else {
+                    None
+                }
+            })
+            .next()
+    }
+}
+
+/// `GET` streams every document as newline-delimited JSON
+const EXPORT: &'static str = "_export";
+/// `POST` restores documents from the output of `EXPORT`
+const IMPORT: &'static str = "_import";
+/// `GET` returns counters and histograms in the Prometheus text format
+const METRICS: &'static str = "_metrics";
+/// CRDT documents live below this, at `/_crdt/<id>`. `GET` reads them, `PATCH` edits them as the
+/// server's replica and `POST` syncs with another replica: the body `{"clock":{...},"ops":[...]}`
+/// carries the other replica's clock and the ops it has that the server may not, and the reply
+/// has the server's clock and the ops the other replica is missing.
+const CRDT: &'static str = "_crdt";
+
 #[derive(Debug)]
 pub enum ApiError {
     BadUri,
+    BodyTooLarge(u64),
+    TooManyOps(usize),
+    Unauthorized,
+    Forbidden,
     DocumentDoesNotExist,
     PathDoesNotExist,
+    IoError(io::Error),
     JsonError(serde_json::Error),
     InvalidPatchError(json_patch::InvalidPatchError),
+    InvalidCrdtOp(String),
+    DryRunUnsupported,
+    /// A `?merge` body was not an object with `base` and `value`
+    BadMergeBody,
     PatchFailedError(json_patch::PatchError),
     DbError(DbError),
 }
 
 wrap_error!(DbError, ApiError::DbError);
 wrap_error!(json_patch::InvalidPatchError, ApiError::InvalidPatchError);
+wrap_error!(io::Error, ApiError::IoError);
 wrap_error!(serde_json::Error, ApiError::JsonError);
 wrap_error!(json_patch::PatchError, ApiError::PatchFailedError);
 
-struct Reply(StatusCode, String);
+struct Reply {
+    status: StatusCode,
+    body: String,
+    /// Canonical hash of the JSON value in `body`, sent as the `ETag`
+    etag: Option<String>,
+    /// URI of a value the request created
+    location: Option<String>,
+}
+
+impl Reply {
+    fn new(status: StatusCode, body: String) -> Reply {
+        Reply {
+            status: status,
+            body: body,
+            etag: None,
+            location: None,
+        }
+    }
+}
 
 impl From<Value> for Reply {
     fn from(v: Value) -> Reply {
-        Reply(StatusCode::Ok, format!("{:?}", v))
+        Reply {
+            status: StatusCode::Ok,
+            body: format!("{:?}", v),
+            etag: Some(etag::hash(&v)),
+            location: None,
+        }
     }
 }
 
@@ -61,112 +149,634 @@ impl From<Result<Reply, ApiError>> for Reply {
     }
 }
 
+impl<'a> From<&'a [ValidationError]> for Reply {
+    fn from(errors: &[ValidationError]) -> Reply {
+        let errors = errors.iter()
+                           .map(|e| {
+                               let mut o = BTreeMap::new();
+                               o.insert("path".to_string(),
+                                        Value::String(json_patch::format_pointer(&e.path)));
+                               o.insert("message".to_string(), Value::String(e.message.clone()));
+                               Value::Object(o)
+                           })
+                           .collect();
+        let mut body = BTreeMap::new();
+        body.insert("message".to_string(),
+                    Value::String("document failed schema validation".into()));
+        body.insert("errors".to_string(), Value::Array(errors));
+        Reply::new(StatusCode::UnprocessableEntity, format!("{:?}", Value::Object(body)))
+    }
+}
+
+impl<'a> From<&'a TestFailure> for Reply {
+    fn from(failure: &TestFailure) -> Reply {
+        let mut body = BTreeMap::new();
+        body.insert("message".to_string(), Value::String(failure.to_string()));
+        body.insert("op".to_string(), Value::U64(failure.op as u64));
+        body.insert("path".to_string(),
+                    Value::String(json_patch::format_pointer(&failure.path)));
+        Reply::new(StatusCode::Conflict, format!("{:?}", Value::Object(body)))
+    }
+}
+
+impl<'a> From<&'a MergeResult> for Reply {
+    fn from(merged: &MergeResult) -> Reply {
+        let conflicts = merged.conflicts
+                              .iter()
+                              .map(|c| {
+                                  let mut o = BTreeMap::new();
+                                  o.insert("path".to_string(),
+                                           Value::String(json_patch::format_pointer(&c.path)));
+                                  let sides = vec![("base", &c.base),
+                                                   ("ours", &c.ours),
+                                                   ("theirs", &c.theirs)];
+                                  for (side, value) in sides {
+                                      if let Some(ref value) = *value {
+                                          o.insert(side.to_string(), value.clone());
+                                      }
+                                  }
+                                  Value::Object(o)
+                              })
+                              .collect();
+        let mut body = BTreeMap::new();
+        body.insert("message".to_string(),
+                    Value::String("the edit conflicts with changes since its base".into()));
+        body.insert("conflicts".to_string(), Value::Array(conflicts));
+        body.insert("value".to_string(), merged.value.clone());
+        Reply::new(StatusCode::Conflict, format!("{:?}", Value::Object(body)))
+    }
+}
+
 impl From<ApiError> for Reply {
     fn from(err: ApiError) -> Reply {
+        if let ApiError::DbError(DbError::ValidationError(ref errors)) = err {
+            return Reply::from(&errors[..]);
+        }
+        if let ApiError::DbError(DbError::TestFailed(ref failure)) = err {
+            return Reply::from(failure);
+        }
+        if let ApiError::DbError(DbError::MergeConflict(ref merged)) = err {
+            return Reply::from(merged);
+        }
+
         let (code, message) = match err {
             ApiError::JsonError(e) => (StatusCode::BadRequest, format!("{:?}", e)),
             ApiError::InvalidPatchError(e) => (StatusCode::BadRequest, format!("{:?}", e)),
+            ApiError::InvalidCrdtOp(e) => (StatusCode::BadRequest, e),
+            ApiError::DryRunUnsupported => (StatusCode::BadRequest,
+                                            "only PATCH requests can be dry runs".into()),
+            ApiError::BadMergeBody => (StatusCode::BadRequest,
+                                       "a merge must have a base and a value".into()),
             ApiError::BadUri => (StatusCode::BadRequest,
                                  "URI must be utf8 with at least one path component".into()),
-            ApiError::DbError(e) => (StatusCode::InternalServerError, format!("{:?}", e)),
+            ApiError::IoError(e) => (StatusCode::BadRequest, format!("{:?}", e)),
+            ApiError::BodyTooLarge(max) => (StatusCode::PayloadTooLarge,
+                                            format!("request body exceeds {} bytes", max)),
+            ApiError::TooManyOps(max) => (StatusCode::PayloadTooLarge,
+                                          format!("patch has more than {} operations", max)),
+            ApiError::Unauthorized => (StatusCode::Unauthorized,
+                                       "a valid bearer token is required".into()),
+            ApiError::Forbidden => (StatusCode::Forbidden, "access denied".into()),
             ApiError::DocumentDoesNotExist => (StatusCode::NotFound, "no such document".into()),
             ApiError::PathDoesNotExist => (StatusCode::NotFound, "path does not exist".into()),
+            ApiError::DbError(e) => {
+                let (status, message) = e.status();
+                (StatusCode::from_u16(status), message)
+            }
             ApiError::PatchFailedError(_) => (StatusCode::BadRequest,
                                               "patch could not be applied".into()),
         };
 
-        Reply(code, format!(r#"{{"message":"{}"}}"#, message))
+        Reply::new(code, format!(r#"{{"message":"{}"}}"#, message))
     }
 }
 
 impl <'a>From<(StatusCode, &'a str)> for Reply {
     fn from(tuple: (StatusCode, &str)) -> Reply {
-        Reply(tuple.0, tuple.1.into())
+        Reply::new(tuple.0, tuple.1.into())
     }
 }
 
 
 fn parse_uri<'a>(uri: &'a RequestUri) -> Result<GlobalJsonPointer<'a>, ApiError> {
     match uri {
-        &RequestUri::AbsolutePath(ref string_path) => {
-            let mut parts = string_path.split("/").skip(1);
+        &RequestUri::AbsolutePath(ref uri) => {
+            let mut halves = uri.splitn(2, "?");
+            let string_path = halves.next().unwrap_or("");
+            let mut parts = string_path.splitn(3, "/").skip(1);
             let doc_id = try!(parts.next().ok_or(ApiError::BadUri));
+            let pointer = parts.next().map(|rest| json_patch::parse_pointer(&format!("/{}", rest)));
             Ok(GlobalJsonPointer {
                 doc_id: doc_id,
-                pointer: parts.collect(),
+                pointer: pointer.unwrap_or(vec![]),
+                query: halves.next().unwrap_or(""),
             })
         }
         _ => Err(ApiError::BadUri),
     }
 }
 
-fn parse_patch(req: Request) -> Result<Patch, ApiError> {
+/// The URI of the value at `path` in a document, which `parse_uri` reads back
+fn location(doc_id: &str, path: &[String]) -> String {
+    format!("/{}{}", doc_id, json_patch::format_pointer(path))
+}
+
+/// The edit a `PATCH`, `PUT`, `POST` or `DELETE` request asks for
+///
+/// `mkdirs`, from the `?mkdirs` query parameter, has `PUT` and `PATCH` create missing parents.
+/// `merge`, from `?merge`, has `PUT` take `{"base", "value"}` and merge the changes from `base`
+/// to `value` into the current value.
+fn parse_edit(req: Request, limits: &Limits, mkdirs: bool, merge: bool) -> Result<Edit, ApiError> {
     match req.method {
-        Method::Get => unreachable!(),
+        Method::Put if merge => {
+            let mut body = try!(read_body(req, limits));
+            let mut take = |k: &str| body.as_object_mut().and_then(|o| o.remove(k));
+            match (take("base"), take("value")) {
+                (Some(base), Some(value)) => {
+                    Ok(Edit::Merge {
+                        base: base,
+                        value: value,
+                    })
+                }
+                _ => Err(ApiError::BadMergeBody),
+            }
+        }
         Method::Patch => {
-            let value = try!(serde_json::from_reader(req));
-            Patch::from_value(value).map_err(|e| e.into())
+            let body = try!(read_body(req, limits));
+            let patch = try!(Patch::from_value_with(body, limits.parse_options()));
+            match limits.max_ops {
+                Some(max) if patch.ops.len() > max => Err(ApiError::TooManyOps(max)),
+                _ => Ok(Edit::Patch(patch, ApplyOptions { create_parents: mkdirs })),
+            }
         }
         Method::Put => {
-            let value = try!(serde_json::from_reader(req));
-            Ok(Patch { ops: vec![Op::Add(vec![], value)] })
-        }
-        Method::Delete => {
-            Ok(Patch { ops: vec![Op::Remove(vec![])] })
+            Ok(Edit::Put {
+                value: try!(read_body(req, limits)),
+                mkdirs: mkdirs,
+            })
         }
+        Method::Post => Ok(Edit::Append(try!(read_body(req, limits)))),
+        Method::Delete => Ok(Edit::Delete),
         _ => Err(ApiError::BadUri),
     }
 }
 
-pub fn start(addr: &str) -> Listening {
-    let db = Database::open("./logs").unwrap();
-    let app = App(db);
-    Server::http(addr).unwrap().handle(app).unwrap()
+/// Parse the request body as JSON, refusing to buffer more than `limits.max_body_bytes`
+fn read_body(req: Request, limits: &Limits) -> Result<Value, ApiError> {
+    let mut body = vec![];
+    try!(req.take(limits.max_body_bytes + 1).read_to_end(&mut body));
+    if body.len() as u64 > limits.max_body_bytes {
+        return Err(ApiError::BodyTooLarge(limits.max_body_bytes));
+    }
+    serde_json::from_reader(&body[..]).map_err(|e| e.into())
+}
+
+#[derive(Debug)]
+pub enum StartError {
+    IoError(io::Error),
+    HttpError(hyper::Error),
+    AuthConfigError(AuthConfigError),
+}
+
+wrap_error!(io::Error, StartError::IoError);
+wrap_error!(hyper::Error, StartError::HttpError);
+wrap_error!(AuthConfigError, StartError::AuthConfigError);
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &StartError::IoError(ref e) => write!(f, "could not open database: {}", e),
+            &StartError::HttpError(ref e) => write!(f, "could not start server: {}", e),
+            &StartError::AuthConfigError(ref e) => write!(f, "could not load tokens: {:?}", e),
+        }
+    }
+}
+
+pub fn start(config: &Config) -> Result<Listening, StartError> {
+    let mut authenticators: Vec<Box<Authenticator>> = vec![];
+    if let Some(ref filename) = config.tokens_file {
+        authenticators.push(Box::new(try!(StaticTokens::from_file(filename))));
+    }
+    if let Some(ref secret) = config.hmac_secret {
+        authenticators.push(Box::new(HmacTokens::new(secret.as_bytes())));
+    }
+
+    let db = Arc::new(try!(Database::open(&config.data_dir,
+                                          DbOptions {
+                                              durability: config.durability,
+                                              quotas: config.quotas.clone(),
+                                              cache: config.cache.clone(),
+                                          })));
+    let authenticators = Arc::new(authenticators);
+    if let Some(ref bind) = config.websocket_bind {
+        let (bind, db, authenticators) = (bind.clone(), db.clone(), authenticators.clone());
+        let limits = config.limits.clone();
+        thread::spawn(move || {
+            if let Err(e) = collab::listen(&bind, db, authenticators, limits) {
+                error!("WebSocket listener on {} failed: {}", bind, e);
+            }
+        });
+    }
+
+    let app = App {
+        db: db,
+        authenticators: authenticators,
+        cors: config.cors.clone(),
+        limits: config.limits.clone(),
+        metrics: Metrics::new(),
+    };
+    let server = try!(Server::http(&config.bind[..]));
+    Ok(try!(server.handle_threads(app, config.threads)))
 }
 
 impl Handler for App {
     fn handle(&self, req: Request, mut res: Response) {
-        {
-            let headers = res.headers_mut();
-            headers.set(AccessControlAllowOrigin::Any);
-            headers.set(AccessControlAllowMethods(vec![Method::Get, Method::Patch]));
-            headers.set(AccessControlAllowHeaders(vec![UniCase("content-type".into()),
+        let started = Instant::now();
+        let method = req.method.clone();
+        let uri = req.uri.clone();
+        let target = parse_uri(&uri).ok();
+        let mut info = RequestInfo {
+            ops: 0,
+            bytes_in: req.headers.get::<ContentLength>().map(|l| l.0).unwrap_or(0),
+        };
 
-                                                       UniCase("authorization".into())]));
+        let origin = req.headers
+                        .get_raw("origin")
+                        .and_then(|values| values.first())
+                        .and_then(|value| String::from_utf8(value.clone()).ok());
+        let cors_allowed = {
+            let headers = res.headers_mut();
             headers.set(ContentType(mime!(Application / Json)));
-        }
-
-        let reply: Reply = self.try_request(req).into();
+            self.cors.set_origin_headers(origin.as_ref().map(|o| &o[..]), headers)
+        };
 
-        {
-            let mut status = res.status_mut();
-            *status = reply.0;
-        }
-        match res.start().unwrap().write_all(reply.1.as_bytes()) {
-            Ok(_) => (),
-            Err(err) => {
-                println!("Error writing response: {}", err);
+        let doc_id = target.as_ref().map(|p| p.doc_id).unwrap_or("");
+        let (status, bytes_out) = match (&method, doc_id) {
+            (&Method::Options, _) => {
+                let status = self.cors.preflight(cors_allowed, &req.headers, res.headers_mut());
+                send(res, Reply::new(status, String::new()))
             }
+            (&Method::Get, EXPORT) => self.export(req, res),
+            (&Method::Get, METRICS) => self.serve_metrics(req, res),
+            _ => {
+                let reply = self.try_request(req, &mut info).into();
+                send(res, reply)
+            }
+        };
+
+        let latency = started.elapsed();
+        self.metrics.record_request(method.as_ref(), status.to_u16(), latency);
+        if status.is_success() && info.ops > 0 {
+            self.metrics.record_patch(info.ops);
         }
+        info!(target: "json_api::access",
+              "method={} doc={:?} pointer={:?} status={} latency_ms={:.3} ops={} bytes_in={} \
+               bytes_out={}",
+              method,
+              doc_id,
+              target.as_ref().map_or(String::new(), |p| json_patch::format_pointer(&p.pointer)),
+              status.to_u16(),
+              latency.as_secs() as f64 * 1e3 + latency.subsec_nanos() as f64 / 1e6,
+              info.ops,
+              info.bytes_in,
+              bytes_out);
+    }
+}
+
+/// Details of a request that are only known while handling it, kept for the access log
+struct RequestInfo {
+    ops: usize,
+    bytes_in: u64,
+}
+
+/// Send `reply`, returning its status and body length for the access log
+fn send(mut res: Response, reply: Reply) -> (StatusCode, u64) {
+    if reply.status == StatusCode::Unauthorized {
+        res.headers_mut().set_raw("WWW-Authenticate", vec![b"Bearer".to_vec()]);
+    }
+    if let Some(ref etag) = reply.etag {
+        res.headers_mut().set_raw("ETag", vec![format!("\"{}\"", etag).into_bytes()]);
+    }
+    if let Some(ref location) = reply.location {
+        res.headers_mut().set_raw("Location", vec![location.clone().into_bytes()]);
+    }
+
+    {
+        let mut status = res.status_mut();
+        *status = reply.status;
+    }
+    let written = res.start().and_then(|mut stream| {
+        try!(stream.write_all(reply.body.as_bytes()));
+        stream.end()
+    });
+    if let Err(err) = written {
+        warn!("Error writing response: {}", err);
+    }
+    (reply.status, reply.body.len() as u64)
+}
+
+/// Counts the bytes passed through to the inner writer
+struct Counted<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = try!(self.inner.write(buf));
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
 impl App {
-    fn try_request(&self, req: Request) -> Result<Reply, ApiError> {
+    fn try_request(&self, req: Request, info: &mut RequestInfo) -> Result<Reply, ApiError> {
         let uri = req.uri.clone();
         let p = try!(parse_uri(&uri));
+        let principal = try!(self.authenticate(&req));
+
+        if req.method == Method::Post && p.doc_id == IMPORT {
+            try!(authorize_all(&principal, Access::Write));
+            let count = try!(self.db.import(io::BufReader::new(req)));
+            return Ok(Reply::new(StatusCode::Ok, format!(r#"{{"imported":{}}}"#, count)));
+        }
+
+        if p.doc_id == CRDT {
+            return self.crdt_request(req, &p, &principal, info);
+        }
 
+        let pointer = p.tokens();
         if req.method == Method::Get {
-            return self.0
-                       .find_in_doc(p.doc_id, &p.pointer)
+            try!(authorize(&principal, Access::Read, p.doc_id, &pointer));
+            return self.db
+                       .find_in_doc(p.doc_id, &pointer)
                        .map(|v| v.into())
                        .map_err(|e| e.into());
         }
 
-        let patch = try!(parse_patch(req));
-        match self.0.patch_doc(p.doc_id, patch, &p.pointer) {
-            Ok(v) => Ok(v.into()),
-            Err(e) => Err(e.into()),
+        let if_match = req.headers
+                          .get_raw("if-match")
+                          .and_then(|values| values.first())
+                          .and_then(|value| ::std::str::from_utf8(value).ok())
+                          .map(IfMatch::parse);
+        let mkdirs = p.param("mkdirs").map_or(false, |v| v != "false" && v != "0");
+        let dry_run = p.param("dry_run").map_or(false, |v| v != "false" && v != "0");
+        let merge = p.param("merge").map_or(false, |v| v != "false" && v != "0");
+        let edit = try!(parse_edit(req, &self.limits, mkdirs, merge));
+        info.ops = match edit {
+            Edit::Patch(ref patch, _) => {
+                try!(authorize_patch(&principal, p.doc_id, &pointer, patch));
+                patch.ops.len()
+            }
+            _ => {
+                try!(authorize(&principal, Access::Write, p.doc_id, &pointer));
+                1
+            }
+        };
+
+        if dry_run {
+            let edited = match edit {
+                Edit::Patch(patch, options) => {
+                    try!(self.db.preview_patch(p.doc_id,
+                                               &pointer,
+                                               patch,
+                                               options,
+                                               if_match.as_ref()))
+                }
+                _ => return Err(ApiError::DryRunUnsupported),
+            };
+            // nothing was applied
+            info.ops = 0;
+            let mut body = BTreeMap::new();
+            body.insert("version".to_string(), Value::U64(edited.version as u64));
+            body.insert("value".to_string(), edited.value.unwrap_or(Value::Null));
+            return Ok(Reply::new(StatusCode::Ok, format!("{:?}", Value::Object(body))));
         }
+
+        let edited = try!(self.db.edit_doc(p.doc_id, &pointer, edit, if_match.as_ref()));
+        let mut reply = match edited.value {
+            Some(value) => Reply::from(value),
+            None => Reply::new(StatusCode::NoContent, String::new()),
+        };
+        if edited.created {
+            reply.status = StatusCode::Created;
+            reply.location = Some(location(p.doc_id, &edited.path));
+        }
+        Ok(reply)
     }
+
+    /// Read, edit or sync the CRDT document named by the first token of the pointer
+    fn crdt_request(&self,
+                    req: Request,
+                    p: &GlobalJsonPointer,
+                    principal: &Option<Principal>,
+                    info: &mut RequestInfo)
+                    -> Result<Reply, ApiError> {
+        let tokens = p.tokens();
+        let (id, pointer) = match tokens.split_first() {
+            Some((id, pointer)) if !id.is_empty() => (*id, pointer),
+            _ => return Err(ApiError::BadUri),
+        };
+        match req.method {
+            Method::Get => {
+                try!(authorize(principal, Access::Read, id, pointer));
+                Ok(try!(self.db.find_in_crdt(id, pointer)).into())
+            }
+            Method::Patch => {
+                let body = try!(read_body(req, &self.limits));
+                let patch = try!(Patch::from_value_with(body, self.limits.parse_options()));
+                if let Some(max) = self.limits.max_ops {
+                    if patch.ops.len() > max {
+                        return Err(ApiError::TooManyOps(max));
+                    }
+                }
+                try!(authorize_patch(principal, id, pointer, &patch));
+                info.ops = patch.ops.len();
+                let patch = patch.prefixed(pointer);
+                let value = try!(self.db.patch_crdt(id, &patch));
+                json_patch::find_path(&value, pointer)
+                    .map(|value| value.clone().into())
+                    .ok_or(ApiError::PathDoesNotExist)
+            }
+            Method::Post if pointer.is_empty() => {
+                // ops can change any part of the document
+                try!(authorize(principal, Access::Write, id, pointer));
+                let body = try!(read_body(req, &self.limits));
+                let clock = match body.find("clock") {
+                    Some(clock) => {
+                        try!(crdt::clock_from_value(clock).map_err(ApiError::InvalidCrdtOp))
+                    }
+                    None => Clock::new(),
+                };
+                let ops = match body.find("ops") {
+                    Some(&Value::Array(ref ops)) => {
+                        try!(ops.iter()
+                                .map(crdt::Op::from_value)
+                                .collect::<Result<Vec<_>, _>>()
+                                .map_err(ApiError::InvalidCrdtOp))
+                    }
+                    None => vec![],
+                    Some(_) => return Err(ApiError::InvalidCrdtOp("ops must be an array".into())),
+                };
+                let (missing, clock, _) = try!(self.db.sync_crdt(id, ops, &clock));
+                let mut reply = BTreeMap::new();
+                reply.insert("clock".to_string(), crdt::clock_to_value(&clock));
+                reply.insert("ops".to_string(),
+                             Value::Array(missing.iter().map(crdt::Op::to_value).collect()));
+                Ok(Value::Object(reply).into())
+            }
+            _ => Err(ApiError::BadUri),
+        }
+    }
+
+    /// Stream an export straight into the response body
+    fn export(&self, req: Request, mut res: Response) -> (StatusCode, u64) {
+        let uri = req.uri.clone();
+        let format = parse_uri(&uri).and_then(|p| {
+            let principal = try!(self.authenticate(&req));
+            try!(authorize_all(&principal, Access::Read));
+            Ok(match p.param("format") {
+                Some("log") => ExportFormat::Log,
+                _ => ExportFormat::Snapshot,
+            })
+        });
+        let format = match format {
+            Ok(format) => format,
+            Err(e) => return send(res, e.into()),
+        };
+
+        res.headers_mut().set_raw("Content-Type", vec![b"application/x-ndjson".to_vec()]);
+        let mut stream = match res.start() {
+            Ok(stream) => Counted {
+                inner: stream,
+                bytes: 0,
+            },
+            Err(err) => {
+                warn!("Error writing response: {}", err);
+                return (StatusCode::Ok, 0);
+            }
+        };
+        // the status has already been sent, so all we can do is cut the stream short
+        if let Err(err) = self.db.export(&mut stream, format) {
+            error!("Error exporting: {:?}", err);
+        }
+        let bytes = stream.bytes;
+        if let Err(err) = stream.inner.end() {
+            warn!("Error writing response: {}", err);
+        }
+        (StatusCode::Ok, bytes)
+    }
+
+    fn serve_metrics(&self, req: Request, mut res: Response) -> (StatusCode, u64) {
+        let cache = self.authenticate(&req)
+                        .and_then(|principal| authorize_all(&principal, Access::Read))
+                        .and_then(|_| self.db.cache_stats().map_err(|e| e.into()));
+        match cache {
+            Ok(cache) => {
+                res.headers_mut()
+                   .set_raw("Content-Type", vec![b"text/plain; version=0.0.4".to_vec()]);
+                let body = self.metrics.render(self.db.stats(), &cache);
+                send(res, Reply::new(StatusCode::Ok, body))
+            }
+            Err(e) => send(res, e.into()),
+        }
+    }
+
+    /// Find the principal for the request's bearer token.
+    ///
+    /// Returns `None` when no authenticators are configured.
+    fn authenticate(&self, req: &Request) -> Result<Option<Principal>, ApiError> {
+        if self.authenticators.is_empty() {
+            return Ok(None);
+        }
+        let token = try!(req.headers
+                            .get_raw("authorization")
+                            .and_then(|values| values.first())
+                            .and_then(|value| auth::bearer_token(value))
+                            .ok_or(ApiError::Unauthorized));
+        self.authenticators
+            .iter()
+            .filter_map(|a| a.authenticate(token))
+            .next()
+            .map(Some)
+            .ok_or(ApiError::Unauthorized)
+    }
+}
+
+fn authorize_all(principal: &Option<Principal>, access: Access) -> Result<(), ApiError> {
+    match principal {
+        &Some(ref p) if !p.allows_all(access) => Err(ApiError::Forbidden),
+        _ => Ok(()),
+    }
+}
+
+/// Authorize a patch to the value at `pointer`, whose paths are all relative to it: writing
+/// there, and reading the source of each copy or writing that of each move
+fn authorize_patch(principal: &Option<Principal>,
+                   id: &str,
+                   pointer: &[&str],
+                   patch: &Patch)
+                   -> Result<(), ApiError> {
+    try!(authorize(principal, Access::Write, id, pointer));
+    for op in &patch.ops {
+        let (access, from) = match op {
+            &Op::Copy(_, ref from) => (Access::Read, from),
+            &Op::Move(_, ref from) => (Access::Write, from),
+            _ => continue,
+        };
+        let from: Vec<&str> = pointer.iter().cloned().chain(from.iter().map(|s| &s[..])).collect();
+        try!(authorize(principal, access, id, &from));
+    }
+    Ok(())
+}
+
+fn authorize<S: AsRef<str>>(principal: &Option<Principal>,
+                            access: Access,
+                            id: &str,
+                            path: &[S])
+                            -> Result<(), ApiError> {
+    match principal {
+        &Some(ref p) if !p.allows(access, id, path) => Err(ApiError::Forbidden),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn uris_are_read_as_escaped_pointers() {
+    let uri = RequestUri::AbsolutePath("/doc/a~1b/~0c/?dry_run".into());
+    let p = parse_uri(&uri).unwrap();
+    assert_eq!((p.doc_id, p.tokens(), p.query), ("doc", vec!["a/b", "~c", ""], "dry_run"));
+    let uri = RequestUri::AbsolutePath("/doc".into());
+    assert_eq!(parse_uri(&uri).unwrap().tokens(), Vec::<&str>::new());
+}
+
+#[test]
+fn locations_read_back_as_the_path_created() {
+    let path = vec!["a/b".to_string(), "~c".into(), "0".into()];
+    assert_eq!(location("doc", &path), "/doc/a~1b/~0c/0");
+    let uri = RequestUri::AbsolutePath(location("doc", &path));
+    assert_eq!(parse_uri(&uri).unwrap().pointer, path);
+}
+
+#[test]
+fn moves_from_outside_the_grant_are_refused() {
+    let grants = r#"{"grants":[{"access":"write","doc":"d","path":"/a"},
+                               {"access":"read","doc":"d","path":"/r"}]}"#;
+    let principal = Some(Principal::from_value("alice", &serde_json::from_str(grants).unwrap())
+                             .unwrap());
+    let allowed = |pointer: &[&str], patch: &str| {
+        authorize_patch(&principal, "d", pointer, &Patch::from_str(patch).unwrap()).is_ok()
+    };
+
+    // a source outside the grant can only be named from a pointer above it, which is refused
+    assert!(!allowed(&[], r#"[{"op":"move","path":"/a/x","from":"/b"}]"#));
+    assert!(!allowed(&[], r#"[{"op":"copy","path":"/a/x","from":"/r"}]"#));
+    assert!(!allowed(&["r"], r#"[{"op":"move","path":"/x","from":"/y"}]"#));
+    // sources are relative to the pointer, like every other path
+    assert!(allowed(&["a"], r#"[{"op":"move","path":"/x","from":"/b"}]"#));
+    assert!(authorize_patch(&None, "d", &[], &Patch::from_str("[]").unwrap()).is_ok());
 }
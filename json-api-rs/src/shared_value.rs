@@ -3,9 +3,8 @@ use std::io;
 //use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::sync::RwLock;
 
-//use patch_helpers::prefix_patch_paths;
 use serde_json::Value;
-use json_patch::{apply, Patch, InvalidPatchError, PatchError};
+use json_patch::{apply, find_path, Patch, InvalidPatchError, PatchError};
 
 /// Thread-safe Wrapper around a serde_json::Value
 #[derive(Debug)]
@@ -28,6 +27,18 @@ impl SharedValue {
         Ok(())
     }
 
+    /// Apply patches to the underlying value, only committing the result if `check` accepts it
+    pub fn patch_checked<F, E>(&self, patch: &Patch, check: F) -> Result<(), E>
+        where F: FnOnce(&Value) -> Result<(), E>,
+              E: From<PatchError>
+    {
+        let mut value = self.value.write().unwrap();
+        let next = try!(apply(patch, &value));
+        try!(check(&next));
+        *value = next;
+        Ok(())
+    }
+
 /* Lock-free impl
     /// Apply patches to the underlying value in a threadsafe way w/o locking
     fn patch(&self, patch: &Patch) -> Result<(), PatchError> {
@@ -45,8 +56,16 @@ impl SharedValue {
     }
 */
 
+    /// Run `f` against the current value while holding a read lock
+    pub fn read<F, T>(&self, f: F) -> T
+        where F: FnOnce(&Value) -> T
+    {
+        let value = self.value.read().unwrap();
+        f(&value)
+    }
+
     pub fn clone_path(&self, path: &[&str]) -> Option<Value> {
         let value = self.value.read().unwrap();
-        (*value).find_path(path).cloned()
+        find_path(&value, path).cloned()
     }
 }
@@ -0,0 +1,160 @@
+//! Which parts of a document patches read and write, and where two patches overlap.
+
+use {Op, Patch, Path};
+#[cfg(test)]
+use apply;
+#[cfg(test)]
+use testing::{random_doc, random_patch, Rng};
+
+/// The pointers a patch reads and writes, each set without any pointer under another.
+///
+/// Pointers are taken as written, so an op that follows an insertion into an array may name a
+/// shifted index; to cover that, inserting or removing an element writes the whole array. As
+/// for `transform`, tokens that are numbers or `-` are taken to be array indices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Footprint {
+    /// Values the outcome depends on: tested values and the sources of copies and moves
+    pub reads: Vec<Path>,
+    /// Values that are added, removed, replaced or moved away
+    pub writes: Vec<Path>,
+}
+
+/// Two patches touching overlapping pointers, one under the other or the same
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub kind: ConflictKind,
+    /// The pointer in the first patch's footprint
+    pub first: Path,
+    /// The pointer in the second patch's footprint
+    pub second: Path,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictKind {
+    /// Both patches write the values
+    WriteWrite,
+    /// The first patch reads a value the second writes
+    ReadWrite,
+    /// The first patch writes a value the second reads
+    WriteRead,
+}
+
+impl Patch {
+    pub fn footprint(&self) -> Footprint {
+        let mut reads = vec![];
+        let mut writes = vec![];
+        for op in &self.ops {
+            match *op {
+                Op::Add(ref path, _) | Op::Remove(ref path) => writes.push(container(path)),
+                Op::Replace(ref path, _) | Op::Extension(ref path, _) => writes.push(path.clone()),
+                Op::Test(ref path, _) | Op::Assert(ref path, _) => reads.push(path.clone()),
+                Op::Copy(ref path, ref from) => {
+                    reads.push(from.clone());
+                    writes.push(container(path));
+                }
+                Op::Move(ref path, ref from) => {
+                    reads.push(from.clone());
+                    writes.push(container(from));
+                    writes.push(container(path));
+                }
+            }
+        }
+        Footprint {
+            reads: outermost(reads),
+            writes: outermost(writes),
+        }
+    }
+}
+
+/// Every overlap between what `a` and `b` write, and between what one reads and the other
+/// writes. Patches without conflicts can be applied in either order with the same result.
+pub fn conflicts(a: &Patch, b: &Patch) -> Vec<Conflict> {
+    let (a, b) = (a.footprint(), b.footprint());
+    let mut conflicts = vec![];
+    let mut find = |kind, firsts: &[Path], seconds: &[Path]| {
+        for first in firsts {
+            for second in seconds {
+                if first.starts_with(second) || second.starts_with(first) {
+                    conflicts.push(Conflict {
+                        kind: kind,
+                        first: first.clone(),
+                        second: second.clone(),
+                    });
+                }
+            }
+        }
+    };
+    find(ConflictKind::WriteWrite, &a.writes, &b.writes);
+    find(ConflictKind::ReadWrite, &a.reads, &b.writes);
+    find(ConflictKind::WriteRead, &a.writes, &b.reads);
+    conflicts
+}
+
+/// The value that changes when something is inserted or removed at `path`: the whole array for
+/// an element, otherwise the value itself
+fn container(path: &[String]) -> Path {
+    match path.last() {
+        Some(key) if key == "-" || key.parse::<usize>().is_ok() => path[..path.len() - 1].to_vec(),
+        _ => path.to_vec(),
+    }
+}
+
+/// `paths` without duplicates or paths under another, in order of first appearance
+fn outermost(paths: Vec<Path>) -> Vec<Path> {
+    let mut kept: Vec<Path> = vec![];
+    for path in paths {
+        if kept.iter().any(|k| path.starts_with(k)) {
+            continue;
+        }
+        kept.retain(|k| !k.starts_with(&path));
+        kept.push(path);
+    }
+    kept
+}
+
+#[test]
+fn footprints_cover_move_sources_and_whole_arrays() {
+    let p = Patch::from_str(r#"[{"op":"move","path":"/a/b","from":"/c/0"},
+                                {"op":"replace","path":"/c/1","value":1},
+                                {"op":"test","path":"/d","value":1},
+                                {"op":"copy","path":"/e","from":"/d/x"}]"#)
+                .unwrap();
+    let path = |s: &str| ::parse_pointer(s);
+    assert_eq!(p.footprint(),
+               Footprint {
+                   reads: vec![path("/c/0"), path("/d")],
+                   writes: vec![path("/c"), path("/a/b"), path("/e")],
+               });
+}
+
+#[test]
+fn conflicts_name_both_pointers() {
+    let a = Patch::from_str(r#"[{"op":"test","path":"/a/b","value":1},
+                                {"op":"add","path":"/c","value":1}]"#)
+                .unwrap();
+    let b = Patch::from_str(r#"[{"op":"replace","path":"/a","value":2},
+                                {"op":"add","path":"/d","value":1}]"#)
+                .unwrap();
+    assert_eq!(conflicts(&a, &b),
+               vec![Conflict {
+                        kind: ConflictKind::ReadWrite,
+                        first: ::parse_pointer("/a/b"),
+                        second: ::parse_pointer("/a"),
+                    }]);
+}
+
+#[test]
+fn patches_without_conflicts_commute() {
+    let mut rng = Rng(0x3c6ef372fe94f82b);
+    for _ in 0..2000 {
+        let doc = random_doc(&mut rng, 3);
+        let a = random_patch(&mut rng, &doc, 3);
+        let b = random_patch(&mut rng, &doc, 3);
+        if !conflicts(&a, &b).is_empty() {
+            continue;
+        }
+        let ab = apply(&a, &doc).and_then(|doc| apply(&b, &doc));
+        let ba = apply(&b, &doc).and_then(|doc| apply(&a, &doc));
+        assert!(ab.is_ok() && ab == ba, "{} and {} on {:?}", a, b, doc);
+    }
+}
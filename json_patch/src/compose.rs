@@ -0,0 +1,235 @@
+//! Composing patches and squashing the redundant operations out of them.
+
+use {apply, Op, Patch, Path};
+#[cfg(test)]
+use serde_json::Value;
+#[cfg(test)]
+use testing::{random_doc, random_patch, Rng};
+
+/// A patch with the same effect as `a` followed by `b`, normalized
+pub fn compose(a: &Patch, b: &Patch) -> Patch {
+    let mut ops = a.ops.clone();
+    ops.extend(b.ops.iter().cloned());
+    Patch { ops: ops }.normalize()
+}
+
+impl Patch {
+    /// An equivalent patch with redundant operations removed: moves and copies of a value onto
+    /// itself, values that are replaced or removed before anything reads them, and changes to a
+    /// value the patch added or replaced, which are made to that value instead.
+    ///
+    /// The result has the same effect on every document the patch applies to, but may apply to
+    /// some documents that the patch does not. A token that is a number may name an array element
+    /// or an object member, so an op is only dropped or merged if that is right for both.
+    pub fn normalize(&self) -> Patch {
+        let mut ops: Vec<Op> = self.ops.iter().filter(|op| !is_noop(op)).cloned().collect();
+        let mut i = 0;
+        while i < ops.len() {
+            if squash(&mut ops, i) {
+                // an earlier op may now meet a later one with nothing in between
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+        Patch { ops: ops }
+    }
+}
+
+/// What to do with `ops[i]` and the first later op that touches the value it changes
+enum Squash {
+    /// The later op overwrites it
+    DropFirst,
+    /// The two ops are equivalent to this one
+    Merge(Op),
+}
+
+/// Combine `ops[i]` with the first later op that touches the value it changes, returning whether
+/// anything changed
+fn squash(ops: &mut Vec<Op>, i: usize) -> bool {
+    let path = match ops[i] {
+        Op::Add(ref path, _) |
+        Op::Remove(ref path) |
+        Op::Replace(ref path, _) |
+        Op::Copy(ref path, _) => path.clone(),
+        Op::Move(..) | Op::Test(..) | Op::Assert(..) | Op::Extension(..) => return false,
+    };
+    let j = match (i + 1..ops.len()).find(|&j| touches(&ops[j], &path)) {
+        Some(j) => j,
+        None => return false,
+    };
+
+    let squash = match (&ops[i], &ops[j]) {
+        (&Op::Replace(..), &Op::Remove(ref removed)) if *removed == path => Squash::DropFirst,
+        (&Op::Remove(_), &Op::Add(ref added, ref value)) if *added == path => {
+            Squash::Merge(Op::Replace(path.clone(), value.clone()))
+        }
+        (&Op::Add(_, ref value), later) |
+        (&Op::Replace(_, ref value), later) if edits_within(later, &path) => {
+            let relative = Patch { ops: vec![relative_to(later, path.len())] };
+            match (apply(&relative, value), &ops[i]) {
+                (Ok(value), &Op::Add(..)) => Squash::Merge(Op::Add(path.clone(), value)),
+                (Ok(value), _) => Squash::Merge(Op::Replace(path.clone(), value)),
+                // the patch fails here anyway
+                (Err(_), _) => return false,
+            }
+        }
+        (_, later) if overwrites_above(later, &path) => Squash::DropFirst,
+        _ => return false,
+    };
+    match squash {
+        Squash::DropFirst => {
+            ops.remove(i);
+        }
+        Squash::Merge(op) => {
+            ops.remove(j);
+            ops[i] = op;
+        }
+    }
+    true
+}
+
+/// Whether `op` changes or reads the value at `path`, something in it or above it, or shifts it
+/// along an array
+fn touches(op: &Op, path: &[String]) -> bool {
+    let overlaps = |other: &Path| {
+        other.starts_with(path) || path.starts_with(other) || shifts(other, path) ||
+        shifts(path, other)
+    };
+    match *op {
+        Op::Add(ref other, _) |
+        Op::Remove(ref other) |
+        Op::Replace(ref other, _) |
+        Op::Test(ref other, _) |
+        Op::Assert(ref other, _) |
+        Op::Extension(ref other, _) => overlaps(other),
+        Op::Copy(ref other, ref from) |
+        Op::Move(ref other, ref from) => overlaps(other) || overlaps(from),
+    }
+}
+
+/// Whether `op` changes or tests the value at `path` or something in it, without inserting a
+/// second value beside it, so it can be made to the value before it is placed there
+fn edits_within(op: &Op, path: &[String]) -> bool {
+    match *op {
+        Op::Add(ref other, _) => {
+            other.starts_with(path) && (other.len() > path.len() || !is_index(path))
+        }
+        Op::Replace(ref other, _) |
+        Op::Test(ref other, _) |
+        Op::Assert(ref other, _) |
+        Op::Extension(ref other, _) => other.starts_with(path),
+        Op::Remove(ref other) => other.starts_with(path) && other.len() > path.len(),
+        Op::Copy(..) | Op::Move(..) => false,
+    }
+}
+
+/// Whether `op` removes or replaces a value that `path` is strictly under
+fn overwrites_above(op: &Op, path: &[String]) -> bool {
+    let above = |other: &Path| path.starts_with(other) && path.len() > other.len();
+    match *op {
+        Op::Remove(ref other) | Op::Replace(ref other, _) => above(other),
+        Op::Add(ref other, _) => above(other) && !is_index(other),
+        _ => false,
+    }
+}
+
+/// `op`, which `edits_within` some path of length `depth`, relative to that path
+fn relative_to(op: &Op, depth: usize) -> Op {
+    match *op {
+        Op::Add(ref path, ref value) => Op::Add(path[depth..].to_vec(), value.clone()),
+        Op::Remove(ref path) => Op::Remove(path[depth..].to_vec()),
+        Op::Replace(ref path, ref value) => Op::Replace(path[depth..].to_vec(), value.clone()),
+        Op::Test(ref path, ref value) => Op::Test(path[depth..].to_vec(), value.clone()),
+        Op::Assert(ref path, ref predicate) => {
+            Op::Assert(path[depth..].to_vec(), predicate.clone())
+        }
+        Op::Extension(ref path, ref extension) => {
+            Op::Extension(path[depth..].to_vec(), extension.clone())
+        }
+        Op::Copy(..) | Op::Move(..) => unreachable!(),
+    }
+}
+
+fn is_noop(op: &Op) -> bool {
+    match *op {
+        Op::Move(ref path, ref from) => path == from,
+        Op::Copy(ref path, ref from) => path == from && !is_index(path),
+        _ => false,
+    }
+}
+
+/// Whether the last token of `path` may address an array element
+fn is_index(path: &[String]) -> bool {
+    path.last().map_or(false, |key| key == "-" || key.parse::<usize>().is_ok())
+}
+
+/// Whether inserting or removing an element at `at` could change what `path` addresses
+fn shifts(at: &[String], path: &[String]) -> bool {
+    is_index(at) && path.len() >= at.len() && path[..at.len() - 1] == at[..at.len() - 1]
+}
+
+#[cfg(test)]
+fn patch(s: &str) -> Patch {
+    Patch::from_str(s).unwrap()
+}
+
+#[test]
+fn repeated_replaces_leave_the_last() {
+    let p = patch(r#"[{"op":"replace","path":"/a","value":1},
+                      {"op":"replace","path":"/b","value":2},
+                      {"op":"replace","path":"/a","value":3}]"#);
+    assert_eq!(p.normalize(),
+               patch(r#"[{"op":"replace","path":"/a","value":3},
+                         {"op":"replace","path":"/b","value":2}]"#));
+}
+
+#[test]
+fn elements_inserted_into_an_added_array_and_removed_vanish() {
+    let p = patch(r#"[{"op":"add","path":"/a","value":[0]},
+                      {"op":"add","path":"/a/1","value":1},
+                      {"op":"move","path":"/b","from":"/b"},
+                      {"op":"remove","path":"/a/1"}]"#);
+    assert_eq!(p.normalize(), patch(r#"[{"op":"add","path":"/a","value":[0]}]"#));
+}
+
+#[test]
+fn numeric_tokens_may_name_object_members() {
+    let p = patch(r#"[{"op":"add","path":"/o/1","value":5},{"op":"remove","path":"/o/1"}]"#);
+    let doc: Value = ::serde_json::from_str(r#"{"o":{"1":0}}"#).unwrap();
+    assert_eq!(apply(&p.normalize(), &doc), apply(&p, &doc));
+}
+
+#[test]
+fn changes_to_an_added_value_are_folded_into_it() {
+    let a = patch(r#"[{"op":"add","path":"/a","value":{"b":[1]}}]"#);
+    let b = patch(r#"[{"op":"add","path":"/a/b/0","value":0},
+                      {"op":"test","path":"/a/b","value":[0,1]},
+                      {"op":"remove","path":"/a/b/1"}]"#);
+    assert_eq!(compose(&a, &b), patch(r#"[{"op":"add","path":"/a","value":{"b":[0]}}]"#));
+}
+
+#[test]
+fn normalized_patches_have_the_same_effect() {
+    let mut rng = Rng(0x6a09e667f3bcc908);
+    for _ in 0..2000 {
+        let doc = random_doc(&mut rng, 3);
+        let p = random_patch(&mut rng, &doc, 8);
+        let normalized = p.normalize();
+        assert!(normalized.ops.len() <= p.ops.len());
+        assert_eq!(apply(&normalized, &doc), apply(&p, &doc), "{} became {}", p, normalized);
+    }
+}
+
+#[test]
+fn composed_patches_have_the_same_effect() {
+    let mut rng = Rng(0xbb67ae8584caa73b);
+    for _ in 0..2000 {
+        let doc = random_doc(&mut rng, 3);
+        let a = random_patch(&mut rng, &doc, 4);
+        let middle = apply(&a, &doc).unwrap();
+        let b = random_patch(&mut rng, &middle, 4);
+        let composed = compose(&a, &b);
+        assert_eq!(apply(&composed, &doc), apply(&b, &middle), "{} then {}", a, b);
+    }
+}
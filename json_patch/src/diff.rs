@@ -0,0 +1,103 @@
+//! Finding a patch that turns one document into another.
+
+use std::cmp::min;
+
+use serde_json::Value;
+
+use {Op, Patch, Path};
+#[cfg(test)]
+use apply;
+#[cfg(test)]
+use testing::{random_doc, Rng};
+
+/// A patch that turns `from` into `to`, replacing only the values that differ.
+///
+/// Objects are compared key by key. Arrays are compared by index after setting aside the
+/// elements they share at either end, so a single insertion or removal becomes a single op.
+pub fn diff(from: &Value, to: &Value) -> Patch {
+    let mut ops = vec![];
+    diff_into(&mut vec![], from, to, &mut ops);
+    Patch { ops: ops }
+}
+
+fn diff_into(path: &mut Path, from: &Value, to: &Value, ops: &mut Vec<Op>) {
+    if from == to {
+        return;
+    }
+    match (from, to) {
+        (&Value::Object(ref a), &Value::Object(ref b)) => {
+            for (key, value) in a {
+                path.push(key.clone());
+                match b.get(key) {
+                    Some(other) => diff_into(path, value, other, ops),
+                    None => ops.push(Op::Remove(path.clone())),
+                }
+                path.pop();
+            }
+            for (key, value) in b {
+                if !a.contains_key(key) {
+                    ops.push(Op::Add(child(path, key), value.clone()));
+                }
+            }
+        }
+        (&Value::Array(ref a), &Value::Array(ref b)) => {
+            let start = a.iter().zip(b).take_while(|&(x, y)| x == y).count();
+            let end = a[start..]
+                          .iter()
+                          .rev()
+                          .zip(b[start..].iter().rev())
+                          .take_while(|&(x, y)| x == y)
+                          .count();
+            let (a, b) = (&a[start..a.len() - end], &b[start..b.len() - end]);
+            let paired = min(a.len(), b.len());
+            for i in 0..paired {
+                path.push((start + i).to_string());
+                diff_into(path, &a[i], &b[i], ops);
+                path.pop();
+            }
+            // from the back, so the indices of the rest stay put
+            for i in (paired..a.len()).rev() {
+                ops.push(Op::Remove(child(path, &(start + i).to_string())));
+            }
+            for i in paired..b.len() {
+                ops.push(Op::Add(child(path, &(start + i).to_string()), b[i].clone()));
+            }
+        }
+        _ => ops.push(Op::Replace(path.clone(), to.clone())),
+    }
+}
+
+fn child(path: &Path, key: &str) -> Path {
+    let mut path = path.clone();
+    path.push(key.to_string());
+    path
+}
+
+#[cfg(test)]
+fn parse(s: &str) -> Value {
+    ::serde_json::from_str(s).unwrap()
+}
+
+#[test]
+fn only_differences_are_patched() {
+    let from = parse(r#"{"a":1,"b":{"c":[1,2,3],"d":true},"e":null}"#);
+    let to = parse(r#"{"a":1,"b":{"c":[0,1,2,3],"d":false},"f":"x"}"#);
+    assert_eq!(diff(&from, &to),
+               Patch::from_str(r#"[{"op":"add","path":"/b/c/0","value":0},
+                                   {"op":"replace","path":"/b/d","value":false},
+                                   {"op":"remove","path":"/e"},
+                                   {"op":"add","path":"/f","value":"x"}]"#)
+                   .unwrap());
+    assert_eq!(diff(&from, &from), Patch { ops: vec![] });
+}
+
+#[test]
+fn diffs_turn_one_document_into_the_other() {
+    let mut rng = Rng(0x510e527fade682d1);
+    for _ in 0..2000 {
+        let from = random_doc(&mut rng, 3);
+        let to = random_doc(&mut rng, 3);
+        let patch = diff(&from, &to);
+        assert_eq!(apply(&patch, &from), Ok(to.clone()), "{} on {:?}", patch, from);
+    }
+}
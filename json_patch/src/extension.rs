@@ -0,0 +1,234 @@
+//! Operations beyond RFC 6902 for counters, text and sets, only parsed when asked for.
+
+use serde_json::Value;
+
+use {move_value, require_key, InvalidOpError, PatchError};
+
+/// A change made in place to the value at the path of an `Op::Extension`
+#[derive(Clone, PartialEq, Debug)]
+pub enum Extension {
+    /// `inc`: add a number, which may be negative, to a number
+    Inc(Value),
+    /// `str-ins`: insert text into a string before the character at `pos`
+    StrIns(usize, String),
+    /// `str-del`: delete `len` characters from a string starting at the one at `pos`
+    StrDel(usize, usize),
+    /// `add-unique`: append a value to an array unless the array already holds an equal one
+    AddUnique(Value),
+    /// `min`: lower a number to this one if it is greater
+    Min(Value),
+    /// `max`: raise a number to this one if it is less
+    Max(Value),
+}
+
+impl Extension {
+    /// The `op` of this extension in a patch
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Extension::Inc(_) => "inc",
+            Extension::StrIns(..) => "str-ins",
+            Extension::StrDel(..) => "str-del",
+            Extension::AddUnique(_) => "add-unique",
+            Extension::Min(_) => "min",
+            Extension::Max(_) => "max",
+        }
+    }
+
+    /// The extension op called `name`, with its arguments taken from the op object `v`, or
+    /// `None` if there is no such extension
+    pub fn from_value(name: &str, v: Value) -> Result<Option<Extension>, InvalidOpError> {
+        Ok(Some(match name {
+            "inc" => Extension::Inc(try!(number(v))),
+            "str-ins" => {
+                let pos = try!(count(&v, "pos"));
+                match try!(move_value(v)) {
+                    Value::String(text) => Extension::StrIns(pos, text),
+                    _ => return Err(InvalidOpError::MustBeString("value".to_string())),
+                }
+            }
+            "str-del" => Extension::StrDel(try!(count(&v, "pos")), try!(count(&v, "len"))),
+            "add-unique" => Extension::AddUnique(try!(move_value(v))),
+            "min" => Extension::Min(try!(number(v))),
+            "max" => Extension::Max(try!(number(v))),
+            _ => return Ok(None),
+        }))
+    }
+
+    /// Change `target`, failing if it is not the kind of value the extension works on
+    pub fn apply(&self, target: &mut Value) -> Result<(), PatchError> {
+        match *self {
+            Extension::Inc(ref n) => {
+                *target = try!(sum(target, n).ok_or(PatchError));
+                Ok(())
+            }
+            Extension::Min(ref n) | Extension::Max(ref n) => {
+                let (current, bound) = match (target.as_f64(), n.as_f64()) {
+                    (Some(current), Some(bound)) => (current, bound),
+                    _ => return Err(PatchError),
+                };
+                let replace = match *self {
+                    Extension::Min(_) => bound < current,
+                    _ => bound > current,
+                };
+                if replace {
+                    *target = n.clone();
+                }
+                Ok(())
+            }
+            Extension::StrIns(pos, ref text) => {
+                match *target {
+                    Value::String(ref mut s) => {
+                        let at = try!(char_offset(s, pos));
+                        let rest = s.split_off(at);
+                        s.push_str(text);
+                        s.push_str(&rest);
+                        Ok(())
+                    }
+                    _ => Err(PatchError),
+                }
+            }
+            Extension::StrDel(pos, len) => {
+                match *target {
+                    Value::String(ref mut s) => {
+                        let end = try!(pos.checked_add(len).ok_or(PatchError));
+                        let (start, end) = (try!(char_offset(s, pos)), try!(char_offset(s, end)));
+                        let rest = s.split_off(end);
+                        s.truncate(start);
+                        s.push_str(&rest);
+                        Ok(())
+                    }
+                    _ => Err(PatchError),
+                }
+            }
+            Extension::AddUnique(ref value) => {
+                match *target {
+                    Value::Array(ref mut a) => {
+                        if !a.contains(value) {
+                            a.push(value.clone());
+                        }
+                        Ok(())
+                    }
+                    _ => Err(PatchError),
+                }
+            }
+        }
+    }
+}
+
+/// The `value` of an op, which must be a number
+fn number(v: Value) -> Result<Value, InvalidOpError> {
+    let n = try!(move_value(v));
+    if n.is_number() {
+        Ok(n)
+    } else {
+        Err(InvalidOpError::MustBeNumber("value".to_string()))
+    }
+}
+
+/// The property `k` of an op, which must be a non-negative integer
+fn count(v: &Value, k: &str) -> Result<usize, InvalidOpError> {
+    match try!(require_key(v, k)).as_u64() {
+        Some(n) => Ok(n as usize),
+        None => Err(InvalidOpError::MustBeNumber(k.to_string())),
+    }
+}
+
+/// `a + b`, kept an integer when both are and the sum fits in one
+fn sum(a: &Value, b: &Value) -> Option<Value> {
+    if !a.is_number() || !b.is_number() {
+        return None;
+    }
+    if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+        if let Some(sum) = a.checked_add(b) {
+            return Some(Value::U64(sum));
+        }
+    }
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        if let Some(sum) = a.checked_add(b) {
+            // as parsing would give it
+            return Some(if sum >= 0 {
+                Value::U64(sum as u64)
+            } else {
+                Value::I64(sum)
+            });
+        }
+    }
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => Some(Value::F64(a + b)),
+        _ => None,
+    }
+}
+
+/// The byte offset of the character at `pos` in `s`, which may be just past the end
+fn char_offset(s: &str, pos: usize) -> Result<usize, PatchError> {
+    s.char_indices().map(|(i, _)| i).chain(Some(s.len())).nth(pos).ok_or(PatchError)
+}
+
+#[cfg(test)]
+fn apply_extended(doc: &str, patch: &str) -> Result<Value, PatchError> {
+    use serde_json;
+    use {apply, ParseOptions, Patch};
+    let options = ParseOptions { extensions: true, ..ParseOptions::default() };
+    let patch = Patch::from_str_with(patch, options).unwrap();
+    apply(&patch, &serde_json::from_str(doc).unwrap())
+}
+
+#[test]
+fn extensions_are_only_parsed_when_enabled() {
+    use {InvalidPatchError, Patch};
+    match Patch::from_str(r#"[{"op":"inc","path":"/n","value":1}]"#) {
+        Err(InvalidPatchError::BadOp(0, InvalidOpError::UnknownOp(ref op))) if op == "inc" => (),
+        other => panic!("parsed {:?}", other),
+    }
+}
+
+#[test]
+fn numbers_are_incremented_and_bounded() {
+    let doc = apply_extended(r#"{"n":1,"m":5,"x":1.5}"#,
+                             r#"[{"op":"inc","path":"/n","value":-3},
+                                 {"op":"inc","path":"/n","value":4},
+                                 {"op":"min","path":"/m","value":2},
+                                 {"op":"max","path":"/m","value":1},
+                                 {"op":"inc","path":"/x","value":1}]"#);
+    assert_eq!(doc, apply_extended("{}", r#"[{"op":"add","path":"/n","value":2},
+                                            {"op":"add","path":"/m","value":2},
+                                            {"op":"add","path":"/x","value":2.5}]"#));
+    assert_eq!(apply_extended(r#"{"s":"1"}"#, r#"[{"op":"inc","path":"/s","value":1}]"#),
+               Err(PatchError));
+}
+
+#[test]
+fn strings_are_spliced_by_character() {
+    let doc = apply_extended(r#"{"s":"héllo"}"#,
+                             r#"[{"op":"str-del","path":"/s","pos":1,"len":3},
+                                 {"op":"str-ins","path":"/s","pos":1,"value":"ipp"},
+                                 {"op":"str-ins","path":"/s","pos":5,"value":"!"}]"#);
+    assert_eq!(doc, apply_extended("{}", r#"[{"op":"add","path":"/s","value":"hippo!"}]"#));
+    assert_eq!(apply_extended(r#"{"s":"ab"}"#,
+                              r#"[{"op":"str-del","path":"/s","pos":1,"len":2}]"#),
+               Err(PatchError));
+}
+
+#[test]
+fn unique_elements_are_added_once() {
+    let doc = apply_extended(r#"{"a":[1]}"#,
+                             r#"[{"op":"add-unique","path":"/a","value":2},
+                                 {"op":"add-unique","path":"/a","value":1},
+                                 {"op":"add-unique","path":"/a","value":2}]"#);
+    assert_eq!(doc, apply_extended("{}", r#"[{"op":"add","path":"/a","value":[1,2]}]"#));
+}
+
+#[test]
+fn extensions_round_trip_through_display() {
+    use {ParseOptions, Patch};
+    let options = ParseOptions { extensions: true, ..ParseOptions::default() };
+    let patch = Patch::from_str_with(r#"[{"op":"inc","path":"/n","value":-1},
+                                          {"op":"str-ins","path":"/s","pos":0,"value":"a\"b"},
+                                          {"op":"str-del","path":"/s","pos":2,"len":1},
+                                          {"op":"add-unique","path":"/a","value":{"k":[1]}},
+                                          {"op":"min","path":"/n","value":0.5},
+                                          {"op":"max","path":"/n","value":7}]"#,
+                                     options)
+                    .unwrap();
+    assert_eq!(Patch::from_str_with(&patch.to_string(), options).unwrap(), patch);
+}
@@ -1,14 +1,40 @@
 #![feature(slice_splits)]
+extern crate regex;
+extern crate serde;
 extern crate serde_json;
 
+mod analysis;
+mod compose;
+mod diff;
+mod extension;
+mod merge;
+mod ot;
 mod patch;
+mod pointer;
+mod predicate;
+mod relocate;
+#[cfg(test)]
+mod testing;
+mod typed;
 
 use serde_json::Value;
 use std::error::Error;
 use std::fmt;
 
-pub use patch::{apply, PatchError};
+pub use analysis::{conflicts, Conflict, ConflictKind, Footprint};
+pub use compose::compose;
+pub use diff::diff;
+pub use extension::Extension;
+pub use merge::{merge3, MergeConflict, MergeResult};
+pub use ot::{transform, TransformError};
+pub use patch::{apply, apply_with, check, expand_parents, find_path, resolve_appends, ApplyOptions,
+                PatchError};
+pub use pointer::{PatchBuilder, Pointee, Pointer, TypedPointer};
+pub use predicate::{JsonType, Pattern, Predicate, TestFailure};
+pub use relocate::OutOfScope;
+pub use typed::{apply_typed, diff_typed, TypedPatchError};
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct Patch {
     pub ops: Vec<Op>,
 }
@@ -21,10 +47,24 @@ pub enum Op {
     Copy(Path, Path),
     Move(Path, Path),
     Test(Path, Value),
+    /// One of the extension ops, which are only parsed when `ParseOptions` enable them
+    Extension(Path, Extension),
+    /// A test beyond equality, which is only parsed when `ParseOptions` enable predicates
+    Assert(Path, Predicate),
 }
 
 pub type Path = Vec<String>;
 
+/// Settings for `Patch::from_value_with`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ParseOptions {
+    /// Accept the extension ops, such as `inc` and `str-ins`, as well as those of RFC 6902
+    pub extensions: bool,
+    /// Accept the predicate ops, such as `test-type` and `test-range`, which only read the
+    /// document
+    pub predicates: bool,
+}
+
 #[derive(Debug)]
 pub enum InvalidPatchError {
     JsonError(serde_json::Error),
@@ -37,6 +77,9 @@ pub enum InvalidOpError {
     UnknownOp(String),
     MissingProperty(String),
     MustBeString(String),
+    MustBeNumber(String),
+    /// The property has a value of the right type that is not allowed, such as an unknown type
+    InvalidValue(String),
 }
 
 impl From<serde_json::Error> for InvalidPatchError {
@@ -50,12 +93,20 @@ impl Patch {
         Patch::from_value(try!(serde_json::from_str(s)))
     }
 
+    pub fn from_str_with(s: &str, options: ParseOptions) -> Result<Patch, InvalidPatchError> {
+        Patch::from_value_with(try!(serde_json::from_str(s)), options)
+    }
+
     pub fn from_value(v: Value) -> Result<Patch, InvalidPatchError> {
+        Patch::from_value_with(v, ParseOptions::default())
+    }
+
+    pub fn from_value_with(v: Value, options: ParseOptions) -> Result<Patch, InvalidPatchError> {
         match v {
             Value::Array(values) => {
                 let mut ops: Vec<Op> = Vec::with_capacity(values.len());
                 for (i, value) in values.into_iter().enumerate() {
-                    match Op::from_value(value) {
+                    match Op::from_value_with(value, options) {
                         Ok(op) => ops.push(op),
                         Err(e) => return Err(InvalidPatchError::BadOp(i, e)),
                     }
@@ -69,6 +120,10 @@ impl Patch {
 
 impl Op {
     pub fn from_value(v: Value) -> Result<Op, InvalidOpError> {
+        Op::from_value_with(v, ParseOptions::default())
+    }
+
+    pub fn from_value_with(v: Value, options: ParseOptions) -> Result<Op, InvalidOpError> {
         let (op, path, from) = {
             let op = try!(require_key_as_string(&v, "op")).to_string();
             let path = try!(require_key_as_path(&v, "path"));
@@ -82,14 +137,33 @@ impl Op {
             "test" => Op::Test(path, try!(move_value(v))),
             "copy" => Op::Copy(path, try!(from)),
             "move" => Op::Move(path, try!(from)),
-            _ => return Err(InvalidOpError::UnknownOp(op)),
+            _ => {
+                if options.predicates {
+                    if let Some(predicate) = try!(Predicate::from_value(&op, &v)) {
+                        return Ok(Op::Assert(path, predicate));
+                    }
+                }
+                if options.extensions {
+                    if let Some(extension) = try!(Extension::from_value(&op, v)) {
+                        return Ok(Op::Extension(path, extension));
+                    }
+                }
+                return Err(InvalidOpError::UnknownOp(op));
+            }
         })
     }
 }
 
 impl fmt::Display for Patch {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self.ops)
+        try!(write!(f, "["));
+        for (i, op) in self.ops.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, ","));
+            }
+            try!(write!(f, "{}", op));
+        }
+        write!(f, "]")
     }
 }
 
@@ -99,33 +173,111 @@ impl fmt::Display for Op {
             &Op::Add(ref path, ref value) => {
                 write!(f,
                        r#"{{"op":"add","path":{:?},"value":{:?}}}"#,
-                       path,
+                       pointer_value(path),
                        value)
             }
             &Op::Remove(ref path) => {
-                write!(f, r#"{{"op":"remove","path":{:?}}}"#, path)
+                write!(f, r#"{{"op":"remove","path":{:?}}}"#, pointer_value(path))
             }
             &Op::Replace(ref path, ref v) => {
                 write!(f,
                        r#"{{"op":"replace","path":{:?},"value":{:?}}}"#,
-                       path,
+                       pointer_value(path),
                        v)
             }
-            &Op::Copy(ref path, ref to) => {
-                write!(f, r#"{{"op":"copy","path":{:?},"to":{:?}}}"#, path, to)
+            &Op::Copy(ref path, ref from) => {
+                write!(f,
+                       r#"{{"op":"copy","path":{:?},"from":{:?}}}"#,
+                       pointer_value(path),
+                       pointer_value(from))
             }
-            &Op::Move(ref path, ref to) => {
-                write!(f, r#"{{"op":"move","path":{:?},"to":{:?}}}"#, path, to)
+            &Op::Move(ref path, ref from) => {
+                write!(f,
+                       r#"{{"op":"move","path":{:?},"from":{:?}}}"#,
+                       pointer_value(path),
+                       pointer_value(from))
             }
             &Op::Test(ref path, ref v) => {
                 write!(f,
-                       r#"{{"op":"move","path":{:?},"value":{:?}}}"#,
-                       path,
+                       r#"{{"op":"test","path":{:?},"value":{:?}}}"#,
+                       pointer_value(path),
                        v)
             }
+            &Op::Extension(ref path, ref extension) => {
+                try!(write!(f,
+                            r#"{{"op":"{}","path":{:?}"#,
+                            extension.name(),
+                            pointer_value(path)));
+                match *extension {
+                    Extension::Inc(ref v) |
+                    Extension::AddUnique(ref v) |
+                    Extension::Min(ref v) |
+                    Extension::Max(ref v) => try!(write!(f, r#","value":{:?}"#, v)),
+                    Extension::StrIns(pos, ref text) => {
+                        try!(write!(f,
+                                    r#","pos":{},"value":{:?}"#,
+                                    pos,
+                                    Value::String(text.clone())))
+                    }
+                    Extension::StrDel(pos, len) => {
+                        try!(write!(f, r#","pos":{},"len":{}"#, pos, len))
+                    }
+                }
+                write!(f, "}}")
+            }
+            &Op::Assert(ref path, ref predicate) => {
+                try!(write!(f,
+                            r#"{{"op":"{}","path":{:?}"#,
+                            predicate.name(),
+                            pointer_value(path)));
+                let bound = |f: &mut fmt::Formatter, k: &str, n: Option<f64>| {
+                    match n {
+                        Some(n) => write!(f, r#","{}":{}"#, k, n),
+                        None => Ok(()),
+                    }
+                };
+                match *predicate {
+                    Predicate::Exists => (),
+                    Predicate::Equals(ref v) | Predicate::Not(ref v) => {
+                        try!(write!(f, r#","value":{:?}"#, v))
+                    }
+                    Predicate::Type(t) => try!(write!(f, r#","type":"{}""#, t.name())),
+                    Predicate::Range(min, max) => {
+                        try!(bound(f, "min", min));
+                        try!(bound(f, "max", max));
+                    }
+                    Predicate::Regex(ref pattern) => {
+                        try!(write!(f,
+                                    r#","pattern":{:?}"#,
+                                    Value::String(pattern.0.as_str().to_string())))
+                    }
+                    Predicate::Length(min, max) => {
+                        try!(bound(f, "min", min.map(|n| n as f64)));
+                        try!(bound(f, "max", max.map(|n| n as f64)));
+                    }
+                }
+                write!(f, "}}")
+            }
         }
     }
 }
+
+/// Parse a JSON pointer (RFC 6901) like `/a/b~1c` into its unescaped tokens
+pub fn parse_pointer(s: &str) -> Path {
+    s.split("/").skip(1).map(|token| token.replace("~1", "/").replace("~0", "~")).collect()
+}
+
+/// The JSON pointer string for `path`, escaping `~` and `/` in its tokens
+pub fn format_pointer(path: &[String]) -> String {
+    path.iter()
+        .map(|token| format!("/{}", token.replace("~", "~0").replace("/", "~1")))
+        .collect()
+}
+
+fn pointer_value(path: &[String]) -> Value {
+    Value::String(format_pointer(path))
+}
+
 fn move_value(mut v: Value) -> Result<Value, InvalidOpError> {
     let mut o = v.as_object_mut().unwrap(); // don't panic, we only get here if the thing was already an object
     match o.remove("value") {
@@ -152,5 +304,5 @@ fn require_key_as_string<'a>(v: &'a Value, k: &str) -> Result<&'a str, InvalidOp
 }
 
 fn require_key_as_path<'a>(v: &'a Value, k: &str) -> Result<Path, InvalidOpError> {
-    require_key_as_string(v, k).map(|s| s.split("/").skip(1).map(|s| s.to_string()).collect())
+    require_key_as_string(v, k).map(parse_pointer)
 }
@@ -0,0 +1,146 @@
+//! Three-way merges of documents that diverged from a common base.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::Value;
+
+use Path;
+#[cfg(test)]
+use apply;
+#[cfg(test)]
+use testing::{random_doc, random_patch, Rng};
+
+/// The outcome of `merge3`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeResult {
+    /// The merged document, holding our side wherever the two sides conflict
+    pub value: Value,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+/// A value that both sides changed, each differently. `None` is a value that is missing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeConflict {
+    pub path: Path,
+    pub base: Option<Value>,
+    pub ours: Option<Value>,
+    pub theirs: Option<Value>,
+}
+
+/// Merge the changes that `ours` and `theirs` each made to `base`.
+///
+/// Objects are merged key by key. Arrays are merged element by element if neither side changed
+/// their length; otherwise an array that both sides changed conflicts as a whole.
+pub fn merge3(base: &Value, ours: &Value, theirs: &Value) -> MergeResult {
+    let mut conflicts = vec![];
+    let value = merge(&mut vec![], Some(base), Some(ours), Some(theirs), &mut conflicts);
+    MergeResult {
+        // our side is always kept when it is present
+        value: value.unwrap(),
+        conflicts: conflicts,
+    }
+}
+
+fn merge(path: &mut Path,
+         base: Option<&Value>,
+         ours: Option<&Value>,
+         theirs: Option<&Value>,
+         conflicts: &mut Vec<MergeConflict>)
+         -> Option<Value> {
+    if ours == theirs || base == theirs {
+        return ours.cloned();
+    }
+    if base == ours {
+        return theirs.cloned();
+    }
+    // keys both sides added to a value that was missing are merged too
+    let base_is_object = base.map_or(true, |b| b.is_object());
+    match (base, ours, theirs) {
+        (_, Some(&Value::Object(ref o)), Some(&Value::Object(ref t))) if base_is_object => {
+            let empty = BTreeMap::new();
+            let b = base.and_then(|b| b.as_object()).unwrap_or(&empty);
+            let keys: BTreeSet<&String> = b.keys().chain(o.keys()).chain(t.keys()).collect();
+            let mut merged = BTreeMap::new();
+            for key in keys {
+                path.push(key.clone());
+                if let Some(value) = merge(path, b.get(key), o.get(key), t.get(key), conflicts) {
+                    merged.insert(key.clone(), value);
+                }
+                path.pop();
+            }
+            return Some(Value::Object(merged));
+        }
+        (Some(&Value::Array(ref b)), Some(&Value::Array(ref o)), Some(&Value::Array(ref t)))
+            if b.len() == o.len() && o.len() == t.len() => {
+            let mut merged = Vec::with_capacity(b.len());
+            for i in 0..b.len() {
+                path.push(i.to_string());
+                merged.extend(merge(path, Some(&b[i]), Some(&o[i]), Some(&t[i]), conflicts));
+                path.pop();
+            }
+            return Some(Value::Array(merged));
+        }
+        _ => (),
+    }
+    conflicts.push(MergeConflict {
+        path: path.clone(),
+        base: base.cloned(),
+        ours: ours.cloned(),
+        theirs: theirs.cloned(),
+    });
+    ours.cloned()
+}
+
+#[cfg(test)]
+fn parse(s: &str) -> Value {
+    ::serde_json::from_str(s).unwrap()
+}
+
+#[test]
+fn separate_changes_are_combined() {
+    let base = parse(r#"{"a":1,"b":[1,2],"c":{"x":1}}"#);
+    let ours = parse(r#"{"a":2,"b":[1,3],"c":{"x":1,"y":2}}"#);
+    let theirs = parse(r#"{"a":1,"b":[0,2],"c":{}}"#);
+    assert_eq!(merge3(&base, &ours, &theirs),
+               MergeResult {
+                   value: parse(r#"{"a":2,"b":[0,3],"c":{"y":2}}"#),
+                   conflicts: vec![],
+               });
+}
+
+#[test]
+fn conflicts_are_found_where_both_sides_differ() {
+    let base = parse(r#"{"a":1,"b":{"c":1},"d":[1]}"#);
+    let ours = parse(r#"{"a":2,"b":{"c":2},"d":[1,2]}"#);
+    let theirs = parse(r#"{"a":3,"d":[]}"#);
+    let result = merge3(&base, &ours, &theirs);
+    assert_eq!(result.value, ours);
+    let paths: Vec<String> = result.conflicts.iter().map(|c| ::format_pointer(&c.path)).collect();
+    assert_eq!(paths, vec!["/a", "/b", "/d"]);
+    assert_eq!(result.conflicts[1],
+               MergeConflict {
+                   path: ::parse_pointer("/b"),
+                   base: Some(parse(r#"{"c":1}"#)),
+                   ours: Some(parse(r#"{"c":2}"#)),
+                   theirs: None,
+               });
+}
+
+#[test]
+fn merges_are_symmetric() {
+    let mut rng = Rng(0x9b05688c2b3e6c1f);
+    for _ in 0..2000 {
+        let base = random_doc(&mut rng, 3);
+        let ours = apply(&random_patch(&mut rng, &base, 3), &base).unwrap();
+        let theirs = apply(&random_patch(&mut rng, &base, 3), &base).unwrap();
+        assert_eq!(merge3(&base, &ours, &base).value, ours);
+        let (a, b) = (merge3(&base, &ours, &theirs), merge3(&base, &theirs, &ours));
+        let paths = |r: &MergeResult| -> Vec<Path> {
+            r.conflicts.iter().map(|c| c.path.clone()).collect()
+        };
+        assert_eq!(paths(&a), paths(&b));
+        if a.conflicts.is_empty() {
+            assert_eq!(a.value, b.value, "{:?} then {:?} and {:?}", base, ours, theirs);
+        }
+    }
+}
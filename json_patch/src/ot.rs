@@ -0,0 +1,991 @@
+//! Operational transformation of patches made concurrently against the same document.
+
+use std::cmp;
+
+use serde_json::Value;
+
+use {Extension, Op, Patch, Path};
+use patch::appends;
+
+/// Why `transform` refuses to rebase two patches over each other
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransformError {
+    /// Both patches append to arrays with `-`, which may be the same array; `resolve_appends`
+    /// replaces `-` with the index it stands for
+    BothAppend,
+    /// An operation of one patch copies or moves a value, and one of the other copies or moves a
+    /// value at, above or below either of its paths, or changes a value at, above or below its
+    /// source. Which value ends up where then depends on the document.
+    Entangled,
+    /// An extension op of one patch and an operation of the other change the same value in ways
+    /// that do not commute, such as `min` and `max`, `inc` and `str-ins`, or `add-unique` and an
+    /// operation on an element of its array.
+    NonCommuting,
+}
+
+/// Rebase two patches made against the same document over each other.
+///
+/// Returns `(a2, b2)`, where `a2` is `a` rebased to apply after `b` and `b2` is `b` rebased to
+/// apply after `a`, so that applying `a` then `b2` gives the same document as `b` then `a2`.
+/// Array indices are shifted past the insertions and removals the other patch made before them,
+/// operations on a value the other patch moved follow it, and operations on a value the other
+/// patch removed or replaced are dropped. Where both patches set the same value or insert at the
+/// same index, `a` wins. Extension ops are kept unless the other patch removes or replaces the
+/// value they change. Text inserted and deleted with `str-ins` and `str-del` is rebased like array
+/// elements; other extension ops that change the same value must commute, as increments do.
+///
+/// The document itself is not consulted, so tokens that are numbers are taken to be array indices
+/// and `-` is taken to be past any index of the other patch. That fails if both patches append
+/// with `-`, and so does rebasing patches that are entangled or do not commute, as
+/// `TransformError` describes; they are refused rather than rebased into patches that do not
+/// converge.
+pub fn transform(a: &Patch, b: &Patch) -> Result<(Patch, Patch), TransformError> {
+    if a.ops.iter().any(appends) && b.ops.iter().any(appends) {
+        return Err(TransformError::BothAppend);
+    }
+    let (a2, b2) = try!(transform_ops(&a.ops, &b.ops, true));
+    Ok((Patch { ops: a2 }, Patch { ops: b2 }))
+}
+
+/// Rebase two sequences of operations over each other, one operation at a time. If `strict`,
+/// a pair of operations that are entangled is refused.
+fn transform_ops(a: &[Op],
+                 b: &[Op],
+                 strict: bool)
+                 -> Result<(Vec<Op>, Vec<Op>), TransformError> {
+    if a.is_empty() || b.is_empty() {
+        return Ok((a.to_vec(), b.to_vec()));
+    }
+    if a.len() > 1 {
+        let (mut a2, b1) = try!(transform_ops(&a[..1], b, strict));
+        let (rest, b2) = try!(transform_ops(&a[1..], &b1, strict));
+        a2.extend(rest);
+        return Ok((a2, b2));
+    }
+    if b.len() > 1 {
+        let (a1, mut b2) = try!(transform_ops(a, &b[..1], strict));
+        let (a2, rest) = try!(transform_ops(&a1, &b[1..], strict));
+        b2.extend(rest);
+        return Ok((a2, b2));
+    }
+    if strict && (entangled(&a[0], &b[0]) || entangled(&b[0], &a[0])) {
+        return Err(TransformError::Entangled);
+    }
+    if strict && (conflicts(&a[0], &b[0]) || conflicts(&b[0], &a[0])) {
+        return Err(TransformError::NonCommuting);
+    }
+    Ok((transform_op(&a[0], &b[0], true), transform_op(&b[0], &a[0], false)))
+}
+
+/// Whether `x` copies or moves a value and `y` copies or moves one at, above or below either of
+/// its paths, or changes one at, above or below its source
+fn entangled(x: &Op, y: &Op) -> bool {
+    let read = match *x {
+        Op::Copy(_, ref from) | Op::Move(_, ref from) => from,
+        _ => return false,
+    };
+    let checked = match *y {
+        Op::Copy(..) | Op::Move(..) => locations(x),
+        _ => vec![read.clone()],
+    };
+    let overlaps = |a: &Path, b: &Path| a.starts_with(b) || b.starts_with(a);
+    checked.iter().any(|loc| locations(y).iter().any(|other| overlaps(loc, other)))
+}
+
+/// Whether `x` is an extension op and `y` changes the value it changes, or an element of the array
+/// `add-unique` appends to, in a way that gives another value depending on which goes first
+fn conflicts(x: &Op, y: &Op) -> bool {
+    let (path, extension) = match *x {
+        Op::Extension(ref path, ref extension) => (path, extension),
+        _ => return false,
+    };
+    if let Op::Extension(ref other, ref with) = *y {
+        if other == path {
+            return match (extension, with) {
+                (&Extension::Inc(_), &Extension::Inc(_)) |
+                (&Extension::Min(_), &Extension::Min(_)) |
+                (&Extension::Max(_), &Extension::Max(_)) |
+                (&Extension::StrIns(..), &Extension::StrIns(..)) |
+                (&Extension::StrIns(..), &Extension::StrDel(..)) |
+                (&Extension::StrDel(..), &Extension::StrIns(..)) |
+                (&Extension::StrDel(..), &Extension::StrDel(..)) => false,
+                // appended in the other order unless they are the same value
+                (&Extension::AddUnique(ref a), &Extension::AddUnique(ref b)) => a != b,
+                _ => true,
+            };
+        }
+    }
+    let changed = match *y {
+        Op::Test(..) | Op::Assert(..) => vec![],
+        Op::Copy(ref to, _) => vec![to.clone()],
+        _ => locations(y),
+    };
+    // whether the array holds the value `add-unique` appends depends on its elements, and where
+    // it goes on how many there are
+    match *extension {
+        Extension::AddUnique(_) => changed.iter().any(|loc| strictly_under(loc, path)),
+        _ => false,
+    }
+}
+
+/// The paths `op` reads or changes, in the document it applies to
+fn locations(op: &Op) -> Vec<Path> {
+    match *op {
+        Op::Copy(ref path, ref from) => vec![from.clone(), path.clone()],
+        // the destination is in the document without the moved value
+        Op::Move(ref path, ref from) => vec![from.clone(), shift(path, from, true)],
+        Op::Add(ref path, _) |
+        Op::Remove(ref path) |
+        Op::Replace(ref path, _) |
+        Op::Extension(ref path, _) => vec![path.clone()],
+        Op::Test(..) | Op::Assert(..) => vec![],
+    }
+}
+
+/// How an operation changes the document, as far as the operations concurrent with it care
+struct Effect {
+    /// Where a value is removed from
+    removes: Option<Path>,
+    /// Where a value is placed, after the removal
+    places: Option<Path>,
+    /// Whether the placement inserts into an array rather than setting a value
+    inserts: bool,
+    /// Whether the value placed is the one removed, taking everything under it along
+    moves: bool,
+}
+
+/// Where a rebased operation places its value
+enum Placed {
+    At(Path),
+    /// The value it would have replaced was removed, so it must be added again
+    Readded(Path),
+    /// The value it would have replaced was moved here, so it replaces it here instead
+    Over(Path),
+    Lost,
+}
+
+/// `x` rebased to apply after `y`, both made against the same document. `wins` says which of
+/// them takes precedence where they conflict.
+fn transform_op(x: &Op, y: &Op, wins: bool) -> Vec<Op> {
+    if is_noop(y) {
+        return vec![x.clone()];
+    }
+    if is_noop(x) {
+        return vec![];
+    }
+    if sets_root(y) {
+        return if sets_root(x) && wins {
+            vec![x.clone()]
+        } else {
+            vec![]
+        };
+    }
+    if sets_root(x) {
+        return vec![x.clone()];
+    }
+    if let Op::Extension(ref changed, ref with) = *y {
+        // nothing moves, but what a test checked may have changed
+        return match *x {
+            Op::Test(ref path, _) |
+            Op::Assert(ref path, _) if path.starts_with(changed) || changed.starts_with(path) => {
+                vec![]
+            }
+            Op::Extension(ref path, ref extension) if path == changed => {
+                let rebased = rebase_text(extension, with, wins).into_iter();
+                rebased.map(|extension| Op::Extension(path.clone(), extension)).collect()
+            }
+            _ => vec![x.clone()],
+        };
+    }
+    let e = effect(y);
+    let ops = match *x {
+        Op::Test(ref path, ref value) => {
+            if touches(&e, path) {
+                vec![]
+            } else {
+                map_loc(&e, path).map(|path| Op::Test(path, value.clone())).into_iter().collect()
+            }
+        }
+        Op::Assert(ref path, ref predicate) => {
+            if touches(&e, path) {
+                vec![]
+            } else {
+                let path = map_loc(&e, path);
+                path.map(|path| Op::Assert(path, predicate.clone())).into_iter().collect()
+            }
+        }
+        Op::Remove(ref path) => {
+            match e.removes {
+                Some(ref from) if path.starts_with(from) => {
+                    match e.places {
+                        Some(ref to) if e.moves => vec![Op::Remove(rebase(path, from, to))],
+                        _ => vec![],
+                    }
+                }
+                _ if replaced_by(&e, path) && wins => vec![Op::Remove(after_removal(&e, path))],
+                _ if replaced_by(&e, path) => vec![],
+                _ => map_loc(&e, path).map(Op::Remove).into_iter().collect(),
+            }
+        }
+        Op::Add(ref path, ref value) => {
+            match place(&e, path, is_insert(path), wins) {
+                Placed::At(path) | Placed::Readded(path) => vec![Op::Add(path, value.clone())],
+                Placed::Over(path) => vec![Op::Replace(path, value.clone())],
+                Placed::Lost => vec![],
+            }
+        }
+        Op::Replace(ref path, ref value) => {
+            match place(&e, path, false, wins) {
+                Placed::At(path) | Placed::Over(path) => vec![Op::Replace(path, value.clone())],
+                Placed::Readded(path) => vec![Op::Add(path, value.clone())],
+                Placed::Lost => vec![],
+            }
+        }
+        Op::Copy(ref path, ref from) => {
+            match (source(&e, from), place(&e, path, is_insert(path), wins)) {
+                (None, Placed::At(path)) | (None, Placed::Readded(path)) => clear(path),
+                (None, _) | (_, Placed::Lost) => vec![],
+                (Some(from), Placed::At(path)) |
+                (Some(from), Placed::Readded(path)) => vec![Op::Copy(path, from)],
+                (Some(from), Placed::Over(path)) => replace_at(path, from, false),
+            }
+        }
+        Op::Move(ref path, ref from) => transform_move(path, from, &e, wins),
+        Op::Extension(ref path, ref extension) => {
+            let path = map_loc(&e, path);
+            path.map(|path| Op::Extension(path, extension.clone())).into_iter().collect()
+        }
+    };
+    compensate(x, y, ops).into_iter().filter(|op| !is_noop(op)).collect()
+}
+
+/// `x` rebased to apply after `y`, both extension ops on the same value. Insertions and deletions
+/// of text shift past each other as array elements do, and text the other inserted into text
+/// deleted is kept; the rest commute and are kept as they are.
+fn rebase_text(x: &Extension, y: &Extension, wins: bool) -> Vec<Extension> {
+    match (x, y) {
+        (&Extension::StrIns(pos, ref text), &Extension::StrIns(at, ref inserted)) => {
+            if at < pos || (at == pos && !wins) {
+                vec![Extension::StrIns(pos + inserted.chars().count(), text.clone())]
+            } else {
+                vec![x.clone()]
+            }
+        }
+        (&Extension::StrIns(pos, ref text), &Extension::StrDel(at, len)) => {
+            if at + len <= pos {
+                vec![Extension::StrIns(pos - len, text.clone())]
+            } else if at < pos {
+                // inserted into the deleted text, so where it was
+                vec![Extension::StrIns(at, text.clone())]
+            } else {
+                vec![x.clone()]
+            }
+        }
+        (&Extension::StrDel(pos, len), &Extension::StrIns(at, ref inserted)) => {
+            let n = inserted.chars().count();
+            if at <= pos {
+                vec![Extension::StrDel(pos + n, len)]
+            } else if at < pos + len {
+                // the inserted text is kept, so the deletion goes around it, later part first
+                vec![Extension::StrDel(at + n, pos + len - at), Extension::StrDel(pos, at - pos)]
+            } else {
+                vec![x.clone()]
+            }
+        }
+        (&Extension::StrDel(pos, len), &Extension::StrDel(at, deleted)) => {
+            let overlap = cmp::min(pos + len, at + deleted).saturating_sub(cmp::max(pos, at));
+            let start = if pos <= at {
+                pos
+            } else if pos >= at + deleted {
+                pos - deleted
+            } else {
+                at
+            };
+            if len > overlap {
+                vec![Extension::StrDel(start, len - overlap)]
+            } else {
+                vec![]
+            }
+        }
+        _ => vec![x.clone()],
+    }
+}
+
+/// A move rebased over an operation with effect `e`
+fn transform_move(path: &[String], from: &[String], e: &Effect, wins: bool) -> Vec<Op> {
+    let source = match e.removes {
+        Some(ref removed) if from.starts_with(removed) => {
+            match e.places {
+                // both moved the same value
+                Some(ref to) if e.moves && from == &removed[..] => {
+                    if wins {
+                        return vec![Op::Move(path.to_vec(), to.clone())];
+                    }
+                    None
+                }
+                Some(ref to) if e.moves => Some(rebase(from, removed, to)),
+                _ => None,
+            }
+        }
+        _ => source(e, from),
+    };
+
+    // the destination is relative to the document without the moved value, so `e` has to be
+    // seen from there as well
+    let removed_from = match e.removes {
+        Some(ref removed) if removed.starts_with(from) => None,
+        Some(ref removed) => Some(shift(removed, from, false)),
+        None => None,
+    };
+    let from_after = match e.removes {
+        Some(ref removed) if from.starts_with(removed) => None,
+        Some(ref removed) => Some(shift(from, removed, false)),
+        None => Some(from.to_vec()),
+    };
+    let placed_at = e.places.as_ref().and_then(|to| {
+        match from_after {
+            // placed in the moved value, or set where it was
+            Some(ref from) if strictly_under(to, from) || (to == from && !e.inserts) => None,
+            Some(ref from) => Some(shift(to, from, false)),
+            None => Some(to.clone()),
+        }
+    });
+    let seen = Effect {
+        moves: e.moves && removed_from.is_some() && placed_at.is_some(),
+        removes: removed_from,
+        places: placed_at,
+        inserts: e.inserts,
+    };
+    match (source, place(&seen, path, is_insert(path), wins)) {
+        (None, Placed::At(path)) | (None, Placed::Readded(path)) => clear(path),
+        (None, _) => vec![],
+        (Some(source), Placed::At(path)) |
+        (Some(source), Placed::Readded(path)) => vec![Op::Move(path, source)],
+        // `seen` has where `e` placed the value it moved in the wrong document
+        (Some(source), Placed::Over(_)) => {
+            replace_at(e.places.clone().unwrap_or(vec![]), source, true)
+        }
+        (Some(source), Placed::Lost) => vec![Op::Remove(source)],
+    }
+}
+
+/// `ops`, which are `x` rebased over `y`, with what else they need because `y` copied or moved
+/// a value that `x` changes
+fn compensate(x: &Op, y: &Op, ops: Vec<Op>) -> Vec<Op> {
+    let (to, from, copies) = match *y {
+        Op::Copy(ref to, ref from) => (to, from, true),
+        Op::Move(ref to, ref from) => (to, from, false),
+        _ => return ops,
+    };
+    if let Op::Move(_, ref moved) = *x {
+        if from.starts_with(moved) {
+            // the copy is of the value `x` moved, wherever it ends up
+            return ops;
+        }
+    }
+    let destroyed = destroys_above(x, from) || *x == Op::Remove(from.clone());
+    if copies && !destroyed {
+        return mirror(ops, map_loc(&effect(y), from), Some(to.clone()));
+    }
+    // whether `ops` remove or replace the value `y` placed anyway
+    let kept = ops.iter().fold(Some(to.clone()),
+                               |to, op| to.and_then(|to| map_loc(&effect(op), &to)));
+    if kept.is_none() || !destroyed {
+        return ops;
+    }
+    // `y` placed a value that `x` would have removed along with its source, and it must go before
+    // `ops` in case they copy it
+    let removed = vec![Op::Remove(to.clone())];
+    let mut compensated = removed.clone();
+    // only strict rebases are refused
+    compensated.extend(transform_ops(&ops, &removed, false).unwrap().0);
+    compensated
+}
+
+/// `ops` followed each by the operations repeating its changes to the value at `source` in the
+/// copy of it at `copy`
+fn mirror(ops: Vec<Op>, mut source: Option<Path>, mut copy: Option<Path>) -> Vec<Op> {
+    let mut mirrored = vec![];
+    for op in ops {
+        let mut pending = vec![op];
+        while !pending.is_empty() {
+            let op = pending.remove(0);
+            let repeats = match (&source, &copy) {
+                (&Some(ref source), &Some(ref copy)) => repeat(&op, source, copy),
+                _ => vec![],
+            };
+            let e = effect(&op);
+            if repeats.is_empty() {
+                if source.as_ref().map_or(false, |source| destroys_above(&op, source)) {
+                    // the copy's source is gone, so the copy goes too
+                    if let Some(copy) = copy.as_ref().and_then(|copy| map_loc(&e, copy)) {
+                        pending.push(Op::Remove(copy));
+                    }
+                    source = None;
+                }
+            }
+            source = source.and_then(|source| map_loc(&e, &source));
+            copy = copy.and_then(|copy| map_loc(&e, &copy));
+            mirrored.push(op);
+            // the repeats are made against the copy, which the source no longer matches
+            for repeat in repeats {
+                let e = effect(&repeat);
+                source = source.and_then(|source| map_loc(&e, &source));
+                copy = copy.and_then(|copy| map_loc(&e, &copy));
+                mirrored.push(repeat);
+            }
+        }
+    }
+    mirrored
+}
+
+/// The operations making the change `op` makes to the value at `source` to the copy of it at
+/// `copy`, to be applied after `op`
+fn repeat(op: &Op, source: &[String], copy: &[String]) -> Vec<Op> {
+    let e = effect(op);
+    let copy = match map_loc(&e, copy) {
+        Some(copy) => copy,
+        None => return vec![],
+    };
+    let relocate = |path: &[String], source: &[String]| rebase(path, source, &copy);
+    match *op {
+        Op::Remove(ref path) if path.starts_with(source) => {
+            vec![Op::Remove(relocate(path, source))]
+        }
+        Op::Add(ref path, ref value) | Op::Replace(ref path, ref value) => {
+            let inserts = match *op {
+                Op::Add(..) => is_insert(path),
+                _ => false,
+            };
+            if path == source && !inserts {
+                vec![Op::Replace(copy.clone(), value.clone())]
+            } else if strictly_under(path, source) {
+                let path = relocate(path, source);
+                match *op {
+                    Op::Add(..) => vec![Op::Add(path, value.clone())],
+                    _ => vec![Op::Replace(path, value.clone())],
+                }
+            } else {
+                vec![]
+            }
+        }
+        Op::Copy(ref path, _) if reaches(path, source) => {
+            if path == source {
+                replace_at(copy.clone(), path.clone(), false)
+            } else {
+                vec![Op::Copy(relocate(path, source), path.clone())]
+            }
+        }
+        Op::Move(ref path, ref from) if !source.starts_with(from) => {
+            let source_after = shift(source, from, false);
+            match (strictly_under(from, source), reaches(path, &source_after)) {
+                (true, true) => {
+                    vec![Op::Move(relocate(path, &source_after), relocate(from, source))]
+                }
+                (true, false) => vec![Op::Remove(relocate(from, source))],
+                (false, true) if *path == source_after => {
+                    replace_at(copy.clone(), path.clone(), false)
+                }
+                (false, true) => vec![Op::Copy(relocate(path, &source_after), path.clone())],
+                (false, false) => vec![],
+            }
+        }
+        Op::Extension(ref path, ref extension) if path.starts_with(source) => {
+            vec![Op::Extension(relocate(path, source), extension.clone())]
+        }
+        _ => vec![],
+    }
+}
+
+/// The operations leaving nothing at `path`, whether or not there was something there.
+///
+/// A copy or move whose source the other operation removed is dropped, but the other operation,
+/// rebased over it, removes what it placed; so must the dropped one in case it replaced a value.
+fn clear(path: Path) -> Vec<Op> {
+    if is_insert(&path) {
+        vec![]
+    } else {
+        vec![Op::Add(path.clone(), Value::Null), Op::Remove(path)]
+    }
+}
+
+/// The operations replacing the value at `path` with the one at `from`, by moving it if `moves`
+/// and otherwise copying it
+fn replace_at(path: Path, from: Path, moves: bool) -> Vec<Op> {
+    let from = match map_loc(&effect(&Op::Remove(path.clone())), &from) {
+        Some(from) => from,
+        None => return vec![],
+    };
+    if moves {
+        let to = shift(&path, &from, false);
+        vec![Op::Remove(path), Op::Move(to, from)]
+    } else {
+        vec![Op::Remove(path.clone()), Op::Copy(path, from)]
+    }
+}
+
+fn effect(op: &Op) -> Effect {
+    let (removes, places, replaces) = match *op {
+        Op::Add(ref path, _) | Op::Copy(ref path, _) => (None, Some(path), false),
+        Op::Replace(ref path, _) => (None, Some(path), true),
+        Op::Remove(ref path) => (Some(path), None, false),
+        Op::Move(ref path, ref from) => (Some(from), Some(path), false),
+        Op::Test(..) | Op::Assert(..) | Op::Extension(..) => (None, None, false),
+    };
+    Effect {
+        removes: removes.cloned(),
+        inserts: !replaces && places.map_or(false, |path| is_insert(path)),
+        places: places.cloned(),
+        moves: match *op {
+            Op::Move(..) => true,
+            _ => false,
+        },
+    }
+}
+
+/// Where the value at `loc` is after `e`, or `None` if `e` removed or replaced it
+fn map_loc(e: &Effect, loc: &[String]) -> Option<Path> {
+    let mut loc = loc.to_vec();
+    if let Some(ref from) = e.removes {
+        if loc.starts_with(from) {
+            return match e.places {
+                Some(ref to) if e.moves => Some(rebase(&loc, from, to)),
+                _ => None,
+            };
+        }
+        loc = shift(&loc, from, false);
+    }
+    if let Some(ref to) = e.places {
+        if e.inserts {
+            loc = shift(&loc, to, true);
+        } else if loc.starts_with(to) {
+            return None;
+        }
+    }
+    Some(loc)
+}
+
+/// Where a value placed at `dest` goes after `e`, or `None` if `e` removed or replaced its parent
+fn map_dest(e: &Effect, dest: &[String], inserts: bool, wins: bool) -> Option<Path> {
+    let (key, parent) = match dest.split_last() {
+        Some(split) => split,
+        None => return Some(vec![]),
+    };
+    let mut parent = parent.to_vec();
+    let mut key = key.clone();
+    if let Some(ref from) = e.removes {
+        if parent.starts_with(from) {
+            return match e.places {
+                Some(ref to) if e.moves => Some(child(rebase(&parent, from, to), &key)),
+                _ => None,
+            };
+        }
+        if siblings(from, dest) {
+            if let (Some(i), Some(j)) = (index(&key), index(&from[from.len() - 1])) {
+                if i > j {
+                    key = (i - 1).to_string();
+                }
+            }
+        }
+        parent = shift(&parent, from, false);
+    }
+    if let Some(ref to) = e.places {
+        if e.inserts {
+            if siblings(to, &child(parent.clone(), &key)) {
+                if let (Some(i), Some(j)) = (index(&key), index(&to[to.len() - 1])) {
+                    if j < i || (j == i && !(inserts && wins)) {
+                        key = (i + 1).to_string();
+                    }
+                }
+            }
+            parent = shift(&parent, to, true);
+        } else if parent.starts_with(to) {
+            return None;
+        }
+    }
+    Some(child(parent, &key))
+}
+
+/// Where a value placed at `dest`, inserting it into an array if `inserts`, goes after `e`
+fn place(e: &Effect, dest: &[String], inserts: bool, wins: bool) -> Placed {
+    if !inserts {
+        if let Some(ref from) = e.removes {
+            if dest == &from[..] {
+                return match e.places {
+                    Some(ref to) if e.moves => Placed::Over(to.clone()),
+                    _ if wins => Placed::Readded(dest.to_vec()),
+                    _ => Placed::Lost,
+                };
+            }
+        }
+        if replaced_by(e, dest) {
+            return if wins {
+                Placed::At(after_removal(e, dest))
+            } else {
+                Placed::Lost
+            };
+        }
+    }
+    match map_dest(e, dest, inserts, wins) {
+        Some(dest) => Placed::At(dest),
+        None => Placed::Lost,
+    }
+}
+
+/// Where a copy from `from` copies from after `e`, or `None` if `e` removed it
+fn source(e: &Effect, from: &[String]) -> Option<Path> {
+    if replaced_by(e, from) {
+        // copy the new value
+        return Some(after_removal(e, from));
+    }
+    map_loc(e, from)
+}
+
+/// Whether `e` sets the value at `loc` (other than by inserting before it)
+fn replaced_by(e: &Effect, loc: &[String]) -> bool {
+    if let Some(ref from) = e.removes {
+        if loc.starts_with(from) {
+            return false;
+        }
+    }
+    match e.places {
+        Some(ref to) if !e.inserts => after_removal(e, loc) == *to,
+        _ => false,
+    }
+}
+
+/// `loc`, which `e` does not remove, in the document with what `e` removes removed
+fn after_removal(e: &Effect, loc: &[String]) -> Path {
+    match e.removes {
+        Some(ref from) => shift(loc, from, false),
+        None => loc.to_vec(),
+    }
+}
+
+/// Whether `e` changes the value at `loc`
+fn touches(e: &Effect, loc: &[String]) -> bool {
+    let overlaps = |a: &[String], b: &[String]| a.starts_with(b) || b.starts_with(a);
+    if e.removes.as_ref().map_or(false, |from| overlaps(from, loc)) {
+        return true;
+    }
+    let loc = after_removal(e, loc);
+    e.places.as_ref().map_or(false, |to| {
+        if e.inserts {
+            strictly_under(to, &loc)
+        } else {
+            overlaps(to, &loc)
+        }
+    })
+}
+
+/// Whether `op` removes or sets a value that `path` is strictly under
+fn destroys_above(op: &Op, path: &[String]) -> bool {
+    match *op {
+        Op::Remove(ref removed) => strictly_under(path, removed),
+        Op::Add(ref set, _) | Op::Copy(ref set, _) => {
+            !is_insert(set) && strictly_under(path, set)
+        }
+        Op::Replace(ref set, _) => strictly_under(path, set),
+        Op::Move(ref set, ref from) => {
+            !path.starts_with(from) && !is_insert(set) &&
+            strictly_under(&shift(path, from, false), set)
+        }
+        Op::Test(..) | Op::Assert(..) | Op::Extension(..) => false,
+    }
+}
+
+/// Whether placing a value at `path` changes the value at `loc`
+fn reaches(path: &[String], loc: &[String]) -> bool {
+    strictly_under(path, loc) || (path == loc && !is_insert(path))
+}
+
+/// Whether `op` is a copy or move of a value to where it already is
+fn is_noop(op: &Op) -> bool {
+    match *op {
+        Op::Copy(ref path, ref from) => path == from && !is_insert(path),
+        Op::Move(ref path, ref from) => path == from,
+        _ => false,
+    }
+}
+
+fn sets_root(op: &Op) -> bool {
+    match *op {
+        Op::Add(ref path, _) |
+        Op::Replace(ref path, _) |
+        Op::Copy(ref path, _) |
+        Op::Move(ref path, _) => path.is_empty(),
+        _ => false,
+    }
+}
+
+/// Whether a value placed at `path` is inserted into an array rather than set
+fn is_insert(path: &[String]) -> bool {
+    path.last().map_or(false, |key| key == "-" || index(key).is_some())
+}
+
+fn index(key: &str) -> Option<usize> {
+    key.parse().ok()
+}
+
+fn strictly_under(path: &[String], prefix: &[String]) -> bool {
+    path.len() > prefix.len() && path.starts_with(prefix)
+}
+
+/// Whether `a` and `b` are in the same container
+fn siblings(a: &[String], b: &[String]) -> bool {
+    !a.is_empty() && a.len() == b.len() && a[..a.len() - 1] == b[..b.len() - 1]
+}
+
+/// `path`, which is under `from`, with `from` replaced by `to`
+fn rebase(path: &[String], from: &[String], to: &[String]) -> Path {
+    let mut rebased = to.to_vec();
+    rebased.extend(path[from.len()..].iter().cloned());
+    rebased
+}
+
+fn child(mut path: Path, key: &str) -> Path {
+    path.push(key.to_string());
+    path
+}
+
+/// `loc` after an array element at `at` is inserted (`up`) or removed before it
+fn shift(loc: &[String], at: &[String], up: bool) -> Path {
+    let mut loc = loc.to_vec();
+    let n = at.len();
+    if n > 0 && loc.len() >= n && loc[..n - 1] == at[..n - 1] {
+        if let (Some(i), Some(j)) = (index(&at[n - 1]), index(&loc[n - 1])) {
+            if up && j >= i {
+                loc[n - 1] = (j + 1).to_string();
+            } else if !up && j > i {
+                loc[n - 1] = (j - 1).to_string();
+            }
+        }
+    }
+    loc
+}
+
+#[cfg(test)]
+use {apply, resolve_appends};
+#[cfg(test)]
+use testing::{all_ops, random_doc_with, random_patch_with, Rng, WORD_KEYS};
+
+/// Check that the patches converge once rebased, unless `transform` refuses them. Patches that
+/// both append are tried again with the appends resolved.
+#[cfg(test)]
+fn assert_converge(doc: &Value, a: &Patch, b: &Patch) {
+    let (a2, b2) = match transform(a, b) {
+        Ok(rebased) => rebased,
+        Err(TransformError::BothAppend) => {
+            let (a, b) = (resolve_appends(a, doc).unwrap(), resolve_appends(b, doc).unwrap());
+            return assert_converge(doc, &a, &b);
+        }
+        Err(TransformError::Entangled) |
+        Err(TransformError::NonCommuting) => return,
+    };
+    let ab = apply(a, doc).and_then(|doc| apply(&b2, &doc));
+    let ba = apply(b, doc).and_then(|doc| apply(&a2, &doc));
+    assert!(ab.is_ok() && ab == ba,
+            "doc {:?}\na  {}\nb  {}\na2 {}\nb2 {}\nab {:?}\nba {:?}",
+            doc,
+            a,
+            b,
+            a2,
+            b2,
+            ab,
+            ba);
+}
+
+/// A patch that may use the extension ops
+#[cfg(test)]
+fn extended(s: &str) -> Patch {
+    let options = ::ParseOptions { extensions: true, ..::ParseOptions::default() };
+    Patch::from_str_with(s, options).unwrap()
+}
+
+#[test]
+fn indices_shift_past_concurrent_inserts_and_removes() {
+    let a = Patch::from_str(r#"[{"op":"add","path":"/a/1","value":"x"}]"#).unwrap();
+    let b = Patch::from_str(r#"[{"op":"remove","path":"/a/0"},
+                                {"op":"replace","path":"/a/2","value":"y"}]"#)
+                .unwrap();
+    let (a2, b2) = transform(&a, &b).unwrap();
+    assert_eq!(a2, Patch::from_str(r#"[{"op":"add","path":"/a/0","value":"x"}]"#).unwrap());
+    assert_eq!(b2,
+               Patch::from_str(r#"[{"op":"remove","path":"/a/0"},
+                                   {"op":"replace","path":"/a/3","value":"y"}]"#)
+                   .unwrap());
+}
+
+#[test]
+fn ops_under_a_removed_value_are_dropped() {
+    let a = Patch::from_str(r#"[{"op":"remove","path":"/a"}]"#).unwrap();
+    let b = Patch::from_str(r#"[{"op":"add","path":"/a/0/b","value":1}]"#).unwrap();
+    let (a2, b2) = transform(&a, &b).unwrap();
+    assert_eq!(a2, a);
+    assert_eq!(b2.ops, vec![]);
+}
+
+#[test]
+fn appends_stay_after_concurrent_inserts() {
+    let a = Patch::from_str(r#"[{"op":"add","path":"/a/-","value":"x"}]"#).unwrap();
+    let b = Patch::from_str(r#"[{"op":"add","path":"/a/2","value":"y"}]"#).unwrap();
+    let (a2, b2) = transform(&a, &b).unwrap();
+    assert_eq!((a2, b2), (a, b));
+}
+
+#[test]
+fn appends_on_both_sides_are_refused_until_resolved() {
+    let doc: Value = ::serde_json::from_str(r#"{"a":[1,2]}"#).unwrap();
+    let a = Patch::from_str(r#"[{"op":"add","path":"/a/-","value":"x"}]"#).unwrap();
+    let b = Patch::from_str(r#"[{"op":"add","path":"/a/-","value":"y"}]"#).unwrap();
+    assert_eq!(transform(&a, &b), Err(TransformError::BothAppend));
+    let (a, b) = (resolve_appends(&a, &doc).unwrap(), resolve_appends(&b, &doc).unwrap());
+    assert_eq!(a, Patch::from_str(r#"[{"op":"add","path":"/a/2","value":"x"}]"#).unwrap());
+    let (_, b2) = transform(&a, &b).unwrap();
+    assert_eq!(b2, Patch::from_str(r#"[{"op":"add","path":"/a/3","value":"y"}]"#).unwrap());
+}
+
+#[test]
+fn entangled_copies_and_moves_are_refused() {
+    let a = Patch::from_str(r#"[{"op":"add","path":"/c","value":1},
+                                {"op":"move","path":"/b/x","from":"/a"}]"#)
+                .unwrap();
+    let b = Patch::from_str(r#"[{"op":"copy","path":"/a/y","from":"/b"}]"#).unwrap();
+    assert_eq!(transform(&a, &b), Err(TransformError::Entangled));
+    // copies and moves of unrelated values are rebased as usual
+    let b = Patch::from_str(r#"[{"op":"copy","path":"/d","from":"/e"}]"#).unwrap();
+    assert!(transform(&a, &b).is_ok());
+}
+
+#[test]
+fn increments_converge_with_every_op() {
+    let doc: Value = ::serde_json::from_str(r#"{"n":1,"a":{"m":2},"l":[3,4]}"#).unwrap();
+    let incs = ["/n", "/a/m", "/l/1"].iter().map(|path| {
+        Op::Extension(::parse_pointer(path), ::Extension::Inc(Value::I64(-2)))
+    });
+    let mut ops = all_ops(&doc, &Value::U64(7), WORD_KEYS);
+    ops.extend(incs.clone());
+    for inc in incs {
+        for op in &ops {
+            let (a, b) = (Patch { ops: vec![inc.clone()] }, Patch { ops: vec![op.clone()] });
+            assert_converge(&doc, &a, &b);
+            assert_converge(&doc, &b, &a);
+        }
+    }
+}
+
+#[test]
+fn every_pair_of_ops_converges() {
+    let mut rng = Rng(0x9e3779b97f4a7c15);
+    for _ in 0..20 {
+        let doc = random_doc_with(&mut rng, 3, WORD_KEYS);
+        let ops = all_ops(&doc, &Value::U64(7), WORD_KEYS);
+        for a in &ops {
+            for b in &ops {
+                assert_converge(&doc,
+                                &Patch { ops: vec![a.clone()] },
+                                &Patch { ops: vec![b.clone()] });
+            }
+        }
+    }
+}
+
+#[test]
+fn random_patches_converge() {
+    let mut rng = Rng(0x2545f4914f6cdd1d);
+    for _ in 0..5000 {
+        let doc = random_doc_with(&mut rng, 3, WORD_KEYS);
+        let a = random_patch_with(&mut rng, &doc, 3, WORD_KEYS);
+        let b = random_patch_with(&mut rng, &doc, 3, WORD_KEYS);
+        assert_converge(&doc, &a, &b);
+    }
+}
+
+#[test]
+fn text_insertions_shift_past_each_other() {
+    let a = extended(r#"[{"op":"str-ins","path":"/s","pos":1,"value":"ab"}]"#);
+    let b = extended(r#"[{"op":"str-ins","path":"/s","pos":1,"value":"xyz"}]"#);
+    let (a2, b2) = transform(&a, &b).unwrap();
+    // `a` wins, so its text goes first
+    assert_eq!(a2, a);
+    assert_eq!(b2, extended(r#"[{"op":"str-ins","path":"/s","pos":3,"value":"xyz"}]"#));
+}
+
+#[test]
+fn deletions_go_around_text_inserted_into_them() {
+    let a = extended(r#"[{"op":"str-del","path":"/s","pos":1,"len":3}]"#);
+    let b = extended(r#"[{"op":"str-ins","path":"/s","pos":2,"value":"x"}]"#);
+    let (a2, b2) = transform(&a, &b).unwrap();
+    assert_eq!(a2,
+               extended(r#"[{"op":"str-del","path":"/s","pos":3,"len":2},
+                            {"op":"str-del","path":"/s","pos":1,"len":1}]"#));
+    assert_eq!(b2, extended(r#"[{"op":"str-ins","path":"/s","pos":1,"value":"x"}]"#));
+}
+
+#[test]
+fn extension_ops_that_do_not_commute_are_refused() {
+    let min = extended(r#"[{"op":"min","path":"/n","value":1}]"#);
+    let max = extended(r#"[{"op":"max","path":"/n","value":5}]"#);
+    assert_eq!(transform(&min, &max), Err(TransformError::NonCommuting));
+    assert_eq!(transform(&min, &min), Ok((min.clone(), min.clone())));
+    let inc = extended(r#"[{"op":"inc","path":"/n","value":1}]"#);
+    let ins = extended(r#"[{"op":"str-ins","path":"/n","pos":0,"value":"x"}]"#);
+    assert_eq!(transform(&inc, &ins), Err(TransformError::NonCommuting));
+    assert_eq!(transform(&max, &inc), Err(TransformError::NonCommuting));
+    let unique = extended(r#"[{"op":"add-unique","path":"/l","value":1}]"#);
+    let set = extended(r#"[{"op":"replace","path":"/l/0","value":1}]"#);
+    assert_eq!(transform(&set, &unique), Err(TransformError::NonCommuting));
+    // elements of other arrays are another matter
+    let other = extended(r#"[{"op":"replace","path":"/m/0","value":1}]"#);
+    assert!(transform(&unique, &other).is_ok());
+}
+
+#[test]
+fn text_insertions_and_deletions_converge() {
+    let doc: Value = ::serde_json::from_str(r#"{"s":"abcdef"}"#).unwrap();
+    let mut ops = vec![];
+    for pos in 0..7 {
+        ops.push(::Extension::StrIns(pos, "xy".to_string()));
+        for len in 0..7 - pos {
+            ops.push(::Extension::StrDel(pos, len));
+        }
+    }
+    let ops: Vec<Op> = ops.into_iter().map(|e| Op::Extension(::parse_pointer("/s"), e)).collect();
+    for a in &ops {
+        for b in &ops {
+            let (a, b) = (Patch { ops: vec![a.clone()] }, Patch { ops: vec![b.clone()] });
+            assert!(transform(&a, &b).is_ok());
+            assert_converge(&doc, &a, &b);
+        }
+    }
+}
+
+#[test]
+fn extension_ops_converge_with_every_op() {
+    let doc: Value = ::serde_json::from_str(r#"{"n":1,"s":"abc","l":[1,"x"]}"#).unwrap();
+    let extensions = vec![::Extension::Min(Value::I64(0)),
+                          ::Extension::Max(Value::I64(3)),
+                          ::Extension::StrIns(1, "y".to_string()),
+                          ::Extension::StrDel(0, 2),
+                          ::Extension::AddUnique(Value::U64(7)),
+                          ::Extension::AddUnique(Value::U64(1))];
+    let extensions: Vec<Op> = extensions.into_iter()
+                                        .map(|e| {
+                                            let path = match e {
+                                                ::Extension::Min(_) | ::Extension::Max(_) => "/n",
+                                                ::Extension::AddUnique(_) => "/l",
+                                                _ => "/s",
+                                            };
+                                            Op::Extension(::parse_pointer(path), e)
+                                        })
+                                        .collect();
+    let mut ops = all_ops(&doc, &Value::U64(7), WORD_KEYS);
+    ops.extend(extensions.clone());
+    for extension in extensions {
+        for op in &ops {
+            let (a, b) = (Patch { ops: vec![extension.clone()] }, Patch { ops: vec![op.clone()] });
+            assert_converge(&doc, &a, &b);
+            assert_converge(&doc, &b, &a);
+        }
+    }
+}
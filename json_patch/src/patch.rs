@@ -2,18 +2,133 @@ use Op;
 use Patch;
 
 use serde_json::Value;
+use std::collections::BTreeMap;
+#[cfg(test)]
+use testing::{random_doc, random_patch, Rng};
 
 #[derive(Debug,PartialEq)]
 pub struct PatchError;
 
+/// Settings for `apply_with`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ApplyOptions {
+    /// Create the missing parents of the target of an `add`, `copy` or `move` instead of
+    /// failing. Each is an array if the token below it is a number or `-`, otherwise an object.
+    /// A `null` parent counts as missing, so this can be used to fill in an empty document.
+    pub create_parents: bool,
+}
+
 pub fn apply(patch: &Patch, v: &Value) -> Result<Value, PatchError> {
+    apply_with(patch, v, ApplyOptions::default())
+}
+
+pub fn apply_with(patch: &Patch, v: &Value, options: ApplyOptions) -> Result<Value, PatchError> {
     let mut v2 = v.clone();
     for op in &patch.ops {
-        try!(apply_op(op, &mut v2))
+        try!(apply_op_with(op, &mut v2, options))
     }
     Ok(v2)
 }
 
+/// Whether `patch` applies to `doc`, without building the result. Only the values the patch reads
+/// or writes are copied, so checking a small patch against a large document is cheap.
+pub fn check(patch: &Patch, doc: &Value) -> Result<(), PatchError> {
+    let footprint = patch.footprint();
+    let paths: Vec<&[String]> = footprint.reads
+                                         .iter()
+                                         .chain(&footprint.writes)
+                                         .map(|path| &path[..])
+                                         .collect();
+    let mut scratch = prune(doc, &paths);
+    for op in &patch.ops {
+        try!(apply_op(op, &mut scratch));
+    }
+    Ok(())
+}
+
+/// `patch` with the parents that `apply_with` would create for it against `v` added as explicit
+/// operations, so the result has the same effect when applied to `v` without options
+pub fn expand_parents(patch: &Patch, v: &Value) -> Result<Patch, PatchError> {
+    let mut scratch = v.clone();
+    let mut ops = Vec::with_capacity(patch.ops.len());
+    for op in &patch.ops {
+        let (parents, op) = with_parents(op, &scratch);
+        for parent in parents {
+            try!(apply_op(&parent, &mut scratch));
+            ops.push(parent);
+        }
+        try!(apply_op(&op, &mut scratch));
+        ops.push(op);
+    }
+    Ok(Patch { ops: ops })
+}
+
+/// `patch` with the `-` of every operation appending to an array replaced by the index it appends
+/// at when applied to `v`, so that the result no longer depends on the lengths of the arrays
+pub fn resolve_appends(patch: &Patch, v: &Value) -> Result<Patch, PatchError> {
+    if !patch.ops.iter().any(appends) {
+        return Ok(patch.clone());
+    }
+    let mut scratch = v.clone();
+    let mut ops = Vec::with_capacity(patch.ops.len());
+    for op in &patch.ops {
+        try!(apply_op(op, &mut scratch));
+        ops.push(resolve_append(op, &scratch));
+    }
+    Ok(Patch { ops: ops })
+}
+
+/// Whether `op` places a value at a path ending in `-`
+pub fn appends(op: &Op) -> bool {
+    match *op {
+        Op::Add(ref path, _) | Op::Copy(ref path, _) | Op::Move(ref path, _) => {
+            path.last().map_or(false, |key| key == "-")
+        }
+        _ => false,
+    }
+}
+
+/// `op`, which has just been applied to give `root`, appending at the index it appended at if it
+/// appended to an array
+fn resolve_append(op: &Op, root: &Value) -> Op {
+    if !appends(op) {
+        return op.clone();
+    }
+    let at = |path: &[String]| -> Option<Vec<String>> {
+        let mut path = path.to_vec();
+        let len = match find_path(root, &path[..path.len() - 1]) {
+            Some(&Value::Array(ref a)) => a.len(),
+            _ => return None,
+        };
+        // the appended value is the last element now
+        *path.last_mut().unwrap() = (len - 1).to_string();
+        Some(path)
+    };
+    let resolved = match *op {
+        Op::Add(ref path, ref value) => at(path).map(|path| Op::Add(path, value.clone())),
+        Op::Copy(ref path, ref from) => at(path).map(|path| Op::Copy(path, from.clone())),
+        Op::Move(ref path, ref from) => at(path).map(|path| Op::Move(path, from.clone())),
+        _ => None,
+    };
+    resolved.unwrap_or_else(|| op.clone())
+}
+
+/// The value at `path`, looking up array elements by index
+pub fn find_path<'a, S: AsRef<str>>(root: &'a Value, path: &[S]) -> Option<&'a Value> {
+    path.iter().fold(Some(root), |node, key| node.and_then(|node| find_key(node, key.as_ref())))
+}
+
+pub fn apply_op_with(op: &Op, root: &mut Value, options: ApplyOptions) -> Result<(), PatchError> {
+    if !options.create_parents {
+        return apply_op(op, root);
+    }
+    let (parents, op) = with_parents(op, root);
+    for parent in &parents {
+        try!(apply_op(parent, root));
+    }
+    apply_op(&op, root)
+}
+
 pub fn apply_op(op: &Op, root: &mut Value) -> Result<(), PatchError> {
     match op {
         &Op::Add(ref path, ref value) => {
@@ -75,10 +190,7 @@ pub fn apply_op(op: &Op, root: &mut Value) -> Result<(), PatchError> {
         }
 
         &Op::Copy(ref to, ref from) => {
-            let from_path: Vec<&str> = from.iter().map(|s| &s[..]).collect();
-            let value = try!(root.find_path(&from_path)
-                                 .ok_or(PatchError)
-                                 .map(|v| v.clone()));
+            let value = try!(find_path(root, from).ok_or(PatchError).map(|v| v.clone()));
 
             to.split_last().ok_or(PatchError).and_then(|(dest_key, dest_path)| {
                 get_path(root, dest_path)
@@ -86,6 +198,117 @@ pub fn apply_op(op: &Op, root: &mut Value) -> Result<(), PatchError> {
                     .and_then(|parent| insert_key(parent, dest_key, value))
             })
         }
+
+        &Op::Extension(ref path, ref extension) => {
+            get_path(root, path).ok_or(PatchError).and_then(|target| extension.apply(target))
+        }
+
+        &Op::Assert(ref path, ref predicate) => {
+            if predicate.holds(find_path(root, path)) {
+                Ok(())
+            } else {
+                Err(PatchError)
+            }
+        }
+    }
+}
+
+/// The operations creating the missing parents of the target of an add, copy or move, and the
+/// operation with any `-` among those parents replaced by the index it was created at
+fn with_parents(op: &Op, root: &Value) -> (Vec<Op>, Op) {
+    match op {
+        &Op::Add(ref path, ref value) => {
+            let (parents, path) = parent_ops(root, path);
+            (parents, Op::Add(path, value.clone()))
+        }
+        &Op::Copy(ref path, ref from) => {
+            let (parents, path) = parent_ops(root, path);
+            (parents, Op::Copy(path, from.clone()))
+        }
+        &Op::Move(ref path, ref from) => {
+            let (parents, path) = parent_ops(root, path);
+            (parents, Op::Move(path, from.clone()))
+        }
+        _ => (vec![], op.clone()),
+    }
+}
+
+fn parent_ops(root: &Value, path: &[String]) -> (Vec<Op>, Vec<String>) {
+    let mut path = path.to_vec();
+    let mut ops = vec![];
+    // `node` is the value at path[..depth], or None once a missing parent has been found
+    let mut node = Some(root);
+    for depth in 0..path.len() {
+        let container = container_for(&path[depth]);
+        match node {
+            None => ops.push(Op::Add(path[..depth].to_vec(), container)),
+            Some(&Value::Null) => ops.push(Op::Replace(path[..depth].to_vec(), container)),
+            Some(_) => (),
+        }
+        if depth + 1 < path.len() && path[depth] == "-" {
+            let len = match node {
+                Some(&Value::Array(ref a)) => a.len(),
+                _ => 0,
+            };
+            path[depth] = len.to_string();
+        }
+        node = node.and_then(|node| find_key(node, &path[depth]));
+    }
+    (ops, path)
+}
+
+/// A copy of `v` with only the values at `paths` and the containers leading to them. Array
+/// elements left out become `null`, so indices and lengths stay the same.
+fn prune(v: &Value, paths: &[&[String]]) -> Value {
+    if paths.iter().any(|path| path.is_empty()) {
+        return v.clone();
+    }
+    match *v {
+        Value::Object(ref o) => {
+            let mut pruned = BTreeMap::new();
+            for path in paths {
+                if let Some(child) = o.get(&path[0]) {
+                    let under: Vec<&[String]> = paths.iter()
+                                                     .filter(|p| p[0] == path[0])
+                                                     .map(|p| &p[1..])
+                                                     .collect();
+                    pruned.insert(path[0].clone(), prune(child, &under));
+                }
+            }
+            Value::Object(pruned)
+        }
+        Value::Array(ref a) => {
+            let mut pruned = vec![Value::Null; a.len()];
+            let index = |path: &[String]| existing_index(&path[0], a.len());
+            for path in paths {
+                if let Ok(i) = index(path) {
+                    let under: Vec<&[String]> = paths.iter()
+                                                     .filter(|p| index(p) == Ok(i))
+                                                     .map(|p| &p[1..])
+                                                     .collect();
+                    pruned[i] = prune(&a[i], &under);
+                }
+            }
+            Value::Array(pruned)
+        }
+        _ => v.clone(),
+    }
+}
+
+/// An empty container that `key` can be added to
+fn container_for(key: &str) -> Value {
+    if key == "-" || key.parse::<usize>().is_ok() {
+        Value::Array(vec![])
+    } else {
+        Value::Object(Default::default())
+    }
+}
+
+fn find_key<'a>(c: &'a Value, key: &str) -> Option<&'a Value> {
+    match c {
+        &Value::Object(ref o) => o.get(key),
+        &Value::Array(ref a) => string_to_index(key, a.len()).ok().and_then(|i| a.get(i)),
+        _ => None,
     }
 }
 
@@ -121,7 +344,11 @@ fn insert_key(container: &mut Value, key: &str, value: Value) -> Result<(), Patc
             Ok(())
         }
         &mut Value::Array(ref mut a) => {
-            let i = try!(string_to_index(&key, a.len() + 1));
+            let i = if key == "-" {
+                a.len()
+            } else {
+                try!(string_to_index(&key, a.len() + 1))
+            };
             if i == a.len() {
                 a.push(value);
             } else {
@@ -140,7 +367,7 @@ fn replace_key(container: &mut Value, key: &str, value: Value) -> Result<(), Pat
             Ok(())
         }
         &mut Value::Array(ref mut a) => {
-            let i = try!(string_to_index(&key, a.len() + 1));
+            let i = try!(existing_index(key, a.len()));
             a[i] = value;
             Ok(())
         }
@@ -154,7 +381,7 @@ fn remove_key(container: &mut Value, key: &str) -> Result<Value, PatchError> {
             o.remove(key)
         }
         &mut Value::Array(ref mut a) => {
-            let i = try!(string_to_index(key, a.len() + 1));
+            let i = try!(existing_index(key, a.len()));
             Some(a.remove(i))
         }
         _ => None,
@@ -173,13 +400,21 @@ fn string_to_index(k: &str, size: usize) -> Result<usize, PatchError> {
     }
 }
 
+/// The index of an element of an array of `size` elements; unlike `string_to_index`, `-` is
+/// never one
+fn existing_index(k: &str, size: usize) -> Result<usize, PatchError> {
+    match k.parse::<usize>() {
+        Ok(i) if i < size => Ok(i),
+        _ => Err(PatchError),
+    }
+}
+
 macro_rules! apply_patch {
     ($doc_str:expr, $patch_str:expr) => {{
         use serde_json;
-        let mut root: serde_json::Value = serde_json::from_str($doc_str).unwrap();
+        let root: serde_json::Value = serde_json::from_str($doc_str).unwrap();
         let patch = Patch::from_str($patch_str).unwrap();
-        apply(&patch, &mut root).unwrap();
-        root
+        apply(&patch, &root).unwrap()
     }}
 }
 
@@ -221,3 +456,76 @@ fn add_with_empty_path_replaces_root() {
     let root = apply_patch!("null", r#"[{"op":"add","path":"","value":12}]"#);
     assert_eq!(root.as_u64().unwrap(), 12)
 }
+
+#[test]
+fn add_with_dash_appends() {
+    let root = apply_patch!("[1,2]", r#"[{"op":"add","path":"/-","value":3}]"#);
+    assert_eq!(root, Value::Array(vec![Value::U64(1), Value::U64(2), Value::U64(3)]));
+}
+
+#[test]
+fn replace_and_remove_fail_past_the_end() {
+    use serde_json;
+    let root: Value = serde_json::from_str("[1,2]").unwrap();
+    for path in &["/2", "/-"] {
+        let replace = Op::Replace(::parse_pointer(path), Value::Null);
+        assert_eq!(apply_op(&replace, &mut root.clone()), Err(PatchError));
+        assert_eq!(apply_op(&Op::Remove(::parse_pointer(path)), &mut root.clone()),
+                   Err(PatchError));
+    }
+}
+
+#[test]
+fn display_round_trips() {
+    let source = concat!(r#"[{"op":"copy","path":"/a~1b","from":"/c~0d"},"#,
+                         r#"{"op":"remove","path":"/e"},{"op":"test","path":"/f","value":1}]"#);
+    let patch = Patch::from_str(source).unwrap();
+    assert_eq!(patch.to_string(), source);
+    assert_eq!(Patch::from_str(&patch.to_string()).unwrap().ops, patch.ops);
+}
+
+#[test]
+fn create_parents_makes_objects_and_arrays() {
+    use serde_json;
+    let patch = Patch::from_str(r#"[{"op":"add","path":"/a/b/0/c","value":1}]"#).unwrap();
+    let options = ApplyOptions { create_parents: true };
+    let root = apply_with(&patch, &Value::Null, options).unwrap();
+    assert_eq!(root, serde_json::from_str(r#"{"a":{"b":[{"c":1}]}}"#).unwrap());
+}
+
+#[test]
+fn create_parents_appends_for_dash() {
+    use serde_json;
+    let patch = Patch::from_str(r#"[{"op":"add","path":"/list/-/name","value":"x"}]"#).unwrap();
+    let options = ApplyOptions { create_parents: true };
+    let root = apply_with(&patch, &serde_json::from_str(r#"{"list":[1]}"#).unwrap(), options)
+                   .unwrap();
+    assert_eq!(root, serde_json::from_str(r#"{"list":[1,{"name":"x"}]}"#).unwrap());
+}
+
+#[test]
+fn expand_parents_applies_without_options() {
+    let patch = Patch::from_str(r#"[{"op":"add","path":"/a/-/b","value":true},
+                                   {"op":"copy","path":"/c/d","from":"/a/0"}]"#)
+                    .unwrap();
+    let options = ApplyOptions { create_parents: true };
+    let expanded = expand_parents(&patch, &Value::Null).unwrap();
+    assert_eq!(apply(&expanded, &Value::Null).unwrap(),
+               apply_with(&patch, &Value::Null, options).unwrap());
+    assert_eq!(expanded.ops.len(), 6);
+}
+
+#[test]
+fn check_agrees_with_apply() {
+    let mut rng = Rng(0x510e527fade682d1);
+    for _ in 0..2000 {
+        let doc = random_doc(&mut rng, 3);
+        let p = random_patch(&mut rng, &doc, 4);
+        let other = match rng.below(2) {
+            0 => random_doc(&mut rng, 3),
+            _ => apply(&random_patch(&mut rng, &doc, 2), &doc).unwrap(),
+        };
+        assert_eq!(check(&p, &doc), Ok(()));
+        assert_eq!(check(&p, &other), apply(&p, &other).map(|_| ()), "{} on {:?}", p, other);
+    }
+}
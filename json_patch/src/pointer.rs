@@ -0,0 +1,162 @@
+//! Pointers that know the type of the value they lead to, and building patches from them.
+//!
+//! `#[derive(Pointee)]` from `json_patch_derive` gives a struct a pointer type with a method for
+//! each field, so `Settings::path().theme()` leads to the `theme` field and only accepts values
+//! of its type.
+
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
+
+use serde::Serialize;
+use serde_json;
+use serde_json::Value;
+
+use {Op, Patch, Path};
+
+/// A type that typed pointers can lead to
+pub trait Pointee: Serialize + Sized {
+    /// The pointer to a value of this type, with a method for each of its fields if it has any
+    type Pointer: TypedPointer<Target = Self>;
+}
+
+/// A path to a value of type `Target`
+pub trait TypedPointer: Sized {
+    type Target: Serialize;
+
+    fn from_path(path: Path) -> Self;
+
+    fn path(&self) -> &Path;
+
+    fn into_path(self) -> Path;
+
+    /// The pointer to the member `key` of the value, which is of type `T`
+    fn child<T: Pointee>(&self, key: &str) -> T::Pointer {
+        let mut path = self.path().clone();
+        path.push(key.to_string());
+        T::Pointer::from_path(path)
+    }
+}
+
+/// A pointer to a value without fields, or to an array or map, whose members are reached with
+/// `at` and `key`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pointer<T> {
+    path: Path,
+    target: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize> TypedPointer for Pointer<T> {
+    type Target = T;
+
+    fn from_path(path: Path) -> Pointer<T> {
+        Pointer {
+            path: path,
+            target: PhantomData,
+        }
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn into_path(self) -> Path {
+        self.path
+    }
+}
+
+impl<T: Pointee> Pointer<Vec<T>> {
+    /// The element at `index`
+    pub fn at(&self, index: usize) -> T::Pointer {
+        self.child::<T>(&index.to_string())
+    }
+
+    /// The end of the array, where `add` appends
+    pub fn end(&self) -> T::Pointer {
+        self.child::<T>("-")
+    }
+}
+
+impl<T: Pointee> Pointer<BTreeMap<String, T>> {
+    pub fn key(&self, key: &str) -> T::Pointer {
+        self.child::<T>(key)
+    }
+}
+
+impl<T: Pointee> Pointer<HashMap<String, T>> {
+    pub fn key(&self, key: &str) -> T::Pointer {
+        self.child::<T>(key)
+    }
+}
+
+macro_rules! leaf_pointee {
+    ($($t:ty),*) => {
+        $(impl Pointee for $t {
+            type Pointer = Pointer<$t>;
+        })*
+    }
+}
+
+leaf_pointee!(bool, String, Value, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64);
+
+impl<T: Serialize> Pointee for Option<T> {
+    type Pointer = Pointer<Option<T>>;
+}
+
+impl<T: Pointee> Pointee for Vec<T> {
+    type Pointer = Pointer<Vec<T>>;
+}
+
+impl<T: Pointee> Pointee for BTreeMap<String, T> {
+    type Pointer = Pointer<BTreeMap<String, T>>;
+}
+
+impl<T: Pointee> Pointee for HashMap<String, T> {
+    type Pointer = Pointer<HashMap<String, T>>;
+}
+
+/// Builds a patch from typed pointers, so the compiler checks each path and value
+#[derive(Debug, Clone, Default)]
+pub struct PatchBuilder {
+    ops: Vec<Op>,
+}
+
+impl PatchBuilder {
+    pub fn new() -> PatchBuilder {
+        PatchBuilder::default()
+    }
+
+    pub fn add<P: TypedPointer>(mut self, pointer: P, value: P::Target) -> PatchBuilder {
+        self.ops.push(Op::Add(pointer.into_path(), serde_json::to_value(&value)));
+        self
+    }
+
+    pub fn replace<P: TypedPointer>(mut self, pointer: P, value: P::Target) -> PatchBuilder {
+        self.ops.push(Op::Replace(pointer.into_path(), serde_json::to_value(&value)));
+        self
+    }
+
+    pub fn remove<P: TypedPointer>(mut self, pointer: P) -> PatchBuilder {
+        self.ops.push(Op::Remove(pointer.into_path()));
+        self
+    }
+
+    pub fn build(self) -> Patch {
+        Patch { ops: self.ops }
+    }
+}
+
+#[test]
+fn builders_follow_typed_pointers() {
+    type Doc = BTreeMap<String, Vec<Option<u32>>>;
+    let root = <Doc as Pointee>::Pointer::from_path(vec![]);
+    let patch = PatchBuilder::new()
+                    .add(root.key("a").end(), Some(1))
+                    .replace(root.key("b").at(0), None)
+                    .remove(root.key("c"))
+                    .build();
+    assert_eq!(patch,
+               Patch::from_str(r#"[{"op":"add","path":"/a/-","value":1},
+                                   {"op":"replace","path":"/b/0","value":null},
+                                   {"op":"remove","path":"/c"}]"#)
+                   .unwrap());
+}
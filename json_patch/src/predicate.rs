@@ -0,0 +1,351 @@
+//! Preconditions beyond the equality of `test`, and finding out which of a patch's failed.
+
+use std::fmt;
+
+use regex::Regex;
+use serde_json::Value;
+
+use patch::apply_op;
+use {find_path, format_pointer, require_key, InvalidOpError, Op, Patch, Path};
+
+/// What an `Op::Assert` requires of the value at its path
+#[derive(Clone, PartialEq, Debug)]
+pub enum Predicate {
+    /// `test`: the value equals this one, as for `Op::Test`
+    Equals(Value),
+    /// `test-exists`: there is a value
+    Exists,
+    /// `test-not`: there is no value or it is not this one
+    Not(Value),
+    /// `test-type`: the value is of this type
+    Type(JsonType),
+    /// `test-range`: the value is a number, no less than `min` and no more than `max`
+    Range(Option<f64>, Option<f64>),
+    /// `test-regex`: the value is a string matching the pattern
+    Regex(Pattern),
+    /// `test-length`: the value is an array with no fewer than `min` elements and no more
+    /// than `max`
+    Length(Option<usize>, Option<usize>),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum JsonType {
+    Null,
+    Boolean,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+/// A regular expression, equal to another with the same source
+#[derive(Clone, Debug)]
+pub struct Pattern(pub Regex);
+
+/// A test or predicate in a patch that did not hold
+#[derive(Clone, PartialEq, Debug)]
+pub struct TestFailure {
+    /// Index of the op in the patch
+    pub op: usize,
+    pub path: Path,
+    pub predicate: Predicate,
+    /// The value at the path when the op was reached
+    pub found: Option<Value>,
+}
+
+impl Predicate {
+    /// The `op` of this predicate in a patch
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Predicate::Equals(_) => "test",
+            Predicate::Exists => "test-exists",
+            Predicate::Not(_) => "test-not",
+            Predicate::Type(_) => "test-type",
+            Predicate::Range(..) => "test-range",
+            Predicate::Regex(_) => "test-regex",
+            Predicate::Length(..) => "test-length",
+        }
+    }
+
+    /// The predicate op called `name`, with its arguments taken from the op object `v`, or
+    /// `None` if there is no such predicate
+    pub fn from_value(name: &str, v: &Value) -> Result<Option<Predicate>, InvalidOpError> {
+        Ok(Some(match name {
+            "test-exists" => Predicate::Exists,
+            "test-not" => Predicate::Not(try!(require_key(v, "value")).clone()),
+            "test-type" => {
+                let name = try!(require_key(v, "type")).as_string();
+                match name.and_then(JsonType::from_name) {
+                    Some(t) => Predicate::Type(t),
+                    None => return Err(InvalidOpError::InvalidValue("type".to_string())),
+                }
+            }
+            "test-range" => Predicate::Range(try!(bound(v, "min")), try!(bound(v, "max"))),
+            "test-regex" => {
+                let source = try!(require_key(v, "pattern")).as_string();
+                let source = try!(source.ok_or(InvalidOpError::MustBeString("pattern".into())));
+                match Regex::new(source) {
+                    Ok(regex) => Predicate::Regex(Pattern(regex)),
+                    Err(_) => return Err(InvalidOpError::InvalidValue("pattern".to_string())),
+                }
+            }
+            "test-length" => Predicate::Length(try!(length(v, "min")), try!(length(v, "max"))),
+            _ => return Ok(None),
+        }))
+    }
+
+    /// Whether the predicate holds for `value`, which is `None` where there is nothing
+    pub fn holds(&self, value: Option<&Value>) -> bool {
+        let within = |n: f64, min: Option<f64>, max: Option<f64>| {
+            min.map_or(true, |min| n >= min) && max.map_or(true, |max| n <= max)
+        };
+        match *self {
+            Predicate::Equals(ref expected) => value == Some(expected),
+            Predicate::Exists => value.is_some(),
+            Predicate::Not(ref unwanted) => value != Some(unwanted),
+            Predicate::Type(t) => value.map_or(false, |value| JsonType::of(value) == t),
+            Predicate::Range(min, max) => {
+                value.and_then(|v| v.as_f64()).map_or(false, |n| within(n, min, max))
+            }
+            Predicate::Regex(ref pattern) => {
+                value.and_then(|v| v.as_string()).map_or(false, |s| pattern.0.is_match(s))
+            }
+            Predicate::Length(min, max) => {
+                match value {
+                    Some(&Value::Array(ref a)) => {
+                        within(a.len() as f64,
+                               min.map(|n| n as f64),
+                               max.map(|n| n as f64))
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bounds = |f: &mut fmt::Formatter, min: Option<f64>, max: Option<f64>, unit: &str| {
+            match (min, max) {
+                (Some(min), Some(max)) => write!(f, " of {} to {}{}", min, max, unit),
+                (Some(min), None) => write!(f, " of at least {}{}", min, unit),
+                (None, Some(max)) => write!(f, " of at most {}{}", max, unit),
+                (None, None) => Ok(()),
+            }
+        };
+        match *self {
+            Predicate::Equals(ref v) => write!(f, "{:?}", v),
+            Predicate::Exists => write!(f, "present"),
+            Predicate::Not(ref v) => write!(f, "anything but {:?}", v),
+            Predicate::Type(t) => write!(f, "of type {}", t.name()),
+            Predicate::Range(min, max) => {
+                try!(write!(f, "a number"));
+                bounds(f, min, max, "")
+            }
+            Predicate::Regex(ref pattern) => {
+                write!(f,
+                       "a string matching {:?}",
+                       Value::String(pattern.0.as_str().to_string()))
+            }
+            Predicate::Length(min, max) => {
+                try!(write!(f, "an array"));
+                bounds(f, min.map(|n| n as f64), max.map(|n| n as f64), " elements")
+            }
+        }
+    }
+}
+
+impl JsonType {
+    pub fn of(value: &Value) -> JsonType {
+        match *value {
+            Value::Null => JsonType::Null,
+            Value::Bool(_) => JsonType::Boolean,
+            Value::Array(_) => JsonType::Array,
+            Value::Object(_) => JsonType::Object,
+            Value::String(_) => JsonType::String,
+            _ => JsonType::Number,
+        }
+    }
+
+    /// The name of the type in a `test-type` op
+    pub fn name(&self) -> &'static str {
+        match *self {
+            JsonType::Null => "null",
+            JsonType::Boolean => "boolean",
+            JsonType::Number => "number",
+            JsonType::String => "string",
+            JsonType::Array => "array",
+            JsonType::Object => "object",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<JsonType> {
+        Some(match name {
+            "null" => JsonType::Null,
+            "boolean" => JsonType::Boolean,
+            "number" => JsonType::Number,
+            "string" => JsonType::String,
+            "array" => JsonType::Array,
+            "object" => JsonType::Object,
+            _ => return None,
+        })
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl fmt::Display for TestFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f,
+                    "op {} failed: the value at {:?} must be {}, ",
+                    self.op,
+                    format_pointer(&self.path),
+                    self.predicate));
+        match self.found {
+            Some(ref found) => write!(f, "but is {:?}", found),
+            None => write!(f, "but there is none"),
+        }
+    }
+}
+
+impl Patch {
+    /// The test or predicate that stops the patch from applying to `doc`, or `None` if it
+    /// applies or fails for another reason
+    pub fn failed_test(&self, doc: &Value) -> Option<TestFailure> {
+        let mut doc = doc.clone();
+        for (i, op) in self.ops.iter().enumerate() {
+            if apply_op(op, &mut doc).is_ok() {
+                continue;
+            }
+            let (path, predicate) = match *op {
+                Op::Test(ref path, ref value) => (path, Predicate::Equals(value.clone())),
+                Op::Assert(ref path, ref predicate) => (path, predicate.clone()),
+                _ => return None,
+            };
+            return Some(TestFailure {
+                op: i,
+                path: path.clone(),
+                predicate: predicate,
+                found: find_path(&doc, path).cloned(),
+            });
+        }
+        None
+    }
+}
+
+/// The optional property `k` of an op, which must be a number
+fn bound(v: &Value, k: &str) -> Result<Option<f64>, InvalidOpError> {
+    match v.find(k) {
+        None => Ok(None),
+        Some(n) => n.as_f64().map(Some).ok_or(InvalidOpError::MustBeNumber(k.to_string())),
+    }
+}
+
+/// The optional length bound `k` of a `test-length` op, which must be a non-negative integer
+fn length(v: &Value, k: &str) -> Result<Option<usize>, InvalidOpError> {
+    match v.find(k) {
+        None => Ok(None),
+        Some(n) if !n.is_number() => Err(InvalidOpError::MustBeNumber(k.to_string())),
+        Some(n) => {
+            match n.as_u64() {
+                Some(n) => Ok(Some(n as usize)),
+                None => Err(InvalidOpError::InvalidValue(k.to_string())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn patch(s: &str) -> Patch {
+    use ParseOptions;
+    Patch::from_str_with(s, ParseOptions { predicates: true, ..ParseOptions::default() }).unwrap()
+}
+
+#[cfg(test)]
+fn doc() -> Value {
+    ::serde_json::from_str(r#"{"n":3,"s":"abc","a":[1,2],"o":{}}"#).unwrap()
+}
+
+#[test]
+fn predicates_hold_for_matching_values() {
+    use apply;
+    let p = patch(r#"[{"op":"test-exists","path":"/o"},
+                      {"op":"test-not","path":"/x","value":1},
+                      {"op":"test-not","path":"/n","value":4},
+                      {"op":"test-type","path":"/s","type":"string"},
+                      {"op":"test-range","path":"/n","min":1,"max":3},
+                      {"op":"test-regex","path":"/s","pattern":"^a.c$"},
+                      {"op":"test-length","path":"/a","min":2}]"#);
+    assert_eq!(apply(&p, &doc()), Ok(doc()));
+}
+
+#[test]
+fn failures_name_the_op_and_predicate() {
+    let p = patch(r#"[{"op":"test-type","path":"/n","type":"number"},
+                      {"op":"replace","path":"/n","value":"x"},
+                      {"op":"test-range","path":"/n","max":5}]"#);
+    let failure = p.failed_test(&doc()).unwrap();
+    assert_eq!(failure,
+               TestFailure {
+                   op: 2,
+                   path: ::parse_pointer("/n"),
+                   predicate: Predicate::Range(None, Some(5.0)),
+                   found: Some(Value::String("x".into())),
+               });
+    assert_eq!(failure.to_string(),
+               r#"op 2 failed: the value at "/n" must be a number of at most 5, but is "x""#);
+
+    let p = patch(r#"[{"op":"test","path":"/a/2","value":3}]"#);
+    assert_eq!(p.failed_test(&doc()).unwrap().found, None);
+    assert_eq!(patch(r#"[{"op":"remove","path":"/x"}]"#).failed_test(&doc()), None);
+}
+
+#[test]
+fn invalid_predicates_are_rejected() {
+    use {InvalidPatchError, ParseOptions};
+    let options = ParseOptions { predicates: true, ..ParseOptions::default() };
+    for s in &[r#"[{"op":"test-type","path":"/n","type":"integer"}]"#,
+               r#"[{"op":"test-regex","path":"/s","pattern":"("}]"#,
+               r#"[{"op":"test-range","path":"/n","min":"1"}]"#,
+               r#"[{"op":"test-length","path":"/a","min":-1}]"#,
+               r#"[{"op":"test-length","path":"/a","max":1.5}]"#] {
+        match Patch::from_str_with(s, options) {
+            Err(InvalidPatchError::BadOp(0, _)) => (),
+            other => panic!("{} parsed as {:?}", s, other),
+        }
+    }
+}
+
+#[test]
+fn predicates_and_extensions_are_enabled_apart() {
+    use {InvalidOpError, InvalidPatchError, ParseOptions};
+    let unknown = |s: &str, options| {
+        match Patch::from_str_with(s, options) {
+            Err(InvalidPatchError::BadOp(0, InvalidOpError::UnknownOp(_))) => true,
+            _ => false,
+        }
+    };
+    let predicate = r#"[{"op":"test-type","path":"/n","type":"number"}]"#;
+    let extension = r#"[{"op":"inc","path":"/n","value":1}]"#;
+    let predicates = ParseOptions { predicates: true, ..ParseOptions::default() };
+    let extensions = ParseOptions { extensions: true, ..ParseOptions::default() };
+    assert!(!unknown(predicate, predicates) && unknown(extension, predicates));
+    assert!(unknown(predicate, extensions) && !unknown(extension, extensions));
+}
+
+#[test]
+fn predicates_round_trip_through_display() {
+    let p = patch(r#"[{"op":"test","path":"/n","value":3},
+                      {"op":"test-exists","path":"/o"},
+                      {"op":"test-not","path":"/x","value":[1]},
+                      {"op":"test-type","path":"/s","type":"null"},
+                      {"op":"test-range","path":"/n","min":-1.5},
+                      {"op":"test-regex","path":"/s","pattern":"\\d+\""},
+                      {"op":"test-length","path":"/a","min":0,"max":2}]"#);
+    assert_eq!(patch(&p.to_string()), p);
+}
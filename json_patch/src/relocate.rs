@@ -0,0 +1,147 @@
+//! Moving patches between a document and the values inside it, and confining them to a subtree.
+
+use {Op, Patch, Path};
+
+/// A pointer in a patch that lies outside the subtree the patch was meant for
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutOfScope {
+    /// Index of the op in the patch
+    pub op: usize,
+    /// The op's `path` or `from`
+    pub path: Path,
+}
+
+impl Patch {
+    /// The patch to the value at `prefix` as a patch to the whole document: every pointer gets
+    /// `prefix` in front, including the `from` of copies and moves
+    pub fn prefixed<S: AsRef<str>>(&self, prefix: &[S]) -> Patch {
+        let prefix: Path = prefix.iter().map(|s| s.as_ref().to_string()).collect();
+        let patch = self.map_paths(|path| {
+            let mut result = prefix.clone();
+            result.extend(path.iter().cloned());
+            Some(result)
+        });
+        patch.unwrap()
+    }
+
+    /// The patch as seen from the value at `prefix`, with `prefix` taken off the front of every
+    /// pointer. Fails on the first op that reads or writes outside that value, such as one that
+    /// replaces a value above it; whoever follows the subtree then needs it afresh.
+    pub fn strip_prefix<S: AsRef<str>>(&self, prefix: &[S]) -> Result<Patch, OutOfScope> {
+        self.map_paths(|path| {
+            if within(path, prefix) {
+                Some(path[prefix.len()..].to_vec())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Check that every pointer in the patch, including the `from` of copies and moves, is
+    /// `scope` or under it
+    pub fn check_scope<S: AsRef<str>>(&self, scope: &[S]) -> Result<(), OutOfScope> {
+        for (i, op) in self.ops.iter().enumerate() {
+            let (path, from) = paths(op);
+            for path in Some(path).into_iter().chain(from) {
+                if !within(path, scope) {
+                    return Err(OutOfScope {
+                        op: i,
+                        path: path.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The patch with `f` applied to every pointer, failing on the first it returns `None` for
+    fn map_paths<F>(&self, mut f: F) -> Result<Patch, OutOfScope>
+        where F: FnMut(&Path) -> Option<Path>
+    {
+        let mut ops = Vec::with_capacity(self.ops.len());
+        for (i, op) in self.ops.iter().enumerate() {
+            let mut map = |path: &Path| {
+                f(path).ok_or_else(|| {
+                    OutOfScope {
+                        op: i,
+                        path: path.clone(),
+                    }
+                })
+            };
+            ops.push(match *op {
+                Op::Add(ref path, ref value) => Op::Add(try!(map(path)), value.clone()),
+                Op::Remove(ref path) => Op::Remove(try!(map(path))),
+                Op::Replace(ref path, ref value) => Op::Replace(try!(map(path)), value.clone()),
+                Op::Test(ref path, ref value) => Op::Test(try!(map(path)), value.clone()),
+                Op::Assert(ref path, ref predicate) => {
+                    Op::Assert(try!(map(path)), predicate.clone())
+                }
+                Op::Extension(ref path, ref extension) => {
+                    Op::Extension(try!(map(path)), extension.clone())
+                }
+                Op::Copy(ref path, ref from) => Op::Copy(try!(map(path)), try!(map(from))),
+                Op::Move(ref path, ref from) => Op::Move(try!(map(path)), try!(map(from))),
+            });
+        }
+        Ok(Patch { ops: ops })
+    }
+}
+
+/// The `path` of an op and its `from`, if it has one
+fn paths(op: &Op) -> (&Path, Option<&Path>) {
+    match *op {
+        Op::Add(ref path, _) |
+        Op::Remove(ref path) |
+        Op::Replace(ref path, _) |
+        Op::Test(ref path, _) |
+        Op::Assert(ref path, _) |
+        Op::Extension(ref path, _) => (path, None),
+        Op::Copy(ref path, ref from) | Op::Move(ref path, ref from) => (path, Some(from)),
+    }
+}
+
+fn within<S: AsRef<str>>(path: &[String], scope: &[S]) -> bool {
+    path.len() >= scope.len() && path.iter().zip(scope).all(|(a, b)| *a == b.as_ref())
+}
+
+#[cfg(test)]
+fn patch(s: &str) -> Patch {
+    Patch::from_str(s).unwrap()
+}
+
+#[test]
+fn prefixing_covers_copy_and_move_sources() {
+    let p = patch(r#"[{"op":"copy","path":"/a","from":"/b"},
+                      {"op":"move","path":"/c/0","from":"/d"},
+                      {"op":"remove","path":"/e"}]"#);
+    assert_eq!(p.prefixed(&["x", "y"]),
+               patch(r#"[{"op":"copy","path":"/x/y/a","from":"/x/y/b"},
+                         {"op":"move","path":"/x/y/c/0","from":"/x/y/d"},
+                         {"op":"remove","path":"/x/y/e"}]"#));
+}
+
+#[test]
+fn stripping_undoes_prefixing() {
+    let p = patch(r#"[{"op":"add","path":"/a/-","value":1},
+                      {"op":"move","path":"/b","from":"/c/d"},
+                      {"op":"replace","path":"","value":{}}]"#);
+    assert_eq!(p.prefixed(&["x"]).strip_prefix(&["x"]), Ok(p.clone()));
+    assert_eq!(p.strip_prefix(&["a"]),
+               Err(OutOfScope {
+                   op: 1,
+                   path: ::parse_pointer("/b"),
+               }));
+}
+
+#[test]
+fn scope_checks_find_sources_outside() {
+    let p = patch(r#"[{"op":"test","path":"/a/b","value":1},
+                      {"op":"copy","path":"/a/c","from":"/secret"}]"#);
+    assert_eq!(p.check_scope(&["a"]),
+               Err(OutOfScope {
+                   op: 1,
+                   path: ::parse_pointer("/secret"),
+               }));
+    assert_eq!(p.check_scope::<&str>(&[]), Ok(()));
+    assert_eq!(p.prefixed(&["a"]).check_scope(&["a"]), Ok(()));
+}
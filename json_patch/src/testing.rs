@@ -0,0 +1,148 @@
+//! Random documents and patches for property tests.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use {apply, find_path, Op, Patch, Path};
+
+/// A xorshift generator, so the property tests are reproducible and need no dependencies
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        if items.is_empty() {
+            None
+        } else {
+            Some(&items[self.below(items.len())])
+        }
+    }
+}
+
+/// The object keys that documents and patches are generated with. A key that is a number could
+/// be taken for an array index.
+pub const KEYS: &'static [&'static str] = &["a", "b", "c", "1"];
+/// `KEYS` without numbers, for testing `transform`, which assumes that only indices are numbers
+pub const WORD_KEYS: &'static [&'static str] = &["a", "b", "c"];
+
+/// A small document with keys from `KEYS`
+pub fn random_doc(rng: &mut Rng, depth: usize) -> Value {
+    random_doc_with(rng, depth, KEYS)
+}
+
+/// A small document whose objects hold some of `keys`
+pub fn random_doc_with(rng: &mut Rng, depth: usize, keys: &[&str]) -> Value {
+    match if depth == 0 { 0 } else { rng.below(3) } {
+        0 => Value::U64(rng.below(10) as u64),
+        1 => {
+            Value::Array((0..rng.below(4)).map(|_| random_doc_with(rng, depth - 1, keys)).collect())
+        }
+        _ => {
+            let mut o = BTreeMap::new();
+            for key in keys {
+                if rng.below(2) == 0 {
+                    o.insert(key.to_string(), random_doc_with(rng, depth - 1, keys));
+                }
+            }
+            Value::Object(o)
+        }
+    }
+}
+
+/// The paths of every value in `doc`, parents before children
+pub fn paths(doc: &Value) -> Vec<Path> {
+    let mut paths = vec![vec![]];
+    let mut i = 0;
+    while i < paths.len() {
+        let path = paths[i].clone();
+        match find_path(doc, &path) {
+            Some(&Value::Array(ref a)) => {
+                paths.extend((0..a.len()).map(|j| child(path.clone(), &j.to_string())))
+            }
+            Some(&Value::Object(ref o)) => paths.extend(o.keys().map(|k| child(path.clone(), k))),
+            _ => (),
+        }
+        i += 1;
+    }
+    paths
+}
+
+/// Every path a value could be added at in `doc`, other than the root, with object keys from
+/// `keys`
+pub fn targets(doc: &Value, keys: &[&str]) -> Vec<Path> {
+    let mut targets = vec![];
+    for path in paths(doc) {
+        match find_path(doc, &path) {
+            Some(&Value::Array(ref a)) => {
+                targets.extend((0..a.len() + 1).map(|j| child(path.clone(), &j.to_string())));
+                targets.push(child(path, "-"));
+            }
+            Some(&Value::Object(_)) => {
+                targets.extend(keys.iter().map(|k| child(path.clone(), k)))
+            }
+            _ => (),
+        }
+    }
+    targets
+}
+
+/// Every operation that applies to `doc`, with `value` for the ones that need a value and object
+/// keys from `keys`
+pub fn all_ops(doc: &Value, value: &Value, keys: &[&str]) -> Vec<Op> {
+    let mut ops = vec![];
+    for path in &paths(doc) {
+        ops.push(Op::Replace(path.clone(), value.clone()));
+        ops.push(Op::Test(path.clone(), find_path(doc, path).unwrap().clone()));
+        if path.is_empty() {
+            continue;
+        }
+        ops.push(Op::Remove(path.clone()));
+        for target in &targets(doc, keys) {
+            if !target.starts_with(path) {
+                ops.push(Op::Copy(target.clone(), path.clone()));
+            }
+        }
+        let without = apply(&Patch { ops: vec![Op::Remove(path.clone())] }, doc).unwrap();
+        for target in targets(&without, keys) {
+            ops.push(Op::Move(target, path.clone()));
+        }
+    }
+    for target in &targets(doc, keys) {
+        ops.push(Op::Add(target.clone(), value.clone()));
+    }
+    ops.into_iter().filter(|op| apply(&Patch { ops: vec![op.clone()] }, doc).is_ok()).collect()
+}
+
+/// A patch of up to `max_ops` operations that applies to `doc`, with keys from `KEYS`
+pub fn random_patch(rng: &mut Rng, doc: &Value, max_ops: usize) -> Patch {
+    random_patch_with(rng, doc, max_ops, KEYS)
+}
+
+/// A patch of up to `max_ops` operations that applies to `doc`, with object keys from `keys`
+pub fn random_patch_with(rng: &mut Rng, doc: &Value, max_ops: usize, keys: &[&str]) -> Patch {
+    let mut doc = doc.clone();
+    let mut ops = vec![];
+    for _ in 0..rng.below(max_ops) + 1 {
+        let value = random_doc_with(rng, 1, keys);
+        let op = match rng.pick(&all_ops(&doc, &value, keys)) {
+            Some(op) => op.clone(),
+            None => break,
+        };
+        doc = apply(&Patch { ops: vec![op.clone()] }, &doc).unwrap();
+        ops.push(op);
+    }
+    Patch { ops: ops }
+}
+
+
+fn child(mut path: Path, key: &str) -> Path {
+    path.push(key.to_string());
+    path
+}
@@ -0,0 +1,68 @@
+//! Patching Rust values through their JSON form.
+
+use serde::{Deserialize, Serialize};
+use serde_json;
+
+use {apply, diff, Patch, PatchError};
+
+/// Why a patch could not be applied to a typed value
+#[derive(Debug)]
+pub enum TypedPatchError {
+    /// The patch does not apply to the value's JSON
+    PatchError(PatchError),
+    /// The patched JSON is not a valid value of the type
+    DeserializeError(serde_json::Error),
+}
+
+impl From<PatchError> for TypedPatchError {
+    fn from(e: PatchError) -> TypedPatchError {
+        TypedPatchError::PatchError(e)
+    }
+}
+
+impl From<serde_json::Error> for TypedPatchError {
+    fn from(e: serde_json::Error) -> TypedPatchError {
+        TypedPatchError::DeserializeError(e)
+    }
+}
+
+/// `value` with `patch` applied to its JSON, read back as a `T`
+pub fn apply_typed<T: Serialize + Deserialize>(patch: &Patch,
+                                                value: &T)
+                                                -> Result<T, TypedPatchError> {
+    let patched = try!(apply(patch, &serde_json::to_value(value)));
+    Ok(try!(serde_json::from_value(patched)))
+}
+
+/// The patch that turns the JSON of `from` into the JSON of `to`
+pub fn diff_typed<T: Serialize>(from: &T, to: &T) -> Patch {
+    diff(&serde_json::to_value(from), &serde_json::to_value(to))
+}
+
+#[test]
+fn typed_values_round_trip_through_diffs() {
+    use std::collections::BTreeMap;
+    let mut from = BTreeMap::new();
+    from.insert("a".to_string(), vec![Some(1), None]);
+    let mut to = from.clone();
+    to.get_mut("a").unwrap().insert(0, Some(0));
+    to.insert("b".to_string(), vec![]);
+    let patch = diff_typed(&from, &to);
+    assert_eq!(patch.ops.len(), 2);
+    assert_eq!(apply_typed(&patch, &from).unwrap(), to);
+}
+
+#[test]
+fn patch_and_type_errors_are_told_apart() {
+    let value = vec![1u32, 2];
+    let remove = Patch::from_str(r#"[{"op":"remove","path":"/2"}]"#).unwrap();
+    match apply_typed(&remove, &value) {
+        Err(TypedPatchError::PatchError(PatchError)) => (),
+        other => panic!("{:?}", other),
+    }
+    let replace = Patch::from_str(r#"[{"op":"replace","path":"/0","value":"one"}]"#).unwrap();
+    match apply_typed(&replace, &value) {
+        Err(TypedPatchError::DeserializeError(_)) => (),
+        other => panic!("{:?}", other),
+    }
+}
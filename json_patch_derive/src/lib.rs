@@ -0,0 +1,102 @@
+//! `#[derive(Pointee)]`, giving a struct a typed pointer API for building patches.
+//!
+//! For `struct Settings { theme: Theme }` this generates a `SettingsPointer` type with a `theme`
+//! method leading to the field, and `Settings::path()` for the pointer to the whole value, so
+//! `Settings::path().theme()` names `/theme`. Fields are named as serde serializes them,
+//! following `#[serde(rename = "...")]`.
+
+extern crate proc_macro;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+use syn::{Body, Field, Ident, Lit, MetaItem, NestedMetaItem, VariantData};
+
+#[proc_macro_derive(Pointee, attributes(serde))]
+pub fn derive_pointee(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+    expand(&ast).parse().unwrap()
+}
+
+fn expand(ast: &syn::DeriveInput) -> quote::Tokens {
+    let fields = match ast.body {
+        Body::Struct(VariantData::Struct(ref fields)) => fields,
+        _ => panic!("#[derive(Pointee)] needs a struct with named fields"),
+    };
+    if !ast.generics.lifetimes.is_empty() || !ast.generics.ty_params.is_empty() {
+        panic!("#[derive(Pointee)] does not support generic structs");
+    }
+    let name = &ast.ident;
+    let vis = &ast.vis;
+    let pointer = Ident::new(format!("{}Pointer", name));
+    let methods: Vec<_> = fields.iter()
+                                .map(|field| {
+                                    let method = field.ident.as_ref().unwrap();
+                                    let key = serialized_name(field);
+                                    let vis = &field.vis;
+                                    let ty = &field.ty;
+                                    quote! {
+                                        #vis fn #method(&self)
+                                                        -> <#ty as ::json_patch::Pointee>::Pointer {
+                                            ::json_patch::TypedPointer::child::<#ty>(self, #key)
+                                        }
+                                    }
+                                })
+                                .collect();
+    quote! {
+        /// A pointer to a value, with a method for each of its fields
+        #[derive(Debug, Clone, PartialEq)]
+        #vis struct #pointer(::json_patch::Path);
+
+        impl ::json_patch::TypedPointer for #pointer {
+            type Target = #name;
+
+            fn from_path(path: ::json_patch::Path) -> #pointer {
+                #pointer(path)
+            }
+
+            fn path(&self) -> &::json_patch::Path {
+                &self.0
+            }
+
+            fn into_path(self) -> ::json_patch::Path {
+                self.0
+            }
+        }
+
+        impl ::json_patch::Pointee for #name {
+            type Pointer = #pointer;
+        }
+
+        impl #name {
+            /// The pointer to the whole value
+            #vis fn path() -> #pointer {
+                #pointer(vec![])
+            }
+        }
+
+        impl #pointer {
+            #(#methods)*
+        }
+    }
+}
+
+/// The key serde writes `field` under
+fn serialized_name(field: &Field) -> String {
+    for attr in &field.attrs {
+        let items = match attr.value {
+            MetaItem::List(ref ident, ref items) if ident == "serde" => items,
+            _ => continue,
+        };
+        for item in items {
+            if let NestedMetaItem::MetaItem(MetaItem::NameValue(ref ident, Lit::Str(ref s, _))) =
+                   *item {
+                if ident == "rename" {
+                    return s.clone();
+                }
+            }
+        }
+    }
+    field.ident.as_ref().unwrap().to_string()
+}
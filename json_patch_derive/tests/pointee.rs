@@ -0,0 +1,81 @@
+extern crate json_patch;
+#[macro_use]
+extern crate json_patch_derive;
+extern crate serde;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+
+use json_patch::{Patch, PatchBuilder, TypedPointer};
+use serde::{Serialize, Serializer};
+use serde_json::to_value;
+
+#[derive(Pointee, Debug, Clone, PartialEq)]
+pub struct Theme {
+    pub name: String,
+    pub size: u32,
+}
+
+#[derive(Pointee, Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub theme: Theme,
+    #[serde(rename = "tag-list")]
+    pub tags: Vec<String>,
+    pub limit: Option<u32>,
+}
+
+// written out by hand, as serde_derive would write them
+impl Serialize for Theme {
+    fn serialize<S: Serializer>(&self, serializer: &mut S) -> Result<(), S::Error> {
+        let mut map = BTreeMap::new();
+        map.insert("name", to_value(&self.name));
+        map.insert("size", to_value(&self.size));
+        map.serialize(serializer)
+    }
+}
+
+impl Serialize for Settings {
+    fn serialize<S: Serializer>(&self, serializer: &mut S) -> Result<(), S::Error> {
+        let mut map = BTreeMap::new();
+        map.insert("theme", to_value(&self.theme));
+        map.insert("tag-list", to_value(&self.tags));
+        map.insert("limit", to_value(&self.limit));
+        map.serialize(serializer)
+    }
+}
+
+#[test]
+fn nested_fields_lead_to_their_paths() {
+    assert_eq!(Settings::path().into_path(), Vec::<String>::new());
+    assert_eq!(Settings::path().theme().size().into_path(),
+               vec!["theme".to_string(), "size".to_string()]);
+}
+
+#[test]
+fn renamed_fields_use_their_serialized_names() {
+    assert_eq!(Settings::path().tags().at(1).into_path(),
+               vec!["tag-list".to_string(), "1".to_string()]);
+}
+
+#[test]
+fn builders_take_derived_pointers() {
+    let theme = Theme {
+        name: "dark".to_string(),
+        size: 12,
+    };
+    let patch = PatchBuilder::new()
+                    .replace(Settings::path().theme(), theme)
+                    .replace(Settings::path().theme().size(), 14)
+                    .add(Settings::path().tags().end(), "new".to_string())
+                    .replace(Settings::path().limit(), Some(3))
+                    .remove(Settings::path().tags().at(0))
+                    .build();
+    assert_eq!(patch,
+               Patch::from_str(r#"[{"op":"replace","path":"/theme",
+                                    "value":{"name":"dark","size":12}},
+                                   {"op":"replace","path":"/theme/size","value":14},
+                                   {"op":"add","path":"/tag-list/-","value":"new"},
+                                   {"op":"replace","path":"/limit","value":3},
+                                   {"op":"remove","path":"/tag-list/0"}]"#)
+                   .unwrap());
+}